@@ -0,0 +1,226 @@
+use std::fmt;
+use std::str::FromStr;
+use bech32::{hrp, Fe32, Hrp};
+use ripemd::Ripemd160;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use crate::crypto::VersionedPublicKey;
+use crate::error::BtcError;
+use crate::network::Network;
+
+const ECDSA_WITNESS_VERSION: Fe32 = Fe32::Q;
+// not 1, which is taproot
+const SCHNORR_WITNESS_VERSION: Fe32 = Fe32::Z;
+
+/// HASH160 (RIPEMD160 of SHA256) of a public key, tagged with
+/// the signature scheme of the key it commits to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PubkeyHash {
+    /// Hash of a SEC1 compressed ECDSA key
+    Ecdsa([u8; 20]),
+    /// Hash of an x-only Schnorr key
+    Schnorr([u8; 20]),
+}
+
+impl PubkeyHash {
+    pub fn of(public_key: &VersionedPublicKey) -> Self {
+        match public_key {
+            VersionedPublicKey::Ecdsa(key) => PubkeyHash::Ecdsa(hash160(&key.to_sec1())),
+            VersionedPublicKey::Schnorr(key) => PubkeyHash::Schnorr(hash160(&key.to_bytes())),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        match self {
+            PubkeyHash::Ecdsa(hash) | PubkeyHash::Schnorr(hash) => hash,
+        }
+    }
+}
+
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+/// A short, checksummed representation of a public key hash
+/// for a specific network.
+///
+/// ECDSA key hashes are shown as bech32 witness version 0 and
+/// can also be written as legacy Base58Check P2PKH. Schnorr key
+/// hashes are shown as bech32m witness version 2: version 1 is
+/// taproot, whose 32-byte program other tools expect to be the
+/// key itself rather than a 20-byte hash of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub network: Network,
+    pub hash: PubkeyHash,
+}
+
+impl Address {
+    pub fn new(network: Network, hash: PubkeyHash) -> Self {
+        Address { network, hash }
+    }
+
+    pub fn from_public_key(network: Network, public_key: &VersionedPublicKey) -> Self {
+        Address::new(network, PubkeyHash::of(public_key))
+    }
+
+    /// Legacy Base58Check P2PKH encoding. Only ECDSA key hashes
+    /// have one.
+    pub fn to_base58check(&self) -> Option<String> {
+        match self.hash {
+            PubkeyHash::Ecdsa(hash) => {
+                let mut payload = vec![base58_version(self.network)];
+                payload.extend_from_slice(&hash);
+                Some(bs58::encode(payload).with_check().into_string())
+            }
+            PubkeyHash::Schnorr(_) => None,
+        }
+    }
+
+    /// Bech32 (version 0, ECDSA) or bech32m (version 2, Schnorr)
+    pub fn to_bech32(&self) -> String {
+        let hrp = bech32_hrp(self.network);
+        let (version, program) = match &self.hash {
+            PubkeyHash::Ecdsa(hash) => (ECDSA_WITNESS_VERSION, hash),
+            PubkeyHash::Schnorr(hash) => (SCHNORR_WITNESS_VERSION, hash),
+        };
+        bech32::segwit::encode(hrp, version, program)
+            .expect("BUG: 20-byte programs are always encodable")
+    }
+
+    fn from_base58check(s: &str) -> Result<Self, BtcError> {
+        let payload = bs58::decode(s)
+            .with_check(None)
+            .into_vec()
+            .map_err(|_| BtcError::InvalidAddress)?;
+        let (version, hash) = payload.split_first().ok_or(BtcError::InvalidAddress)?;
+        let network = match *version {
+            0x00 => Network::Mainnet,
+            // testnet and regtest share a version byte, like in Bitcoin
+            0x6f => Network::Testnet,
+            _ => return Err(BtcError::InvalidAddress),
+        };
+        let hash = hash.try_into().map_err(|_| BtcError::InvalidAddress)?;
+        Ok(Address::new(network, PubkeyHash::Ecdsa(hash)))
+    }
+
+    fn from_bech32(s: &str) -> Result<Self, BtcError> {
+        let (hrp, version, program) =
+            bech32::segwit::decode(s).map_err(|_| BtcError::InvalidAddress)?;
+        let network = [Network::Mainnet, Network::Testnet, Network::Regtest]
+            .into_iter()
+            .find(|network| bech32_hrp(*network) == hrp)
+            .ok_or(BtcError::InvalidAddress)?;
+        let hash: [u8; 20] = program
+            .as_slice()
+            .try_into()
+            .map_err(|_| BtcError::InvalidAddress)?;
+        let hash = match version {
+            ECDSA_WITNESS_VERSION => PubkeyHash::Ecdsa(hash),
+            SCHNORR_WITNESS_VERSION => PubkeyHash::Schnorr(hash),
+            _ => return Err(BtcError::InvalidAddress),
+        };
+        Ok(Address::new(network, hash))
+    }
+
+    /// Whether the address can be used on `network`. Legacy
+    /// addresses cannot tell testnet and regtest apart.
+    pub fn is_valid_for(&self, network: Network) -> bool {
+        self.network == network
+            || (self.to_base58check().is_some()
+                && self.network == Network::Testnet
+                && network == Network::Regtest)
+    }
+}
+
+fn base58_version(network: Network) -> u8 {
+    match network {
+        Network::Mainnet => 0x00,
+        Network::Testnet | Network::Regtest => 0x6f,
+    }
+}
+
+fn bech32_hrp(network: Network) -> Hrp {
+    match network {
+        Network::Mainnet => hrp::BC,
+        Network::Testnet => hrp::TB,
+        Network::Regtest => hrp::BCRT,
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_bech32())
+    }
+}
+
+/// Parses both bech32(m) and Base58Check addresses
+impl FromStr for Address {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Address::from_bech32(s).or_else(|_| Address::from_base58check(s))
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PublicKey, SchnorrPublicKey};
+
+    fn generator_key() -> PublicKey {
+        let key = hex::decode("0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798").unwrap();
+        PublicKey::from_sec1(&key).unwrap()
+    }
+
+    #[test]
+    fn ecdsa_addresses_match_bitcoin() {
+        // from BIP173, and the P2PKH address of the same key
+        let key = VersionedPublicKey::Ecdsa(generator_key());
+        let address = Address::from_public_key(Network::Mainnet, &key);
+        assert_eq!(address.to_string(), "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+        assert_eq!(
+            address.to_base58check().unwrap(),
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"
+        );
+        for encoded in [address.to_string(), address.to_base58check().unwrap()] {
+            assert_eq!(encoded.parse::<Address>().unwrap(), address);
+        }
+    }
+
+    #[test]
+    fn schnorr_addresses_are_not_taproot() {
+        let key = VersionedPublicKey::Schnorr(SchnorrPublicKey::from(&generator_key()));
+        let address = Address::from_public_key(Network::Testnet, &key);
+        let encoded = address.to_string();
+        let (_, version, program) = bech32::segwit::decode(&encoded).unwrap();
+        assert_eq!(version, Fe32::Z);
+        assert_eq!(program.len(), 20);
+        assert!(encoded.starts_with("tb1z"));
+        assert_eq!(encoded.parse::<Address>().unwrap(), address);
+        assert_eq!(address.to_base58check(), None);
+    }
+
+    #[test]
+    fn other_witness_programs_are_rejected() {
+        // a taproot address from BIP350
+        let taproot = "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0";
+        assert!(taproot.parse::<Address>().is_err());
+        // a P2WSH address from BIP173
+        let script = "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3";
+        assert!(script.parse::<Address>().is_err());
+    }
+}
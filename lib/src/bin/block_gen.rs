@@ -1,41 +1,26 @@
 use std::process::exit;
-use chrono::Utc;
-use uuid::Uuid;
-use lib::crypto::PrivateKey;
-use lib::{INITIAL_REWARD, MIN_TARGET};
-use lib::sha256::Hash;
-use lib::types::{Block, BlockHeader, Transaction, TransactionOutput};
-use lib::utils::{MerkleRoot, Saveable};
+use lib::network::Network;
+use lib::types::Block;
+use lib::utils::Saveable;
 
+// writes the genesis block of a network, which every chain on it
+// starts with
 fn main() {
 
-    let path = if let Some(arg) = std::env::args().nth(1) {
-        arg
-    }
-    else {
-        eprintln!("Usage: block_gen <block_file>");
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("Usage: block_gen <block_file> [network]");
         exit(1);
     };
-    let private_key = PrivateKey::new_key();
-    let transactions = vec![Transaction::new(
-        vec![],
-        vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
-        }],
-    )];
-    let merkle_root = MerkleRoot::calculate(&transactions);
-    let block = Block::new(
-        BlockHeader::new(
-            Utc::now(),
-            0,
-            Hash::zero(),
-            merkle_root,
-            MIN_TARGET,
-        ),
-        transactions
-    );
+    let network = match args.next().map(|network| network.parse::<Network>()) {
+        None => Network::Mainnet,
+        Some(Ok(network)) => network,
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            exit(1);
+        }
+    };
+    let block = Block::genesis(network);
     block.save_to_file(path).expect("Failed to save block")
 
-}
\ No newline at end of file
+}
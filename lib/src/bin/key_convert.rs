@@ -0,0 +1,172 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::process::exit;
+use lib::crypto::{EncryptedKey, KeyFile, PrivateKey, PublicKey};
+use lib::network::Network;
+use lib::utils::Saveable;
+use zeroize::Zeroizing;
+
+const USAGE: &str = "Usage:
+    key_convert private <from> <to> <input> [output] [--network <network>] [--encrypt]
+        formats: cbor (our .priv.cbor), wif, pem (PKCS#8), sec1 (SEC1 PEM, input only)
+    key_convert public <from> <to> <input> [output]
+        formats: pem (SPKI, our .pub.pem), hex (SEC1 compressed), hex-uncompressed
+
+Text output goes to stdout when no output file is given.
+WIF output is for mainnet unless --network says otherwise.";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let encrypt = take_flag(&mut args, "--encrypt");
+    let network = take_option(&mut args, "--network").map(|network| {
+        network.parse::<Network>().unwrap_or_else(|e| fail(&e))
+    });
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["private", from, to, input, output @ ..] if output.len() <= 1 => {
+            let key = read_private(from, input);
+            write_private(&key, to, output.first().copied(), network, encrypt);
+        }
+        ["public", from, to, input, output @ ..] if output.len() <= 1 && !encrypt => {
+            let key = read_public(from, input);
+            write_public(&key, to, output.first().copied());
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            exit(1);
+        }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    exit(1);
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let found = args.iter().any(|arg| arg == flag);
+    args.retain(|arg| arg != flag);
+    found
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    if index + 1 >= args.len() {
+        fail(&format!("{name} needs a value"));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+fn read_text(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("Failed to read {path}: {e}")))
+}
+
+fn read_private(format: &str, path: &str) -> PrivateKey {
+    let key = match format {
+        "cbor" => {
+            let file = KeyFile::<PrivateKey>::load_from_file(path)
+                .unwrap_or_else(|e| fail(&format!("Failed to read {path}: {e}")));
+            let password = if file.is_encrypted() {
+                rpassword::prompt_password(format!("Password for {path}: "))
+                    .unwrap_or_else(|e| fail(&e.to_string()))
+            } else {
+                String::new()
+            };
+            file.unlock(&password)
+        }
+        "wif" => PrivateKey::from_wif(&read_text(path)).map(|(key, network)| {
+            eprintln!("WIF key for {network}");
+            key
+        }),
+        "pem" => PrivateKey::from_pkcs8_pem(&read_text(path)),
+        "sec1" => PrivateKey::from_sec1_pem(&read_text(path)),
+        _ => fail(&format!("Unknown private key format: {format}")),
+    };
+    key.unwrap_or_else(|e| fail(&format!("{path}: {e}")))
+}
+
+fn write_private(
+    key: &PrivateKey,
+    format: &str,
+    output: Option<&str>,
+    network: Option<Network>,
+    encrypt: bool,
+) {
+    if encrypt && format != "cbor" {
+        fail("Only cbor output can be encrypted");
+    }
+    let text = match format {
+        "cbor" => {
+            let path = output.unwrap_or_else(|| fail("cbor output needs an output file"));
+            let mut bytes = Zeroizing::new(vec![]);
+            let saved = if encrypt {
+                let password = rpassword::prompt_password("New password: ")
+                    .unwrap_or_else(|e| fail(&e.to_string()));
+                if password.is_empty() {
+                    fail("The password cannot be empty");
+                }
+                EncryptedKey::seal(key, &password)
+                    .map_err(|e| e.to_string())
+                    .and_then(|sealed| sealed.save(&mut *bytes).map_err(|e| e.to_string()))
+            } else {
+                key.save(&mut *bytes).map_err(|e| e.to_string())
+            };
+            saved.unwrap_or_else(|e| fail(&format!("Failed to write {path}: {e}")));
+            write_secret(&bytes, path);
+            return;
+        }
+        "wif" => Zeroizing::new(format!("{}\n", key.to_wif(network.unwrap_or_default()).as_str())),
+        "pem" => key.to_pkcs8_pem().unwrap_or_else(|e| fail(&e.to_string())),
+        _ => fail(&format!("Unknown private key format: {format}")),
+    };
+    match output {
+        Some(path) => write_secret(text.as_bytes(), path),
+        None => print!("{}", text.as_str()),
+    }
+}
+
+// private key files are readable only by their owner, even
+// when they replace a file that was not
+fn write_secret(bytes: &[u8], path: &str) {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let written = options.open(path).and_then(|mut file| {
+        #[cfg(unix)]
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(bytes)
+    });
+    written.unwrap_or_else(|e| fail(&format!("Failed to write {path}: {e}")));
+}
+
+fn read_public(format: &str, path: &str) -> PublicKey {
+    let key = match format {
+        "pem" => PublicKey::from_spki_pem(&read_text(path)),
+        "hex" | "hex-uncompressed" => PublicKey::from_sec1_hex(&read_text(path)),
+        _ => fail(&format!("Unknown public key format: {format}")),
+    };
+    key.unwrap_or_else(|e| fail(&format!("{path}: {e}")))
+}
+
+fn write_public(key: &PublicKey, format: &str, output: Option<&str>) {
+    let text = match format {
+        "pem" => key.to_spki_pem().unwrap_or_else(|e| fail(&e.to_string())),
+        "hex" => format!("{}\n", hex::encode(key.to_sec1())),
+        "hex-uncompressed" => format!("{}\n", hex::encode(key.to_sec1_uncompressed())),
+        _ => fail(&format!("Unknown public key format: {format}")),
+    };
+    write_text(&text, output);
+}
+
+fn write_text(text: &str, output: Option<&str>) {
+    match output {
+        Some(path) => fs::write(path, text)
+            .unwrap_or_else(|e| fail(&format!("Failed to write {path}: {e}"))),
+        None => print!("{text}"),
+    }
+}
@@ -1,14 +1,411 @@
 use std::env;
-use lib::crypto::PrivateKey;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::exit;
+use lib::crypto::{
+    AggregateNonce, DerivationPath, EncryptedKey, ExtendedPrivateKey, KeyAggContext, KeyFile,
+    MuSigSession, PartialSignature, PrivateKey, PublicKey, PublicNonce, SecretNonce,
+};
+use lib::network::Network;
+use lib::sha256::Hash;
 use lib::utils::Saveable;
+use zeroize::Zeroizing;
+
+const USAGE: &str = "Usage:
+    key_gen [--encrypt] <name>
+    key_gen encrypt <name.priv.cbor|name.xprv>
+    key_gen musig aggregate <pub.pem>...
+    key_gen musig nonce <name> <session_dir> <txid> <pub.pem>...
+    key_gen musig sign <name> <session_dir> <txid> <pub.pem>...
+    key_gen musig combine <session_dir> <txid> <pub.pem>...
+    key_gen hd new [--encrypt] <name> [mainnet|testnet|regtest]
+    key_gen hd xpub <name> [path]
+    key_gen hd derive [--encrypt] <name> <path> <out_name>";
 
 fn main() {
-    let name = env::args().nth(1).expect("Please provide a name");
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // private keys are written encrypted when --encrypt is given
+    let encrypt = args.iter().any(|arg| arg == "--encrypt");
+    args.retain(|arg| arg != "--encrypt");
+    match args.first().map(String::as_str) {
+        Some("musig") => musig(&args[1..]),
+        Some("hd") => hd(&args[1..], encrypt),
+        Some("encrypt") => match args.get(1..) {
+            Some([path]) => encrypt_file(path),
+            _ => usage(),
+        },
+        Some(name) => generate(name, encrypt),
+        None => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    exit(1);
+}
+
+fn generate(name: &str, encrypt: bool) {
     let private_key = PrivateKey::new_key();
     let public_key = private_key.public_key();
-    let public_key_file = name.clone() + ".pub.pem";
-    let private_key_file = name + ".priv.cbor";
-    private_key.save_to_file(&private_key_file).unwrap();
+    let public_key_file = name.to_owned() + ".pub.pem";
+    let private_key_file = name.to_owned() + ".priv.cbor";
+    save_private(&private_key, &private_key_file, encrypt);
     public_key.save_to_file(&public_key_file).unwrap();
-    
-}
\ No newline at end of file
+}
+
+fn save_private<T: Saveable>(key: &T, path: &str, encrypt: bool) {
+    let mut bytes = Zeroizing::new(vec![]);
+    if encrypt {
+        EncryptedKey::seal(key, &new_password())
+            .expect("Failed to encrypt key")
+            .save(&mut *bytes)
+    } else {
+        key.save(&mut *bytes)
+    }
+    .expect("Failed to serialize key");
+    write_private(path, &bytes).expect("Failed to save key");
+}
+
+// Plaintext key files can still be read, and encrypted ones
+// ask for their password
+fn load_private<T: Saveable>(path: &str) -> T {
+    unlock_private(path).0
+}
+
+// The key, and the password it was encrypted with, if it was
+fn unlock_private<T: Saveable>(path: &str) -> (T, Option<String>) {
+    let file = KeyFile::<T>::load_from_file(path).unwrap_or_else(|e| {
+        eprintln!("Failed to load {path}: {e}");
+        exit(1);
+    });
+    let password = file.is_encrypted().then(|| {
+        rpassword::prompt_password(format!("Password for {path}: ")).expect("Failed to read password")
+    });
+    let key = file.unlock(password.as_deref().unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        exit(1);
+    });
+    (key, password)
+}
+
+// Write a file only its owner can read. It replaces `path` in
+// one step, so an interrupted write cannot lose what was there.
+fn write_private(path: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn new_password() -> String {
+    let password = rpassword::prompt_password("New password: ").expect("Failed to read password");
+    let confirmation =
+        rpassword::prompt_password("Repeat password: ").expect("Failed to read password");
+    if password.is_empty() || password != confirmation {
+        eprintln!("Passwords are empty or do not match");
+        exit(1);
+    }
+    password
+}
+
+// Encrypt a key file in place. Already encrypted files get a
+// new password.
+fn encrypt_file(path: &str) {
+    if path.ends_with(".xprv") {
+        let key: ExtendedPrivateKey = load_private(path);
+        save_private(&key, path, true);
+    } else {
+        let key: PrivateKey = load_private(path);
+        save_private(&key, path, true);
+    }
+    println!("{path} encrypted");
+}
+
+// MuSig2 demo: every signer runs `nonce`, then `sign` once all
+// public nonces are in the shared session directory, and anyone
+// can `combine` the partial signatures into the final signature.
+// Secret nonces never go into the session directory: with one and
+// the partial signature made with it, anyone can compute the
+// signer's private key. They are kept in <name>.secnonce instead,
+// encrypted like <name>.priv.cbor.
+fn musig(args: &[String]) {
+    match (args.first().map(String::as_str), args.get(1..)) {
+        (Some("aggregate"), Some(keys)) if !keys.is_empty() => {
+            let key_agg = key_agg(keys);
+            println!("{}", hex::encode(key_agg.aggregated_key().to_bytes()));
+        }
+        (Some("nonce"), Some([name, session, txid, keys @ ..])) if !keys.is_empty() => {
+            let (private_key, public_key, password) = load_key_pair(name);
+            let key_agg = key_agg(keys);
+            let txid = parse_txid(txid);
+            let (secret_nonce, public_nonce) = SecretNonce::generate(
+                Some(&private_key),
+                &public_key,
+                Some(&key_agg),
+                Some(&txid),
+            );
+            save_secret_nonce(&secret_nonce_file(name), &secret_nonce, password.as_deref())
+                .expect("Failed to save secret nonce");
+            fs::create_dir_all(session).expect("Failed to create session directory");
+            write_hex(session, &public_key, "pubnonce", &public_nonce.to_bytes());
+            println!("public nonce written to {session}");
+        }
+        (Some("sign"), Some([name, session, txid, keys @ ..])) if !keys.is_empty() => {
+            let (private_key, public_key, password) = load_key_pair(name);
+            let key_agg = key_agg(keys);
+            let txid = parse_txid(txid);
+            let public_nonces: Vec<PublicNonce> = load_keys(keys)
+                .iter()
+                .map(|key| {
+                    PublicNonce::from_bytes(&read_hex(session, key, "pubnonce"))
+                        .expect("Invalid public nonce")
+                })
+                .collect();
+            let aggregate_nonce =
+                AggregateNonce::sum(&public_nonces).expect("Failed to aggregate nonces");
+
+            let path = secret_nonce_file(name);
+            let secret_nonce = take_secret_nonce(&path, password.as_deref()).unwrap_or_else(|e| {
+                eprintln!("No usable secret nonce in {path} ({e}), run `musig nonce` again");
+                exit(1);
+            });
+
+            let session_ctx = MuSigSession::new(&key_agg, &aggregate_nonce, &txid)
+                .expect("Failed to start signing session");
+            let partial = session_ctx
+                .sign(secret_nonce, &private_key)
+                .expect("Failed to sign");
+            write_hex(session, &public_key, "psig", &partial.to_bytes());
+            println!("partial signature written to {session}");
+        }
+        (Some("combine"), Some([session, txid, keys @ ..])) if !keys.is_empty() => {
+            let key_agg = key_agg(keys);
+            let txid = parse_txid(txid);
+            let public_keys = load_keys(keys);
+            let public_nonces: Vec<PublicNonce> = public_keys
+                .iter()
+                .map(|key| {
+                    PublicNonce::from_bytes(&read_hex(session, key, "pubnonce"))
+                        .expect("Invalid public nonce")
+                })
+                .collect();
+            let aggregate_nonce =
+                AggregateNonce::sum(&public_nonces).expect("Failed to aggregate nonces");
+            let session_ctx = MuSigSession::new(&key_agg, &aggregate_nonce, &txid)
+                .expect("Failed to start signing session");
+
+            let mut partials = vec![];
+            for (key, nonce) in public_keys.iter().zip(&public_nonces) {
+                let partial = PartialSignature::from_bytes(&read_hex(session, key, "psig"))
+                    .expect("Invalid partial signature");
+                if !session_ctx.verify_partial(&partial, nonce, key) {
+                    eprintln!("Invalid partial signature from {}", hex::encode(key.to_sec1()));
+                    exit(1);
+                }
+                partials.push(partial);
+            }
+            let signature = session_ctx
+                .aggregate(&partials)
+                .expect("Failed to aggregate signatures");
+            if !signature.verify(&txid, &key_agg.aggregated_key()) {
+                eprintln!("Aggregated signature does not verify");
+                exit(1);
+            }
+            println!("{}", hex::encode(signature.to_bytes()));
+        }
+        _ => usage(),
+    }
+}
+
+// BIP32 keys: `new` writes a master key to <name>.xprv, which is
+// the only file that needs a backup. `derive` writes the key at a
+// path as a regular key pair, so it can be listed in a wallet.
+fn hd(args: &[String], encrypt: bool) {
+    match (args.first().map(String::as_str), args.get(1..)) {
+        (Some("new"), Some([name, network @ ..])) if network.len() <= 1 => {
+            let network: Network = match network.first() {
+                Some(network) => network.parse().unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    exit(1);
+                }),
+                None => Network::default(),
+            };
+            let mut seed = [0u8; 32];
+            rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut seed);
+            let master = ExtendedPrivateKey::new_master(&seed, network)
+                .expect("Failed to create master key");
+            save_private(&master, &format!("{name}.xprv"), encrypt);
+            println!("{}", master.public_key());
+        }
+        (Some("xpub"), Some([name, path @ ..])) if path.len() <= 1 => {
+            let path = path.first().map_or_else(DerivationPath::master, |path| parse_path(path));
+            let key = load_xprv(name).derive_path(&path).expect("Failed to derive key");
+            println!("{}", key.public_key());
+        }
+        (Some("derive"), Some([name, path, out_name])) => {
+            let key = load_xprv(name)
+                .derive_path(&parse_path(path))
+                .expect("Failed to derive key");
+            save_private(&key.private_key, &format!("{out_name}.priv.cbor"), encrypt);
+            key.private_key
+                .public_key()
+                .save_to_file(format!("{out_name}.pub.pem"))
+                .unwrap();
+        }
+        _ => usage(),
+    }
+}
+
+fn load_xprv(name: &str) -> ExtendedPrivateKey {
+    load_private(&format!("{name}.xprv"))
+}
+
+fn parse_path(path: &str) -> DerivationPath {
+    path.parse().unwrap_or_else(|_| {
+        eprintln!("<path> should look like m/84'/0'/0'/0/1");
+        exit(1);
+    })
+}
+
+fn load_key_pair(name: &str) -> (PrivateKey, PublicKey, Option<String>) {
+    let (private_key, password): (PrivateKey, _) = unlock_private(&format!("{name}.priv.cbor"));
+    let public_key = private_key.public_key();
+    (private_key, public_key, password)
+}
+
+// keys are sorted, so every signer gets the same aggregate
+// no matter the order they were listed in
+fn load_keys(paths: &[String]) -> Vec<PublicKey> {
+    let mut keys: Vec<PublicKey> = paths
+        .iter()
+        .map(|path| PublicKey::load_from_file(path).expect("Failed to load public key"))
+        .collect();
+    KeyAggContext::sort_keys(&mut keys);
+    keys
+}
+
+fn key_agg(paths: &[String]) -> KeyAggContext {
+    KeyAggContext::new(&load_keys(paths)).expect("Failed to aggregate keys")
+}
+
+fn parse_txid(txid: &str) -> Hash {
+    txid.parse().unwrap_or_else(|_| {
+        eprintln!("<txid> should be 64 hex characters");
+        exit(1);
+    })
+}
+
+fn secret_nonce_file(name: &str) -> String {
+    format!("{name}.secnonce")
+}
+
+fn save_secret_nonce(path: &str, nonce: &SecretNonce, password: Option<&str>) -> io::Result<()> {
+    let mut bytes = Zeroizing::new(vec![]);
+    match password {
+        Some(password) => EncryptedKey::seal(nonce, password)
+            .map_err(io::Error::other)?
+            .save(&mut *bytes)?,
+        None => nonce.save(&mut *bytes)?,
+    }
+    write_private(path, &bytes)
+}
+
+// The secret nonce is deleted before it is used, so it signs
+// at most once even if signing is interrupted
+fn take_secret_nonce(path: &str, password: Option<&str>) -> io::Result<SecretNonce> {
+    let nonce = KeyFile::<SecretNonce>::load_from_file(path)?
+        .unlock(password.unwrap_or_default())
+        .map_err(io::Error::other)?;
+    fs::remove_file(path)?;
+    Ok(nonce)
+}
+
+fn session_file(session: &str, key: &PublicKey, extension: &str) -> String {
+    Path::new(session)
+        .join(format!("{}.{extension}", hex::encode(key.to_sec1())))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn write_hex(session: &str, key: &PublicKey, extension: &str, bytes: &[u8]) {
+    fs::write(session_file(session, key, extension), hex::encode(bytes))
+        .expect("Failed to write session file");
+}
+
+fn read_hex(session: &str, key: &PublicKey, extension: &str) -> Vec<u8> {
+    let path = session_file(session, key, extension);
+    let content = fs::read_to_string(&path).unwrap_or_else(|_| {
+        eprintln!("Missing {path}, has every signer finished the previous step?");
+        exit(1);
+    });
+    hex::decode(content.trim()).expect("Session file is not hex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::crypto::{MuSigSession, PublicNonce};
+
+    fn temp_path(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("key_gen_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    fn new_nonce(private_key: &PrivateKey) -> (SecretNonce, PublicNonce) {
+        SecretNonce::generate(Some(private_key), &private_key.public_key(), None, None)
+    }
+
+    #[test]
+    fn secret_nonce_signs_once() {
+        let private_key = PrivateKey::new_key();
+        let key_agg = KeyAggContext::new(&[private_key.public_key()]).unwrap();
+        let (secret_nonce, public_nonce) = new_nonce(&private_key);
+        let path = temp_path("once.secnonce");
+        save_secret_nonce(&path, &secret_nonce, None).unwrap();
+
+        let txid = Hash::hash_bytes(b"tx");
+        let aggregate_nonce = AggregateNonce::sum(&[public_nonce]).unwrap();
+        let session = MuSigSession::new(&key_agg, &aggregate_nonce, &txid).unwrap();
+        let nonce = take_secret_nonce(&path, None).unwrap();
+        assert!(session.sign(nonce, &private_key).is_ok());
+
+        // so a second signature finds no nonce to use
+        assert!(!Path::new(&path).exists());
+        assert!(take_secret_nonce(&path, None).is_err());
+    }
+
+    #[test]
+    fn secret_nonce_is_private_and_encrypted_with_the_key() {
+        let private_key = PrivateKey::new_key();
+        let (secret_nonce, _) = new_nonce(&private_key);
+        let path = temp_path("encrypted.secnonce");
+        save_secret_nonce(&path, &secret_nonce, Some("password")).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let stored = fs::read(&path).unwrap();
+        assert!(!stored
+            .windows(32)
+            .any(|window| window == &secret_nonce.to_bytes()[..32]));
+        assert!(KeyFile::<SecretNonce>::load_from_file(&path).unwrap().is_encrypted());
+
+        // a wrong password leaves the nonce in place
+        assert!(take_secret_nonce(&path, Some("wrong")).is_err());
+        let nonce = take_secret_nonce(&path, Some("password")).unwrap();
+        assert_eq!(nonce.to_bytes(), secret_nonce.to_bytes());
+    }
+}
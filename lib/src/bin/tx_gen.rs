@@ -1,6 +1,5 @@
 use std::process::exit;
-use uuid::Uuid;
-use lib::crypto::PrivateKey;
+use lib::crypto::{PrivateKey, VersionedPublicKey};
 use lib::INITIAL_REWARD;
 use lib::types::{Transaction, TransactionOutput};
 use lib::utils::Saveable;
@@ -17,9 +16,8 @@ fn main() {
     let transaction = Transaction::new(
         vec![],
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
             value: INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
+            lock: VersionedPublicKey::from(private_key.public_key()).into(),
         }],
     );
     transaction.save_to_file(path).expect(
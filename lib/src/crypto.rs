@@ -5,28 +5,145 @@ use ecdsa::{signature::Signer, Signature as ECDSASignature, SigningKey, Verifyin
 use k256::Secp256k1;
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
-use spki::EncodePublicKey;
+
+mod bip32;
+mod bip39;
+mod formats;
+mod keystore;
+mod message;
+mod musig;
+mod schnorr;
+
+pub use bip32::{ChildNumber, DerivationPath, ExtendedPrivateKey, ExtendedPublicKey};
+pub use bip39::Mnemonic;
+pub use keystore::{EncryptedKey, Kdf, KeyFile};
+pub use message::{message_hash, SignedMessage};
+pub use musig::{
+    AggregateNonce, KeyAggContext, MuSigSession, PartialSignature, PublicNonce, SecretNonce,
+};
+pub use schnorr::{SchnorrPublicKey, SchnorrSignature};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Signature(pub ECDSASignature<Secp256k1>);
 
 impl Signature {
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    /// Sign a transaction id. Signatures are always produced in
+    /// low-S form, so they cannot be re-encoded by third parties.
+    pub fn sign(txid: &Hash, private_key: &PrivateKey) -> Self {
         let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
-        Signature(signature)
+        let signature: ECDSASignature<Secp256k1> = signing_key.sign(&txid.as_bytes());
+        Signature(signature.normalize_s().unwrap_or(signature))
+    }
+
+    /// Verify a signature over a transaction id. High-S
+    /// signatures are rejected.
+    pub fn verify(&self, txid: &Hash, public_key: &PublicKey) -> bool {
+        self.is_low_s()
+            && public_key
+                .0
+                .verify(&txid.as_bytes(), &self.0)
+                .is_ok()
+    }
+
+    pub fn is_low_s(&self) -> bool {
+        self.0.normalize_s().is_none()
+    }
+
+    /// Fixed-size r || s encoding
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0.to_bytes().into()
     }
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        ECDSASignature::from_slice(bytes).ok().map(Signature)
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Ord, PartialOrd)]
 pub struct PublicKey(pub VerifyingKey<Secp256k1>);
 
+impl PublicKey {
+    /// SEC1 compressed encoding
+    pub fn to_sec1(&self) -> [u8; 33] {
+        self.0
+            .to_encoded_point(true)
+            .as_bytes()
+            .try_into()
+            .expect("BUG: compressed point is 33 bytes")
+    }
+
+    pub fn from_sec1(bytes: &[u8]) -> Option<Self> {
+        VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey)
+    }
+}
+
+/// A public key tagged with the signature scheme it expects.
+/// This is what transaction outputs lock to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Ord, PartialOrd)]
+pub enum VersionedPublicKey {
+    /// ECDSA over secp256k1
+    Ecdsa(PublicKey),
+    /// BIP340 Schnorr over secp256k1
+    Schnorr(SchnorrPublicKey),
+}
+
+/// A signature tagged with the scheme that produced it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum VersionedSignature {
+    Ecdsa(Signature),
+    Schnorr(SchnorrSignature),
+}
+
+impl VersionedPublicKey {
+    /// Verify a signature over a txid. The signature has to be
+    /// of the same scheme as the key.
+    pub fn verify(&self, txid: &Hash, signature: &VersionedSignature) -> bool {
+        match (self, signature) {
+            (VersionedPublicKey::Ecdsa(key), VersionedSignature::Ecdsa(sig)) => {
+                sig.verify(txid, key)
+            }
+            (VersionedPublicKey::Schnorr(key), VersionedSignature::Schnorr(sig)) => {
+                sig.verify(txid, key)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `private_key` can sign for this key
+    pub fn is_controlled_by(&self, private_key: &PrivateKey) -> bool {
+        match self {
+            VersionedPublicKey::Ecdsa(key) => *key == private_key.public_key(),
+            VersionedPublicKey::Schnorr(key) => *key == private_key.schnorr_public_key(),
+        }
+    }
+}
+
+impl From<PublicKey> for VersionedPublicKey {
+    fn from(public_key: PublicKey) -> Self {
+        VersionedPublicKey::Ecdsa(public_key)
+    }
+}
+
+impl From<SchnorrPublicKey> for VersionedPublicKey {
+    fn from(public_key: SchnorrPublicKey) -> Self {
+        VersionedPublicKey::Schnorr(public_key)
+    }
+}
+
+impl VersionedSignature {
+    /// Sign a txid with the scheme `public_key` expects
+    pub fn sign(txid: &Hash, private_key: &PrivateKey, public_key: &VersionedPublicKey) -> Self {
+        match public_key {
+            VersionedPublicKey::Ecdsa(_) => {
+                VersionedSignature::Ecdsa(Signature::sign(txid, private_key))
+            }
+            VersionedPublicKey::Schnorr(_) => {
+                VersionedSignature::Schnorr(SchnorrSignature::sign(txid, private_key))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>);
 
@@ -48,7 +165,8 @@ mod signkey_serde {
         D: serde::Deserializer<'de>,
     {
         let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
-        Ok(super::SigningKey::from_slice(&bytes).unwrap())
+        super::SigningKey::from_slice(&bytes)
+            .map_err(|_| serde::de::Error::custom("invalid private key"))
     }
 }
 impl PrivateKey {
@@ -56,7 +174,7 @@ impl PrivateKey {
         PrivateKey(SigningKey::random(&mut rand::thread_rng()))
     }
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
     }
 }
 
@@ -77,12 +195,12 @@ impl Saveable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
         let mut buf = String::new();
         reader.read_to_string(&mut buf)?;
-        let public_key = buf.parse().map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to parse PublicKey"))?;
-        Ok(PublicKey(public_key))
+        PublicKey::from_spki_pem(&buf)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to parse PublicKey"))
     }
 
     fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
-        let s = self.0.to_public_key_pem(Default::default())
+        let s = self.to_spki_pem()
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize PublicKey"))?;
         writer.write_all(s.as_bytes())?;
         Ok(())
@@ -0,0 +1,647 @@
+// BIP32 hierarchical deterministic keys
+use crate::address::hash160;
+use crate::error::{BtcError, Result};
+use crate::network::Network;
+use crate::utils::Saveable;
+use ecdsa::{SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::PrimeField;
+use k256::{FieldBytes, NonZeroScalar, ProjectivePoint, Scalar};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha512;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::str::FromStr;
+
+use super::{PrivateKey, PublicKey};
+
+type HmacSha512 = Hmac<Sha512>;
+
+const MASTER_KEY_SALT: &[u8] = b"Bitcoin seed";
+const XPRV_MAINNET: [u8; 4] = [0x04, 0x88, 0xad, 0xe4];
+const XPUB_MAINNET: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const XPRV_TESTNET: [u8; 4] = [0x04, 0x35, 0x83, 0x94];
+const XPUB_TESTNET: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+const SERIALIZED_LEN: usize = 78;
+
+/// Index of a child key. Indices from 2^31 up are hardened:
+/// they can only be derived from the parent private key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChildNumber(u32);
+
+impl ChildNumber {
+    pub const HARDENED: u32 = 1 << 31;
+
+    pub fn normal(index: u32) -> Result<Self> {
+        if index >= Self::HARDENED {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        Ok(ChildNumber(index))
+    }
+
+    pub fn hardened(index: u32) -> Result<Self> {
+        if index >= Self::HARDENED {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        Ok(ChildNumber(index | Self::HARDENED))
+    }
+
+    pub fn is_hardened(&self) -> bool {
+        self.0 & Self::HARDENED != 0
+    }
+
+    /// Index without the hardened bit
+    pub fn index(&self) -> u32 {
+        self.0 & !Self::HARDENED
+    }
+
+    pub fn to_u32(&self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for ChildNumber {
+    fn from(value: u32) -> Self {
+        ChildNumber(value)
+    }
+}
+
+impl fmt::Display for ChildNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_hardened() {
+            write!(f, "{}'", self.index())
+        } else {
+            write!(f, "{}", self.index())
+        }
+    }
+}
+
+/// Accepts `44'` and `44h` for hardened indices
+impl FromStr for ChildNumber {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, hardened) = match s.strip_suffix(['\'', 'h', 'H']) {
+            Some(index) => (index, true),
+            None => (s, false),
+        };
+        let index: u32 = index.parse().map_err(|_| BtcError::InvalidDerivationPath)?;
+        if hardened {
+            ChildNumber::hardened(index)
+        } else {
+            ChildNumber::normal(index)
+        }
+    }
+}
+
+/// A path from the master key down to a descendant, such as
+/// `m/84'/0'/0'/0/5`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DerivationPath(Vec<ChildNumber>);
+
+impl DerivationPath {
+    pub fn master() -> Self {
+        DerivationPath(vec![])
+    }
+
+    pub fn child(&self, child: ChildNumber) -> Self {
+        let mut path = self.0.clone();
+        path.push(child);
+        DerivationPath(path)
+    }
+
+    pub fn as_slice(&self) -> &[ChildNumber] {
+        &self.0
+    }
+}
+
+impl From<Vec<ChildNumber>> for DerivationPath {
+    fn from(path: Vec<ChildNumber>) -> Self {
+        DerivationPath(path)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for child in &self.0 {
+            write!(f, "/{child}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        parts
+            .map(ChildNumber::from_str)
+            .collect::<Result<Vec<_>>>()
+            .map(DerivationPath)
+    }
+}
+
+/// Extended private key: a private key plus the chain code
+/// needed to derive its children
+#[derive(Debug, Clone)]
+pub struct ExtendedPrivateKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: ChildNumber,
+    pub chain_code: [u8; 32],
+    pub private_key: PrivateKey,
+}
+
+/// Extended public key. Can derive non-hardened children
+/// without access to any private key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedPublicKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: ChildNumber,
+    pub chain_code: [u8; 32],
+    pub public_key: PublicKey,
+}
+
+// HMAC-SHA512, split into the tweak (left half) and the chain
+// code (right half)
+fn hmac_split(key: &[u8], data: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = HmacSha512::new_from_slice(key).expect("BUG: HMAC takes keys of any length");
+    for chunk in data {
+        mac.update(chunk);
+    }
+    let result = mac.finalize().into_bytes();
+    let mut left = [0u8; 32];
+    let mut right = [0u8; 32];
+    left.copy_from_slice(&result[..32]);
+    right.copy_from_slice(&result[32..]);
+    (left, right)
+}
+
+// the tweak has to be a valid scalar, which fails with
+// probability lower than 1 in 2^127
+fn parse_tweak(bytes: [u8; 32]) -> Result<Scalar> {
+    Option::from(Scalar::from_repr(FieldBytes::from(bytes))).ok_or(BtcError::InvalidExtendedKey)
+}
+
+fn fingerprint(public_key: &PublicKey) -> [u8; 4] {
+    let hash = hash160(&public_key.to_sec1());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+impl ExtendedPrivateKey {
+    /// Master key from a seed of 16 to 64 bytes
+    pub fn new_master(seed: &[u8], network: Network) -> Result<Self> {
+        if !(16..=64).contains(&seed.len()) {
+            return Err(BtcError::InvalidExtendedKey);
+        }
+        let (key, chain_code) = hmac_split(MASTER_KEY_SALT, &[seed]);
+        let scalar = parse_tweak(key)?;
+        Ok(ExtendedPrivateKey {
+            network,
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: ChildNumber(0),
+            chain_code,
+            private_key: private_key_from_scalar(scalar)?,
+        })
+    }
+
+    pub fn public_key(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            network: self.network,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            public_key: self.private_key.public_key(),
+        }
+    }
+
+    /// First four bytes of HASH160 of the public key, which
+    /// children store as their parent fingerprint
+    pub fn fingerprint(&self) -> [u8; 4] {
+        fingerprint(&self.private_key.public_key())
+    }
+
+    pub fn derive_child(&self, child: ChildNumber) -> Result<Self> {
+        let depth = self.depth.checked_add(1).ok_or(BtcError::InvalidDerivationPath)?;
+        let index = child.to_u32().to_be_bytes();
+        let (tweak, chain_code) = if child.is_hardened() {
+            let secret: [u8; 32] = self.private_key.0.to_bytes().into();
+            hmac_split(&self.chain_code, &[&[0], &secret, &index])
+        } else {
+            let public = self.private_key.public_key().to_sec1();
+            hmac_split(&self.chain_code, &[&public, &index])
+        };
+        let scalar = parse_tweak(tweak)? + self.private_key.0.as_nonzero_scalar().as_ref();
+        Ok(ExtendedPrivateKey {
+            network: self.network,
+            depth,
+            parent_fingerprint: self.fingerprint(),
+            child_number: child,
+            chain_code,
+            private_key: private_key_from_scalar(scalar)?,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        path.as_slice()
+            .iter()
+            .try_fold(self.clone(), |key, child| key.derive_child(*child))
+    }
+
+    fn to_bytes(&self) -> [u8; SERIALIZED_LEN] {
+        let version = match self.network {
+            Network::Mainnet => XPRV_MAINNET,
+            Network::Testnet | Network::Regtest => XPRV_TESTNET,
+        };
+        let mut key = [0u8; 33];
+        key[1..].copy_from_slice(&self.private_key.0.to_bytes());
+        serialize(version, self.depth, self.parent_fingerprint, self.child_number, &self.chain_code, &key)
+    }
+}
+
+fn private_key_from_scalar(scalar: Scalar) -> Result<PrivateKey> {
+    let scalar: Option<NonZeroScalar> = NonZeroScalar::new(scalar).into();
+    let scalar = scalar.ok_or(BtcError::InvalidExtendedKey)?;
+    Ok(PrivateKey(SigningKey::from(scalar)))
+}
+
+impl ExtendedPublicKey {
+    pub fn fingerprint(&self) -> [u8; 4] {
+        fingerprint(&self.public_key)
+    }
+
+    /// Derive a non-hardened child. Hardened children need the
+    /// private key.
+    pub fn derive_child(&self, child: ChildNumber) -> Result<Self> {
+        if child.is_hardened() {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        let depth = self.depth.checked_add(1).ok_or(BtcError::InvalidDerivationPath)?;
+        let (tweak, chain_code) = hmac_split(
+            &self.chain_code,
+            &[&self.public_key.to_sec1(), &child.to_u32().to_be_bytes()],
+        );
+        let point = ProjectivePoint::GENERATOR * parse_tweak(tweak)?
+            + ProjectivePoint::from(*self.public_key.0.as_affine());
+        let public_key = VerifyingKey::from_affine(point.to_affine())
+            .map_err(|_| BtcError::InvalidExtendedKey)?;
+        Ok(ExtendedPublicKey {
+            network: self.network,
+            depth,
+            parent_fingerprint: self.fingerprint(),
+            child_number: child,
+            chain_code,
+            public_key: PublicKey(public_key),
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        path.as_slice()
+            .iter()
+            .try_fold(self.clone(), |key, child| key.derive_child(*child))
+    }
+
+    fn to_bytes(&self) -> [u8; SERIALIZED_LEN] {
+        let version = match self.network {
+            Network::Mainnet => XPUB_MAINNET,
+            Network::Testnet | Network::Regtest => XPUB_TESTNET,
+        };
+        serialize(
+            version,
+            self.depth,
+            self.parent_fingerprint,
+            self.child_number,
+            &self.chain_code,
+            &self.public_key.to_sec1(),
+        )
+    }
+}
+
+fn serialize(
+    version: [u8; 4],
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: ChildNumber,
+    chain_code: &[u8; 32],
+    key: &[u8; 33],
+) -> [u8; SERIALIZED_LEN] {
+    let mut bytes = [0u8; SERIALIZED_LEN];
+    bytes[0..4].copy_from_slice(&version);
+    bytes[4] = depth;
+    bytes[5..9].copy_from_slice(&parent_fingerprint);
+    bytes[9..13].copy_from_slice(&child_number.to_u32().to_be_bytes());
+    bytes[13..45].copy_from_slice(chain_code);
+    bytes[45..78].copy_from_slice(key);
+    bytes
+}
+
+/// The fields of a Base58Check-decoded extended key
+struct RawExtendedKey {
+    version: [u8; 4],
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: ChildNumber,
+    chain_code: [u8; 32],
+    key: [u8; 33],
+}
+
+fn deserialize(s: &str) -> Result<RawExtendedKey> {
+    let bytes = bs58::decode(s)
+        .with_check(None)
+        .into_vec()
+        .map_err(|_| BtcError::InvalidExtendedKey)?;
+    if bytes.len() != SERIALIZED_LEN {
+        return Err(BtcError::InvalidExtendedKey);
+    }
+    let raw = RawExtendedKey {
+        version: bytes[0..4].try_into().expect("BUG: length checked"),
+        depth: bytes[4],
+        parent_fingerprint: bytes[5..9].try_into().expect("BUG: length checked"),
+        child_number: ChildNumber(u32::from_be_bytes(
+            bytes[9..13].try_into().expect("BUG: length checked"),
+        )),
+        chain_code: bytes[13..45].try_into().expect("BUG: length checked"),
+        key: bytes[45..78].try_into().expect("BUG: length checked"),
+    };
+    // a master key has neither a parent nor an index
+    if raw.depth == 0 && (raw.parent_fingerprint != [0; 4] || raw.child_number.to_u32() != 0) {
+        return Err(BtcError::InvalidExtendedKey);
+    }
+    Ok(raw)
+}
+
+impl fmt::Display for ExtendedPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", bs58::encode(self.to_bytes()).with_check().into_string())
+    }
+}
+
+impl fmt::Display for ExtendedPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", bs58::encode(self.to_bytes()).with_check().into_string())
+    }
+}
+
+/// Parses `xprv` and `tprv` keys. `tprv` keys are read as testnet.
+impl FromStr for ExtendedPrivateKey {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let raw = deserialize(s)?;
+        let network = match raw.version {
+            XPRV_MAINNET => Network::Mainnet,
+            XPRV_TESTNET => Network::Testnet,
+            _ => return Err(BtcError::InvalidExtendedKey),
+        };
+        if raw.key[0] != 0 {
+            return Err(BtcError::InvalidExtendedKey);
+        }
+        let scalar = parse_tweak(raw.key[1..].try_into().expect("BUG: 32 bytes"))?;
+        Ok(ExtendedPrivateKey {
+            network,
+            depth: raw.depth,
+            parent_fingerprint: raw.parent_fingerprint,
+            child_number: raw.child_number,
+            chain_code: raw.chain_code,
+            private_key: private_key_from_scalar(scalar)?,
+        })
+    }
+}
+
+/// Parses `xpub` and `tpub` keys. `tpub` keys are read as testnet.
+impl FromStr for ExtendedPublicKey {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let raw = deserialize(s)?;
+        let network = match raw.version {
+            XPUB_MAINNET => Network::Mainnet,
+            XPUB_TESTNET => Network::Testnet,
+            _ => return Err(BtcError::InvalidExtendedKey),
+        };
+        let public_key = PublicKey::from_sec1(&raw.key).ok_or(BtcError::InvalidExtendedKey)?;
+        Ok(ExtendedPublicKey {
+            network,
+            depth: raw.depth,
+            parent_fingerprint: raw.parent_fingerprint,
+            child_number: raw.child_number,
+            chain_code: raw.chain_code,
+            public_key,
+        })
+    }
+}
+
+macro_rules! string_serde {
+    ($name:ty) => {
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+// extended keys are saved as their Base58Check text
+macro_rules! text_saveable {
+    ($name:ty) => {
+        impl Saveable for $name {
+            fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+                let mut buf = String::new();
+                reader.read_to_string(&mut buf)?;
+                buf.trim().parse().map_err(|_| {
+                    IoError::new(IoErrorKind::InvalidData, "Failed to parse extended key")
+                })
+            }
+
+            fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+                writeln!(writer, "{self}")
+            }
+        }
+    };
+}
+
+string_serde!(ExtendedPrivateKey);
+string_serde!(ExtendedPublicKey);
+string_serde!(DerivationPath);
+text_saveable!(ExtendedPrivateKey);
+text_saveable!(ExtendedPublicKey);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (path, xpub, xprv) from the BIP32 test vectors
+    type Vector = (&'static str, &'static str, &'static str);
+
+    fn check_vectors(seed: &str, vectors: &[Vector]) {
+        let seed = hex::decode(seed).unwrap();
+        let master = ExtendedPrivateKey::new_master(&seed, Network::Mainnet).unwrap();
+        for (path, xpub, xprv) in vectors {
+            let key = master.derive_path(&path.parse().unwrap()).unwrap();
+            assert_eq!(key.to_string(), *xprv, "{path}");
+            assert_eq!(key.public_key().to_string(), *xpub, "{path}");
+
+            let parsed: ExtendedPrivateKey = xprv.parse().unwrap();
+            assert_eq!(parsed.to_string(), *xprv);
+            let parsed: ExtendedPublicKey = xpub.parse().unwrap();
+            assert_eq!(parsed.to_string(), *xpub);
+        }
+    }
+
+    #[test]
+    fn vector_1() {
+        check_vectors(
+            "000102030405060708090a0b0c0d0e0f",
+            &[
+                (
+                    "m",
+                    "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+                    "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi",
+                ),
+                (
+                    "m/0'",
+                    "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+                    "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7",
+                ),
+                (
+                    "m/0'/1",
+                    "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+                    "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs",
+                ),
+                (
+                    "m/0'/1/2'",
+                    "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+                    "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM",
+                ),
+                (
+                    "m/0'/1/2'/2",
+                    "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+                    "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqsunu5Mm3wDvUAKRHSC34sJ7in334",
+                ),
+                (
+                    "m/0'/1/2'/2/1000000000",
+                    "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy",
+                    "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rFSruoUihUZREPSL39UNdE3BBDu76",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn vector_2() {
+        check_vectors(
+            "fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542",
+            &[
+                (
+                    "m",
+                    "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB",
+                    "xprv9s21ZrQH143K31xYSDQpPDxsXRTUcvj2iNHm5NUtrGiGG5e2DtALGdso3pGz6ssrdK4PFmM8NSpSBHNqPqm55Qn3LqFtT2emdEXVYsCzC2U",
+                ),
+                (
+                    "m/0",
+                    "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH",
+                    "xprv9vHkqa6EV4sPZHYqZznhT2NPtPCjKuDKGY38FBWLvgaDx45zo9WQRUT3dKYnjwih2yJD9mkrocEZXo1ex8G81dwSM1fwqWpWkeS3v86pgKt",
+                ),
+                (
+                    "m/0/2147483647'",
+                    "xpub6ASAVgeehLbnwdqV6UKMHVzgqAG8Gr6riv3Fxxpj8ksbH9ebxaEyBLZ85ySDhKiLDBrQSARLq1uNRts8RuJiHjaDMBU4Zn9h8LZNnBC5y4a",
+                    "xprv9wSp6B7kry3Vj9m1zSnLvN3xH8RdsPP1Mh7fAaR7aRLcQMKTR2vidYEeEg2mUCTAwCd6vnxVrcjfy2kRgVsFawNzmjuHc2YmYRmagcEPdU9",
+                ),
+                (
+                    "m/0/2147483647'/1",
+                    "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon",
+                    "xprv9zFnWC6h2cLgpmSA46vutJzBcfJ8yaJGg8cX1e5StJh45BBciYTRXSd25UEPVuesF9yog62tGAQtHjXajPPdbRCHuWS6T8XA2ECKADdw4Ef",
+                ),
+                (
+                    "m/0/2147483647'/1/2147483646'",
+                    "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL",
+                    "xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc",
+                ),
+                (
+                    "m/0/2147483647'/1/2147483646'/2",
+                    "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt",
+                    "xprvA2nrNbFZABcdryreWet9Ea4LvTJcGsqrMzxHx98MMrotbir7yrKCEXw7nadnHM8Dq38EGfSh6dqA9QWTyefMLEcBYJUuekgW4BYPJcr9E7j",
+                ),
+            ],
+        );
+    }
+
+    // leading zeros of private keys are kept
+    #[test]
+    fn vector_3() {
+        check_vectors(
+            "4b381541583be4423346c643850da4b320e46a87ae3d2a4e6da11eba819cd4acba45d239319ac14f863b8d5ab5a0d0c64d2e8a1e7d1457df2e5a3c51c73235be",
+            &[
+                (
+                    "m",
+                    "xpub661MyMwAqRbcEZVB4dScxMAdx6d4nFc9nvyvH3v4gJL378CSRZiYmhRoP7mBy6gSPSCYk6SzXPTf3ND1cZAceL7SfJ1Z3GC8vBgp2epUt13",
+                    "xprv9s21ZrQH143K25QhxbucbDDuQ4naNntJRi4KUfWT7xo4EKsHt2QJDu7KXp1A3u7Bi1j8ph3EGsZ9Xvz9dGuVrtHHs7pXeTzjuxBrCmmhgC6",
+                ),
+                (
+                    "m/0'",
+                    "xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y",
+                    "xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn public_derivation_matches_private_derivation() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedPrivateKey::new_master(&seed, Network::Mainnet).unwrap();
+        let account = master.derive_path(&"m/0'".parse().unwrap()).unwrap();
+        let path: DerivationPath = "m/1/2".parse().unwrap();
+        assert_eq!(
+            account.derive_path(&path).unwrap().public_key(),
+            account.public_key().derive_path(&path).unwrap(),
+        );
+        // hardened children need the private key
+        assert!(account
+            .public_key()
+            .derive_child(ChildNumber::hardened(0).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn paths() {
+        let path: DerivationPath = "m/84'/0'/0'/0/1".parse().unwrap();
+        assert_eq!(path.to_string(), "m/84'/0'/0'/0/1");
+        assert_eq!(path.as_slice()[0], ChildNumber::hardened(84).unwrap());
+        assert_eq!(path.as_slice()[4], ChildNumber::normal(1).unwrap());
+        assert_eq!("m/0h".parse::<DerivationPath>().unwrap().to_string(), "m/0'");
+        for invalid in ["", "0/1", "m/", "m/x", "m/2147483648", "m//1"] {
+            assert!(invalid.parse::<DerivationPath>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let xprv = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+        // a broken checksum
+        let mut broken = xprv.to_string();
+        broken.replace_range(110.., "j");
+        assert!(broken.parse::<ExtendedPrivateKey>().is_err());
+        // a private key is not a public key and the other way around
+        assert!(xprv.parse::<ExtendedPublicKey>().is_err());
+        let xpub = xprv.parse::<ExtendedPrivateKey>().unwrap().public_key().to_string();
+        assert!(xpub.parse::<ExtendedPrivateKey>().is_err());
+    }
+}
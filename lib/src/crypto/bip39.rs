@@ -0,0 +1,211 @@
+// BIP39 mnemonic phrases, English wordlist only
+use crate::error::{BtcError, Result};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use unicode_normalization::UnicodeNormalization;
+
+const ENGLISH: &str = include_str!("bip39_english.txt");
+const PBKDF2_ROUNDS: u32 = 2048;
+const WORD_BITS: usize = 11;
+
+// the list is sorted, so lookups can binary search it
+fn wordlist() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDS.get_or_init(|| ENGLISH.lines().collect())
+}
+
+/// A mnemonic phrase encoding 128 to 256 bits of entropy,
+/// with a checksum of one bit per 32 bits of entropy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mnemonic {
+    entropy: Vec<u8>,
+}
+
+impl Mnemonic {
+    /// New random mnemonic of 12, 15, 18, 21 or 24 words
+    pub fn generate(word_count: usize) -> Result<Self> {
+        if !(12..=24).contains(&word_count) || !word_count.is_multiple_of(3) {
+            return Err(BtcError::InvalidMnemonic);
+        }
+        let mut entropy = vec![0u8; word_count * 4 / 3];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut entropy);
+        Mnemonic::from_entropy(&entropy)
+    }
+
+    pub fn from_entropy(entropy: &[u8]) -> Result<Self> {
+        if !(16..=32).contains(&entropy.len()) || !entropy.len().is_multiple_of(4) {
+            return Err(BtcError::InvalidMnemonic);
+        }
+        Ok(Mnemonic { entropy: entropy.to_vec() })
+    }
+
+    pub fn entropy(&self) -> &[u8] {
+        &self.entropy
+    }
+
+    pub fn words(&self) -> Vec<&'static str> {
+        let checksum = Sha256::digest(&self.entropy);
+        let mut bits = bits_of(&self.entropy);
+        bits.extend(bits_of(&checksum).into_iter().take(self.entropy.len() / 4));
+        bits.chunks(WORD_BITS)
+            .map(|chunk| wordlist()[chunk.iter().fold(0, |index, bit| index << 1 | *bit as usize)])
+            .collect()
+    }
+
+    /// 64-byte seed for BIP32. The passphrase is optional, and
+    /// every passphrase gives a different, valid seed.
+    pub fn to_seed(&self, passphrase: &str) -> [u8; 64] {
+        let phrase: String = self.to_string().nfkd().collect();
+        let salt: String = format!("mnemonic{passphrase}").nfkd().collect();
+        let mut seed = [0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha512>(phrase.as_bytes(), salt.as_bytes(), PBKDF2_ROUNDS, &mut seed);
+        seed
+    }
+}
+
+fn bits_of(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .collect()
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.words().join(" "))
+    }
+}
+
+/// Parses a phrase, ignoring case and extra whitespace. Fails
+/// on unknown words and on a wrong checksum.
+impl FromStr for Mnemonic {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bits = vec![];
+        let mut word_count: usize = 0;
+        for word in s.split_whitespace() {
+            let index = wordlist()
+                .binary_search(&word.to_lowercase().as_str())
+                .map_err(|_| BtcError::InvalidMnemonic)?;
+            bits.extend((0..WORD_BITS).rev().map(|i| index >> i & 1 == 1));
+            word_count += 1;
+        }
+        if !(12..=24).contains(&word_count) || !word_count.is_multiple_of(3) {
+            return Err(BtcError::InvalidMnemonic);
+        }
+        let entropy_bits = word_count * WORD_BITS * 32 / 33;
+        let entropy: Vec<u8> = bits[..entropy_bits]
+            .chunks(8)
+            .map(|chunk| chunk.iter().fold(0u8, |byte, bit| byte << 1 | *bit as u8))
+            .collect();
+        let mnemonic = Mnemonic::from_entropy(&entropy)?;
+        let checksum = bits_of(&Sha256::digest(&entropy));
+        if bits[entropy_bits..] != checksum[..bits.len() - entropy_bits] {
+            return Err(BtcError::InvalidMnemonic);
+        }
+        Ok(mnemonic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (entropy, phrase, seed) from the reference BIP39 vectors,
+    // all with the passphrase "TREZOR"
+    const VECTORS: [(&str, &str, &str); 8] = [
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "80808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+            "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd3097170af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+            "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad",
+        ),
+        (
+            "9e885d952ad362caeb4efe34a8e91bd2",
+            "ozone drill grab fiber curtain grace pudding thank cruise elder eight picnic",
+            "274ddc525802f7c828d8ef7ddbcdc5304e87ac3535913611fbbfa986d0c9e5476c91689f9c8a54fd55bd38606aa6a8595ad213d4c9c9f9aca3fb217069a41028",
+        ),
+        (
+            "c0ba5a8e914111210f2bd131f3d5e08d",
+            "scheme spot photo card baby mountain device kick cradle pact join borrow",
+            "ea725895aaae8d4c1cf682c1bfd2d358d52ed9f0f0591131b559e2724bb234fca05aa9c02c57407e04ee9dc3b454aa63fbff483a8b11de949624b9f1831a9612",
+        ),
+    ];
+
+    #[test]
+    fn vectors() {
+        for (entropy, phrase, seed) in VECTORS {
+            let mnemonic = Mnemonic::from_entropy(&hex::decode(entropy).unwrap()).unwrap();
+            assert_eq!(mnemonic.to_string(), phrase);
+            assert_eq!(phrase.parse::<Mnemonic>().unwrap(), mnemonic);
+            assert_eq!(hex::encode(mnemonic.to_seed("TREZOR")), seed);
+        }
+    }
+
+    #[test]
+    fn parsing_is_lenient_about_case_and_spacing() {
+        let phrase = "  Legal WINNER thank year wave sausage\tworth useful legal winner thank yellow\n";
+        assert_eq!(phrase.parse::<Mnemonic>().unwrap().to_string(), VECTORS[1].1);
+    }
+
+    #[test]
+    fn invalid_phrases() {
+        for phrase in [
+            // wrong checksum
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon",
+            // not a word of the list
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abou",
+            // too short, and not a multiple of three words
+            "abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "",
+        ] {
+            assert!(phrase.parse::<Mnemonic>().is_err(), "{phrase}");
+        }
+        assert!(Mnemonic::from_entropy(&[0; 15]).is_err());
+        assert!(Mnemonic::from_entropy(&[0; 36]).is_err());
+    }
+
+    #[test]
+    fn generated_phrases_round_trip() {
+        for word_count in [12, 15, 18, 21, 24] {
+            let mnemonic = Mnemonic::generate(word_count).unwrap();
+            assert_eq!(mnemonic.words().len(), word_count);
+            assert_eq!(mnemonic.to_string().parse::<Mnemonic>().unwrap(), mnemonic);
+        }
+        assert!(Mnemonic::generate(13).is_err());
+    }
+
+    #[test]
+    fn passphrases_change_the_seed() {
+        let mnemonic: Mnemonic = VECTORS[0].1.parse().unwrap();
+        assert_ne!(mnemonic.to_seed(""), mnemonic.to_seed("TREZOR"));
+    }
+}
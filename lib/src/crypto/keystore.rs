@@ -0,0 +1,278 @@
+// Password-encrypted private key files
+use crate::error::{BtcError, Result};
+use crate::utils::Saveable;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Version 1: scrypt key derivation, ChaCha20-Poly1305 encryption
+const ENVELOPE_VERSION: u8 = 1;
+
+// 32 MiB of memory per attempt, as recommended for interactive logins
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Files can ask for more work than we would ever write, but not
+// so much that opening one exhausts memory or hangs: at most
+// 1 GiB and a few passes
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 4;
+
+/// How the encryption key is derived from the password
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Kdf {
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+        salt: [u8; 16],
+    },
+}
+
+/// A private key encrypted with a password. The plaintext is
+/// the key's regular file format, so any `Saveable` key fits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedKey {
+    pub version: u8,
+    pub kdf: Kdf,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+impl Kdf {
+    fn new_scrypt() -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Kdf::Scrypt {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt,
+        }
+    }
+
+    fn derive(&self, password: &str) -> Result<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        match self {
+            Kdf::Scrypt { log_n, r, p, salt } => {
+                if *log_n > MAX_SCRYPT_LOG_N || *r > MAX_SCRYPT_R || *p > MAX_SCRYPT_P {
+                    return Err(BtcError::InvalidKeyFile);
+                }
+                let params = scrypt::Params::new(*log_n, *r, *p, key.len())
+                    .map_err(|_| BtcError::InvalidKeyFile)?;
+                scrypt::scrypt(password.as_bytes(), salt, &params, key.as_mut())
+                    .map_err(|_| BtcError::InvalidKeyFile)?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl EncryptedKey {
+    pub fn seal<T: Saveable>(key: &T, password: &str) -> Result<Self> {
+        let mut plaintext = Zeroizing::new(vec![]);
+        key.save(&mut *plaintext).map_err(|_| BtcError::InvalidPrivateKey)?;
+        let kdf = Kdf::new_scrypt();
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cipher = ChaCha20Poly1305::new(kdf.derive(password)?.as_ref().into());
+        let aad = associated_data(ENVELOPE_VERSION, &kdf);
+        let ciphertext = cipher
+            .encrypt(&nonce.into(), Payload { msg: &plaintext, aad: &aad })
+            .map_err(|_| BtcError::InvalidPrivateKey)?;
+        Ok(EncryptedKey {
+            version: ENVELOPE_VERSION,
+            kdf,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt the key. Fails on a wrong password, a tampered
+    /// file, or an envelope version this build does not know.
+    pub fn open<T: Saveable>(&self, password: &str) -> Result<T> {
+        if self.version != ENVELOPE_VERSION {
+            return Err(BtcError::InvalidKeyFile);
+        }
+        let cipher = ChaCha20Poly1305::new(self.kdf.derive(password)?.as_ref().into());
+        let aad = associated_data(self.version, &self.kdf);
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(&self.nonce.into(), Payload { msg: &self.ciphertext, aad: &aad })
+                .map_err(|_| BtcError::InvalidPassword)?,
+        );
+        T::load(plaintext.as_slice()).map_err(|_| BtcError::InvalidKeyFile)
+    }
+}
+
+// the header is authenticated too, so the KDF parameters
+// cannot be swapped out
+fn associated_data(version: u8, kdf: &Kdf) -> Vec<u8> {
+    let mut aad = vec![];
+    ciborium::ser::into_writer(&(version, kdf), &mut aad)
+        .expect("BUG: serializing to memory cannot fail");
+    aad
+}
+
+impl Saveable for EncryptedKey {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize EncryptedKey")
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to serialize EncryptedKey")
+        })
+    }
+}
+
+/// A private key file as found on disk: either encrypted, or
+/// in the plaintext format written before keys were encrypted
+pub enum KeyFile<T> {
+    Plain(T),
+    Encrypted(EncryptedKey),
+}
+
+impl<T: Saveable> KeyFile<T> {
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        let bytes = fs::read(path)?;
+        match EncryptedKey::load(bytes.as_slice()) {
+            Ok(encrypted) => Ok(KeyFile::Encrypted(encrypted)),
+            Err(_) => T::load(bytes.as_slice()).map(KeyFile::Plain),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, KeyFile::Encrypted(_))
+    }
+
+    /// The key, decrypted if needed. Plaintext files ignore the
+    /// password.
+    pub fn unlock(self, password: &str) -> Result<T> {
+        match self {
+            KeyFile::Plain(key) => Ok(key),
+            KeyFile::Encrypted(encrypted) => encrypted.open(password),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    fn sealed() -> (PrivateKey, EncryptedKey) {
+        let key = PrivateKey::new_key();
+        let encrypted = EncryptedKey::seal(&key, "correct horse").unwrap();
+        (key, encrypted)
+    }
+
+    fn opens(encrypted: &EncryptedKey) -> Result<PrivateKey> {
+        encrypted.open("correct horse")
+    }
+
+    #[test]
+    fn seal_then_open() {
+        let (key, encrypted) = sealed();
+        assert_eq!(opens(&encrypted).unwrap().public_key(), key.public_key());
+
+        // through the file format too
+        let mut bytes = vec![];
+        encrypted.save(&mut bytes).unwrap();
+        let loaded = EncryptedKey::load(bytes.as_slice()).unwrap();
+        assert_eq!(opens(&loaded).unwrap().public_key(), key.public_key());
+
+        // the same key and password never give the same file
+        let (_, other) = sealed();
+        assert_ne!(encrypted.nonce, other.nonce);
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let (_, encrypted) = sealed();
+        assert!(matches!(
+            encrypted.open::<PrivateKey>("battery staple"),
+            Err(BtcError::InvalidPassword)
+        ));
+        assert!(encrypted.open::<PrivateKey>("").is_err());
+    }
+
+    #[test]
+    fn tampered_files_fail() {
+        let (_, encrypted) = sealed();
+
+        let last = encrypted.ciphertext.len() - 1;
+        // the start, the middle and the authentication tag
+        for i in [0, last / 2, last] {
+            let mut tampered = encrypted.clone();
+            tampered.ciphertext[i] ^= 1;
+            assert!(opens(&tampered).is_err(), "ciphertext byte {i}");
+        }
+        let mut tampered = encrypted.clone();
+        tampered.ciphertext.pop();
+        assert!(opens(&tampered).is_err());
+
+        let mut tampered = encrypted.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(opens(&tampered).is_err());
+
+        // the header is authenticated as associated data
+        let mut tampered = encrypted.clone();
+        tampered.version += 1;
+        assert!(opens(&tampered).is_err());
+        let Kdf::Scrypt { log_n, r, p, salt } = encrypted.kdf.clone();
+        let kdfs = [
+            Kdf::Scrypt { log_n: log_n - 1, r, p, salt },
+            Kdf::Scrypt { log_n, r: r - 1, p, salt },
+            Kdf::Scrypt { log_n, r, p: p + 1, salt },
+            Kdf::Scrypt { log_n, r, p, salt: [salt[0] ^ 1; 16] },
+        ];
+        for kdf in kdfs {
+            let tampered = EncryptedKey { kdf, ..encrypted.clone() };
+            assert!(opens(&tampered).is_err(), "{:?}", tampered.kdf);
+        }
+    }
+
+    #[test]
+    fn expensive_kdf_params_are_refused() {
+        let (_, encrypted) = sealed();
+        let Kdf::Scrypt { salt, .. } = encrypted.kdf;
+        let kdfs = [
+            Kdf::Scrypt { log_n: 64, r: SCRYPT_R, p: SCRYPT_P, salt },
+            Kdf::Scrypt { log_n: MAX_SCRYPT_LOG_N + 1, r: SCRYPT_R, p: SCRYPT_P, salt },
+            Kdf::Scrypt { log_n: SCRYPT_LOG_N, r: u32::MAX, p: SCRYPT_P, salt },
+            Kdf::Scrypt { log_n: SCRYPT_LOG_N, r: SCRYPT_R, p: u32::MAX, salt },
+        ];
+        for kdf in kdfs {
+            assert!(matches!(kdf.derive("correct horse"), Err(BtcError::InvalidKeyFile)));
+        }
+    }
+
+    #[test]
+    fn key_files_may_be_plain_or_encrypted() {
+        let (key, encrypted) = sealed();
+        let dir = std::env::temp_dir().join(format!("keystore_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let plain_path = dir.join("plain.priv.cbor");
+        key.save_to_file(&plain_path).unwrap();
+        let plain = KeyFile::<PrivateKey>::load_from_file(&plain_path).unwrap();
+        assert!(!plain.is_encrypted());
+        assert_eq!(plain.unlock("ignored").unwrap().public_key(), key.public_key());
+
+        let encrypted_path = dir.join("encrypted.priv.cbor");
+        encrypted.save_to_file(&encrypted_path).unwrap();
+        let file = KeyFile::<PrivateKey>::load_from_file(&encrypted_path).unwrap();
+        assert!(file.is_encrypted());
+        assert_eq!(file.unlock("correct horse").unwrap().public_key(), key.public_key());
+    }
+}
@@ -0,0 +1,252 @@
+// Signing arbitrary text messages, e.g. to prove control of a key
+use crate::address::{Address, PubkeyHash};
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use sha2::Digest;
+use std::fmt;
+use std::str::FromStr;
+
+use super::schnorr::tagged_hash;
+use super::{
+    PrivateKey, PublicKey, SchnorrPublicKey, SchnorrSignature, Signature, VersionedPublicKey,
+    VersionedSignature,
+};
+
+// a different tag than any transaction hash, so a message
+// signature can never be replayed as a transaction signature
+const MESSAGE_TAG: &[u8] = b"BitcoinInRust/message";
+
+const BEGIN_MESSAGE: &str = "-----BEGIN SIGNED MESSAGE-----";
+const BEGIN_SIGNATURE: &str = "-----BEGIN SIGNATURE-----";
+const END_SIGNATURE: &str = "-----END SIGNATURE-----";
+
+/// Line endings are normalized and trailing whitespace dropped,
+/// from every line and from the end, so a message survives
+/// being copied between systems and editors. The armored format
+/// drops it too.
+fn normalize(message: &str) -> String {
+    message
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+/// The hash a message signature commits to
+pub fn message_hash(message: &str) -> Hash {
+    let digest = tagged_hash(MESSAGE_TAG)
+        .chain_update(normalize(message).as_bytes())
+        .finalize();
+    Hash::from_bytes(digest.into())
+}
+
+/// A text message along with a signature over it and the key
+/// that made the signature. Only `sign` and parsing create one,
+/// so the key and the signature always use the same scheme.
+#[derive(Debug, Clone)]
+pub struct SignedMessage {
+    message: String,
+    public_key: VersionedPublicKey,
+    signature: VersionedSignature,
+}
+
+impl SignedMessage {
+    pub fn sign(message: &str, private_key: &PrivateKey, public_key: &VersionedPublicKey) -> Self {
+        let message = normalize(message);
+        let signature = VersionedSignature::sign(&message_hash(&message), private_key, public_key);
+        SignedMessage {
+            message,
+            public_key: public_key.clone(),
+            signature,
+        }
+    }
+
+    /// The message as it was signed, normalized
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn public_key(&self) -> &VersionedPublicKey {
+        &self.public_key
+    }
+
+    pub fn signature(&self) -> &VersionedSignature {
+        &self.signature
+    }
+
+    /// Check the signature against the key it carries. Use
+    /// `is_signed_by` or `is_signed_by_address` to check who
+    /// that key belongs to.
+    pub fn verify(&self) -> bool {
+        self.public_key.verify(&message_hash(&self.message), &self.signature)
+    }
+
+    /// Whether the message was signed with `public_key`, in its
+    /// ECDSA or Schnorr form
+    pub fn is_signed_by(&self, public_key: &PublicKey) -> bool {
+        match &self.public_key {
+            VersionedPublicKey::Ecdsa(key) => key == public_key,
+            VersionedPublicKey::Schnorr(key) => *key == SchnorrPublicKey::from(public_key),
+        }
+    }
+
+    pub fn is_signed_by_address(&self, address: &Address) -> bool {
+        PubkeyHash::of(&self.public_key) == address.hash
+    }
+}
+
+/// Armored text format:
+///
+/// ```text
+/// -----BEGIN SIGNED MESSAGE-----
+/// <message>
+/// -----BEGIN SIGNATURE-----
+/// Scheme: ecdsa
+/// Key: <SEC1 compressed or x-only key, hex>
+/// Signature: <64-byte signature, hex>
+/// -----END SIGNATURE-----
+/// ```
+impl fmt::Display for SignedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (scheme, key, signature) = match (&self.public_key, &self.signature) {
+            (VersionedPublicKey::Ecdsa(key), VersionedSignature::Ecdsa(signature)) => {
+                ("ecdsa", hex::encode(key.to_sec1()), hex::encode(signature.to_bytes()))
+            }
+            (VersionedPublicKey::Schnorr(key), VersionedSignature::Schnorr(signature)) => {
+                ("schnorr", hex::encode(key.to_bytes()), hex::encode(signature.to_bytes()))
+            }
+            _ => unreachable!("BUG: signed messages pair keys and signatures of one scheme"),
+        };
+        writeln!(f, "{BEGIN_MESSAGE}")?;
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "{BEGIN_SIGNATURE}")?;
+        writeln!(f, "Scheme: {scheme}")?;
+        writeln!(f, "Key: {key}")?;
+        writeln!(f, "Signature: {signature}")?;
+        writeln!(f, "{END_SIGNATURE}")
+    }
+}
+
+impl FromStr for SignedMessage {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let lines: Vec<&str> = s.trim().lines().map(str::trim_end).collect();
+        // the message itself may contain anything, so the last
+        // signature header is the one that counts
+        let begin = lines
+            .iter()
+            .rposition(|line| *line == BEGIN_SIGNATURE)
+            .ok_or(BtcError::InvalidSignature)?;
+        let (Some(&BEGIN_MESSAGE), Some(&END_SIGNATURE)) = (lines.first(), lines.last()) else {
+            return Err(BtcError::InvalidSignature);
+        };
+        if begin == 0 {
+            return Err(BtcError::InvalidSignature);
+        }
+        let message = normalize(&lines[1..begin].join("\n"));
+        let field = |name: &str| {
+            lines[begin + 1..lines.len() - 1]
+                .iter()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                .ok_or(BtcError::InvalidSignature)
+        };
+        let key = hex::decode(field("Key")?).map_err(|_| BtcError::InvalidPublicKey)?;
+        let signature = hex::decode(field("Signature")?).map_err(|_| BtcError::InvalidSignature)?;
+        let (public_key, signature) = match field("Scheme")? {
+            "ecdsa" => (
+                VersionedPublicKey::Ecdsa(
+                    PublicKey::from_sec1(&key).ok_or(BtcError::InvalidPublicKey)?,
+                ),
+                VersionedSignature::Ecdsa(
+                    Signature::from_bytes(&signature).ok_or(BtcError::InvalidSignature)?,
+                ),
+            ),
+            "schnorr" => (
+                VersionedPublicKey::Schnorr(
+                    SchnorrPublicKey::from_bytes(&key).ok_or(BtcError::InvalidPublicKey)?,
+                ),
+                VersionedSignature::Schnorr(
+                    SchnorrSignature::from_bytes(&signature).ok_or(BtcError::InvalidSignature)?,
+                ),
+            ),
+            _ => return Err(BtcError::InvalidSignature),
+        };
+        Ok(SignedMessage {
+            message,
+            public_key,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(message: &str, schnorr: bool) -> SignedMessage {
+        let private_key = PrivateKey::new_key();
+        let public_key = if schnorr {
+            VersionedPublicKey::Schnorr(SchnorrPublicKey::from(&private_key.public_key()))
+        } else {
+            VersionedPublicKey::Ecdsa(private_key.public_key())
+        };
+        SignedMessage::sign(message, &private_key, &public_key)
+    }
+
+    #[test]
+    fn armored_round_trip() {
+        for schnorr in [false, true] {
+            let signed = signed("I control this key.\nSecond line", schnorr);
+            assert!(signed.verify());
+            let parsed: SignedMessage = signed.to_string().parse().unwrap();
+            assert!(parsed.verify());
+            assert_eq!(parsed.message(), signed.message());
+            assert!(parsed.is_signed_by_address(&Address::from_public_key(
+                crate::network::Network::Mainnet,
+                signed.public_key()
+            )));
+        }
+    }
+
+    #[test]
+    fn trailing_whitespace_survives_a_round_trip() {
+        let signed = signed("ends in spaces   \r\n\tand a tab\t\n\n", false);
+        assert_eq!(signed.message(), "ends in spaces\n\tand a tab");
+        let parsed: SignedMessage = signed.to_string().parse().unwrap();
+        assert!(parsed.verify());
+
+        // as do editors that strip or add whitespace at line ends
+        let text = signed.to_string().replace("tab\n", "tab  \r\n");
+        assert!(text.parse::<SignedMessage>().unwrap().verify());
+    }
+
+    #[test]
+    fn changed_messages_do_not_verify() {
+        let text = signed("pay 1 coin", true).to_string().replace("pay 1", "pay 9");
+        assert!(!text.parse::<SignedMessage>().unwrap().verify());
+    }
+
+    #[test]
+    fn message_may_contain_the_headers() {
+        let message = format!("{BEGIN_SIGNATURE}\nScheme: ecdsa\n{END_SIGNATURE}");
+        let parsed: SignedMessage = signed(&message, false).to_string().parse().unwrap();
+        assert!(parsed.verify());
+        assert_eq!(parsed.message(), message);
+    }
+
+    #[test]
+    fn invalid_armor() {
+        let text = signed("hello", false).to_string();
+        for broken in [
+            text.replace(BEGIN_MESSAGE, ""),
+            text.replace(END_SIGNATURE, ""),
+            text.replace("Scheme: ecdsa", "Scheme: rsa"),
+            text.replace("Key: ", "Key: zz"),
+        ] {
+            assert!(broken.parse::<SignedMessage>().is_err(), "{broken}");
+        }
+    }
+}
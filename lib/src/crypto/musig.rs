@@ -0,0 +1,797 @@
+// MuSig2 multi-signatures (BIP327), producing BIP340 Schnorr signatures
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::utils::Saveable;
+use k256::elliptic_curve::ops::Reduce;
+use k256::elliptic_curve::point::AffineCoordinates;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::elliptic_curve::PrimeField;
+use k256::{AffinePoint, FieldBytes, ProjectivePoint, Scalar, U256 as FieldU256};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use zeroize::Zeroizing;
+
+use super::schnorr::tagged_hash;
+use super::{PrivateKey, PublicKey, SchnorrPublicKey, SchnorrSignature};
+
+const KEYAGG_LIST_TAG: &[u8] = b"KeyAgg list";
+const KEYAGG_COEFFICIENT_TAG: &[u8] = b"KeyAgg coefficient";
+const AUX_TAG: &[u8] = b"MuSig/aux";
+const NONCE_TAG: &[u8] = b"MuSig/nonce";
+const NONCE_COEFFICIENT_TAG: &[u8] = b"MuSig/noncecoef";
+const CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+
+fn hash_to_scalar(digest: sha2::Sha256) -> Scalar {
+    <Scalar as Reduce<FieldU256>>::reduce_bytes(&digest.finalize())
+}
+
+fn compressed(point: &AffinePoint) -> [u8; 33] {
+    point
+        .to_encoded_point(true)
+        .as_bytes()
+        .try_into()
+        .expect("BUG: compressed point is 33 bytes")
+}
+
+// like `compressed`, but the point at infinity is 33 zero bytes
+fn compressed_ext(point: &ProjectivePoint) -> [u8; 33] {
+    if *point == ProjectivePoint::IDENTITY {
+        [0u8; 33]
+    } else {
+        compressed(&point.to_affine())
+    }
+}
+
+fn parse_point(bytes: &[u8]) -> Option<ProjectivePoint> {
+    k256::PublicKey::from_sec1_bytes(bytes)
+        .ok()
+        .map(|key| key.to_projective())
+}
+
+fn parse_point_ext(bytes: &[u8]) -> Option<ProjectivePoint> {
+    if bytes.iter().all(|byte| *byte == 0) {
+        Some(ProjectivePoint::IDENTITY)
+    } else {
+        parse_point(bytes)
+    }
+}
+
+fn parse_scalar(bytes: &[u8]) -> Option<Scalar> {
+    let bytes: [u8; 32] = bytes.try_into().ok()?;
+    Option::from(Scalar::from_repr(FieldBytes::from(bytes)))
+}
+
+fn has_even_y(point: &ProjectivePoint) -> bool {
+    !bool::from(point.to_affine().y_is_odd())
+}
+
+fn x_bytes(point: &ProjectivePoint) -> [u8; 32] {
+    point.to_affine().x().into()
+}
+
+/// The result of aggregating the public keys of all signers
+#[derive(Debug, Clone)]
+pub struct KeyAggContext {
+    public_keys: Vec<[u8; 33]>,
+    coefficients: Vec<Scalar>,
+    aggregate: ProjectivePoint,
+    // accumulated sign flips and tweaks, so signers can make
+    // their shares match the tweaked key
+    parity_acc: Scalar,
+    tweak_acc: Scalar,
+}
+
+impl KeyAggContext {
+    /// Aggregate public keys. The order matters, so all signers
+    /// have to agree on it, e.g. by calling `sort_keys` first.
+    pub fn new(public_keys: &[PublicKey]) -> Result<Self> {
+        if public_keys.is_empty() {
+            return Err(BtcError::InvalidPublicKey);
+        }
+        let public_keys: Vec<[u8; 33]> = public_keys.iter().map(PublicKey::to_sec1).collect();
+
+        let mut list_hash = tagged_hash(KEYAGG_LIST_TAG);
+        for key in &public_keys {
+            list_hash.update(key);
+        }
+        let list_hash = list_hash.finalize();
+
+        // the first key that differs from the first one gets
+        // coefficient 1, which saves a multiplication
+        let second_key = public_keys
+            .iter()
+            .find(|key| **key != public_keys[0])
+            .copied();
+
+        let mut coefficients = Vec::with_capacity(public_keys.len());
+        let mut aggregate = ProjectivePoint::IDENTITY;
+        for key in &public_keys {
+            let coefficient = if Some(*key) == second_key {
+                Scalar::ONE
+            } else {
+                hash_to_scalar(
+                    tagged_hash(KEYAGG_COEFFICIENT_TAG)
+                        .chain_update(list_hash)
+                        .chain_update(key),
+                )
+            };
+            let point = parse_point(key).ok_or(BtcError::InvalidPublicKey)?;
+            aggregate += point * coefficient;
+            coefficients.push(coefficient);
+        }
+        if aggregate == ProjectivePoint::IDENTITY {
+            return Err(BtcError::InvalidPublicKey);
+        }
+
+        Ok(KeyAggContext {
+            public_keys,
+            coefficients,
+            aggregate,
+            parity_acc: Scalar::ONE,
+            tweak_acc: Scalar::ZERO,
+        })
+    }
+
+    /// Add `tweak` times the generator to the aggregate key
+    /// (ApplyTweak in BIP327). An x-only tweak applies to the
+    /// key with even y, as taproot commitments do, a plain one
+    /// to the key as it is.
+    pub fn tweak(mut self, tweak: &[u8; 32], x_only: bool) -> Result<Self> {
+        let tweak = parse_scalar(tweak).ok_or(BtcError::InvalidTweak)?;
+        let parity = if x_only && !has_even_y(&self.aggregate) {
+            -Scalar::ONE
+        } else {
+            Scalar::ONE
+        };
+        self.aggregate = self.aggregate * parity + ProjectivePoint::GENERATOR * tweak;
+        if self.aggregate == ProjectivePoint::IDENTITY {
+            return Err(BtcError::InvalidTweak);
+        }
+        self.parity_acc *= parity;
+        self.tweak_acc = tweak + parity * self.tweak_acc;
+        Ok(self)
+    }
+
+    /// Sort keys by their compressed encoding (KeySort in BIP327)
+    pub fn sort_keys(public_keys: &mut [PublicKey]) {
+        public_keys.sort_by_key(PublicKey::to_sec1);
+    }
+
+    /// The x-only key outputs can lock to. On-chain it looks
+    /// like any other Schnorr key.
+    pub fn aggregated_key(&self) -> SchnorrPublicKey {
+        SchnorrPublicKey::from_bytes(&x_bytes(&self.aggregate))
+            .expect("BUG: aggregate key is a valid point")
+    }
+
+    fn coefficient(&self, public_key: &[u8; 33]) -> Option<Scalar> {
+        self.public_keys
+            .iter()
+            .position(|key| key == public_key)
+            .map(|i| self.coefficients[i])
+    }
+
+    // negate secret keys if the aggregate key has an odd y
+    fn parity(&self) -> Scalar {
+        if has_even_y(&self.aggregate) {
+            Scalar::ONE
+        } else {
+            -Scalar::ONE
+        }
+    }
+
+    // what every secret key is multiplied with, taking the sign
+    // flips of earlier tweaks into account
+    fn parity_factor(&self) -> Scalar {
+        self.parity() * self.parity_acc
+    }
+}
+
+/// Secret half of a signer's nonce.
+///
+/// It must be used for exactly one signature, which is why
+/// `MuSigSession::sign` consumes it. Reusing it leaks the private key.
+pub struct SecretNonce {
+    k1: Scalar,
+    k2: Scalar,
+    public_key: [u8; 33],
+}
+
+/// Public half of a signer's nonce, sent to the other signers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicNonce(#[serde(with = "nonce_serde")] [u8; 66]);
+
+/// Sum of all signers' public nonces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregateNonce(#[serde(with = "nonce_serde")] [u8; 66]);
+
+/// A signer's share of the final signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialSignature([u8; 32]);
+
+// NonceGen from BIP327 with the randomness passed in. Messages
+// of any length and extra input are allowed here so the test
+// vectors can be checked; `generate` only signs txids.
+fn nonce_gen(
+    mut rand: [u8; 32],
+    secret_key: Option<[u8; 32]>,
+    public_key: [u8; 33],
+    aggregate_key: &[u8],
+    message: Option<&[u8]>,
+    extra_in: &[u8],
+) -> (SecretNonce, PublicNonce) {
+    if let Some(secret_key) = secret_key {
+        let aux = tagged_hash(AUX_TAG).chain_update(rand).finalize();
+        for (byte, (secret, aux)) in rand.iter_mut().zip(secret_key.iter().zip(aux.iter())) {
+            *byte = secret ^ aux;
+        }
+    }
+
+    let message_prefixed: Vec<u8> = match message {
+        None => vec![0],
+        Some(message) => {
+            let mut bytes = vec![1];
+            bytes.extend_from_slice(&(message.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(message);
+            bytes
+        }
+    };
+
+    let nonce = |i: u8| {
+        hash_to_scalar(
+            tagged_hash(NONCE_TAG)
+                .chain_update(rand)
+                .chain_update([public_key.len() as u8])
+                .chain_update(public_key)
+                .chain_update([aggregate_key.len() as u8])
+                .chain_update(aggregate_key)
+                .chain_update(&message_prefixed)
+                .chain_update((extra_in.len() as u32).to_be_bytes())
+                .chain_update(extra_in)
+                .chain_update([i]),
+        )
+    };
+    let (k1, k2) = (nonce(0), nonce(1));
+
+    let mut public_nonce = [0u8; 66];
+    public_nonce[..33].copy_from_slice(&compressed(&(ProjectivePoint::GENERATOR * k1).to_affine()));
+    public_nonce[33..].copy_from_slice(&compressed(&(ProjectivePoint::GENERATOR * k2).to_affine()));
+
+    (
+        SecretNonce { k1, k2, public_key },
+        PublicNonce(public_nonce),
+    )
+}
+
+impl SecretNonce {
+    /// Generate a fresh nonce pair (NonceGen in BIP327). Every
+    /// optional argument that is known in advance makes nonce
+    /// reuse after a broken random number generator less likely.
+    pub fn generate(
+        private_key: Option<&PrivateKey>,
+        public_key: &PublicKey,
+        key_agg: Option<&KeyAggContext>,
+        message: Option<&Hash>,
+    ) -> (SecretNonce, PublicNonce) {
+        let mut rand = [0u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut rand);
+        let aggregate_key = key_agg.map(|ctx| ctx.aggregated_key().to_bytes());
+        let message = message.map(Hash::as_bytes);
+        nonce_gen(
+            rand,
+            private_key.map(|key| key.0.to_bytes().into()),
+            public_key.to_sec1(),
+            aggregate_key.as_ref().map_or(&[], |key| key.as_slice()),
+            message.as_ref().map(|message| message.as_slice()),
+            &[],
+        )
+    }
+
+    /// 97-byte encoding, for signers that have to persist the
+    /// nonce between the two rounds. Handle it like a private key.
+    pub fn to_bytes(&self) -> [u8; 97] {
+        let mut bytes = [0u8; 97];
+        bytes[..32].copy_from_slice(&self.k1.to_bytes());
+        bytes[32..64].copy_from_slice(&self.k2.to_bytes());
+        bytes[64..].copy_from_slice(&self.public_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 97 {
+            return Err(BtcError::InvalidNonce);
+        }
+        let k1 = parse_scalar(&bytes[..32]).ok_or(BtcError::InvalidNonce)?;
+        let k2 = parse_scalar(&bytes[32..64]).ok_or(BtcError::InvalidNonce)?;
+        let public_key = bytes[64..].try_into().expect("BUG: checked length");
+        Ok(SecretNonce { k1, k2, public_key })
+    }
+}
+
+// the 97-byte encoding, so a nonce kept between the rounds
+// can be encrypted like a private key
+impl Saveable for SecretNonce {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let mut bytes = Zeroizing::new(vec![]);
+        reader.read_to_end(&mut bytes)?;
+        SecretNonce::from_bytes(&bytes)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to parse SecretNonce"))
+    }
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        writer.write_all(Zeroizing::new(self.to_bytes()).as_slice())
+    }
+}
+
+impl PublicNonce {
+    pub fn to_bytes(&self) -> [u8; 66] {
+        self.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 66] = bytes.try_into().map_err(|_| BtcError::InvalidNonce)?;
+        parse_point(&bytes[..33]).ok_or(BtcError::InvalidNonce)?;
+        parse_point(&bytes[33..]).ok_or(BtcError::InvalidNonce)?;
+        Ok(PublicNonce(bytes))
+    }
+
+    // None if the nonce was deserialized from invalid bytes
+    fn points(&self) -> Option<(ProjectivePoint, ProjectivePoint)> {
+        Some((parse_point(&self.0[..33])?, parse_point(&self.0[33..])?))
+    }
+}
+
+impl AggregateNonce {
+    /// Sum the public nonces of all signers (NonceAgg in BIP327)
+    pub fn sum(public_nonces: &[PublicNonce]) -> Result<Self> {
+        if public_nonces.is_empty() {
+            return Err(BtcError::InvalidNonce);
+        }
+        let (mut r1, mut r2) = (ProjectivePoint::IDENTITY, ProjectivePoint::IDENTITY);
+        for nonce in public_nonces {
+            let (nonce1, nonce2) = nonce.points().ok_or(BtcError::InvalidNonce)?;
+            r1 += nonce1;
+            r2 += nonce2;
+        }
+        let mut bytes = [0u8; 66];
+        bytes[..33].copy_from_slice(&compressed_ext(&r1));
+        bytes[33..].copy_from_slice(&compressed_ext(&r2));
+        Ok(AggregateNonce(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 66] {
+        self.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 66] = bytes.try_into().map_err(|_| BtcError::InvalidNonce)?;
+        parse_point_ext(&bytes[..33]).ok_or(BtcError::InvalidNonce)?;
+        parse_point_ext(&bytes[33..]).ok_or(BtcError::InvalidNonce)?;
+        Ok(AggregateNonce(bytes))
+    }
+
+    fn points(&self) -> Option<(ProjectivePoint, ProjectivePoint)> {
+        Some((parse_point_ext(&self.0[..33])?, parse_point_ext(&self.0[33..])?))
+    }
+}
+
+impl PartialSignature {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        parse_scalar(bytes).ok_or(BtcError::InvalidPartialSignature)?;
+        Ok(PartialSignature(bytes.try_into().expect("BUG: checked length")))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    // None if the signature was deserialized from an out-of-range value
+    fn scalar(&self) -> Option<Scalar> {
+        parse_scalar(&self.0)
+    }
+}
+
+/// Everything the signers share once the nonces are exchanged
+pub struct MuSigSession<'a> {
+    key_agg: &'a KeyAggContext,
+    nonce_coefficient: Scalar,
+    final_nonce: ProjectivePoint,
+    challenge: Scalar,
+}
+
+impl<'a> MuSigSession<'a> {
+    /// Set up the signing session for a txid
+    pub fn new(
+        key_agg: &'a KeyAggContext,
+        aggregate_nonce: &AggregateNonce,
+        txid: &Hash,
+    ) -> Result<Self> {
+        let aggregate_key = key_agg.aggregated_key().to_bytes();
+        let nonce_coefficient = hash_to_scalar(
+            tagged_hash(NONCE_COEFFICIENT_TAG)
+                .chain_update(aggregate_nonce.0)
+                .chain_update(aggregate_key)
+                .chain_update(txid.as_bytes()),
+        );
+        let (r1, r2) = aggregate_nonce.points().ok_or(BtcError::InvalidNonce)?;
+        let mut final_nonce = r1 + r2 * nonce_coefficient;
+        if final_nonce == ProjectivePoint::IDENTITY {
+            final_nonce = ProjectivePoint::GENERATOR;
+        }
+        let challenge = hash_to_scalar(
+            tagged_hash(CHALLENGE_TAG)
+                .chain_update(x_bytes(&final_nonce))
+                .chain_update(aggregate_key)
+                .chain_update(txid.as_bytes()),
+        );
+        Ok(MuSigSession {
+            key_agg,
+            nonce_coefficient,
+            final_nonce,
+            challenge,
+        })
+    }
+
+    /// Produce this signer's partial signature, consuming the nonce
+    pub fn sign(&self, secret_nonce: SecretNonce, private_key: &PrivateKey) -> Result<PartialSignature> {
+        let public_key = private_key.public_key().to_sec1();
+        if secret_nonce.public_key != public_key {
+            return Err(BtcError::InvalidNonce);
+        }
+        let coefficient = self
+            .key_agg
+            .coefficient(&public_key)
+            .ok_or(BtcError::InvalidPublicKey)?;
+
+        let secret = *private_key.0.as_nonzero_scalar().as_ref() * self.key_agg.parity_factor();
+        let (k1, k2) = if has_even_y(&self.final_nonce) {
+            (secret_nonce.k1, secret_nonce.k2)
+        } else {
+            (-secret_nonce.k1, -secret_nonce.k2)
+        };
+        let s = k1 + self.nonce_coefficient * k2 + self.challenge * coefficient * secret;
+        Ok(PartialSignature(s.to_bytes().into()))
+    }
+
+    /// Check another signer's partial signature against their
+    /// public nonce, so a bad signer can be identified
+    pub fn verify_partial(
+        &self,
+        partial_signature: &PartialSignature,
+        public_nonce: &PublicNonce,
+        public_key: &PublicKey,
+    ) -> bool {
+        let sec1 = public_key.to_sec1();
+        let Some(coefficient) = self.key_agg.coefficient(&sec1) else {
+            return false;
+        };
+        let Some((r1, r2)) = public_nonce.points() else {
+            return false;
+        };
+        let Some(s) = partial_signature.scalar() else {
+            return false;
+        };
+        let mut nonce = r1 + r2 * self.nonce_coefficient;
+        if !has_even_y(&self.final_nonce) {
+            nonce = -nonce;
+        }
+        let point = parse_point(&sec1).expect("BUG: valid public key");
+        let expected = nonce
+            + point * (self.challenge * coefficient * self.key_agg.parity_factor());
+        ProjectivePoint::GENERATOR * s == expected
+    }
+
+    /// Combine all partial signatures into a BIP340 signature
+    /// valid for the aggregated key
+    pub fn aggregate(&self, partial_signatures: &[PartialSignature]) -> Result<SchnorrSignature> {
+        // the tweaks are nobody's share, so they are added here
+        let mut s = self.challenge * self.key_agg.parity() * self.key_agg.tweak_acc;
+        for partial in partial_signatures {
+            s += partial.scalar().ok_or(BtcError::InvalidPartialSignature)?;
+        }
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&x_bytes(&self.final_nonce));
+        bytes[32..].copy_from_slice(&s.to_bytes());
+        SchnorrSignature::from_bytes(&bytes).ok_or(BtcError::InvalidSignature)
+    }
+}
+
+mod nonce_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 66], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 66], D::Error> {
+        let bytes: Vec<u8> = Vec::deserialize(deserializer)?;
+        bytes
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("nonce must be 66 bytes"))
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // from the BIP327 test vectors
+    const SECRET_KEY: &str = "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671";
+    const SECRET_NONCE: &str = "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F703935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9";
+    const MESSAGE: &str = "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF";
+    const SIGN_KEYS: [&str; 3] = [
+        "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+        "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+        "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+    ];
+    const PUBLIC_NONCES: [&str; 4] = [
+        "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+        "0237C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0387BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+    ];
+
+    fn bytes(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    fn public_key(s: &str) -> PublicKey {
+        PublicKey::from_sec1(&bytes(s)).unwrap()
+    }
+
+    fn secret_key() -> PrivateKey {
+        PrivateKey(ecdsa::SigningKey::from_slice(&bytes(SECRET_KEY)).unwrap())
+    }
+
+    fn message() -> Hash {
+        Hash::from_bytes(bytes(MESSAGE).try_into().unwrap())
+    }
+
+    fn select<T: Clone>(items: &[T], indices: &[usize]) -> Vec<T> {
+        indices.iter().map(|i| items[*i].clone()).collect()
+    }
+
+    fn sign_keys() -> Vec<PublicKey> {
+        SIGN_KEYS.iter().map(|key| public_key(key)).collect()
+    }
+
+    fn public_nonces() -> Vec<PublicNonce> {
+        PUBLIC_NONCES
+            .iter()
+            .map(|nonce| PublicNonce::from_bytes(&bytes(nonce)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn key_aggregation_vectors() {
+        let keys: Vec<PublicKey> = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .iter()
+        .map(|key| public_key(key))
+        .collect();
+        let vectors: [(&[usize], &str); 4] = [
+            (&[0, 1, 2], "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C"),
+            (&[2, 1, 0], "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B"),
+            (&[0, 0, 0], "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935"),
+            (&[0, 0, 1, 1], "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E"),
+        ];
+        for (indices, expected) in vectors {
+            let key_agg = KeyAggContext::new(&select(&keys, indices)).unwrap();
+            assert_eq!(hex::encode_upper(key_agg.aggregated_key().to_bytes()), expected);
+        }
+        assert!(KeyAggContext::new(&[]).is_err());
+    }
+
+    #[test]
+    fn nonce_generation_vectors() {
+        let public_key: [u8; 33] = bytes(
+            "024D4B6CD1361032CA9BD2AEB9D900AA4D45D9EAD80AC9423374C451A7254D0766",
+        )
+        .try_into()
+        .unwrap();
+        let long_message = [0x26; 38];
+        let vectors: [(Option<&[u8]>, &str); 3] = [
+            (
+                Some(&[0x01; 32]),
+                "B114E502BEAA4E301DD08A50264172C84E41650E6CB726B410C0694D59EFFB6495B5CAF28D045B973D63E3C99A44B807BDE375FD6CB39E46DC4A511708D0E9D2",
+            ),
+            (
+                Some(&[]),
+                "E862B068500320088138468D47E0E6F147E01B6024244AE45EAC40ACE5929B9F0789E051170B9E705D0B9EB49049A323BBBBB206D8E05C19F46C6228742AA7A9",
+            ),
+            (
+                Some(&long_message),
+                "3221975ACBDEA6820EABF02A02B7F27D3A8EF68EE42787B88CBEFD9AA06AF3632EE85B1A61D8EF31126D4663A00DD96E9D1D4959E72D70FE5EBB6E7696EBA66F",
+            ),
+        ];
+        for (message, expected) in vectors {
+            let (secret_nonce, public_nonce) =
+                nonce_gen([0x0f; 32], Some([0x02; 32]), public_key, &[0x07; 32], message, &[0x08; 32]);
+            assert_eq!(hex::encode_upper(&secret_nonce.to_bytes()[..64]), expected);
+            assert_eq!(&secret_nonce.to_bytes()[64..], &public_key);
+            assert_eq!(
+                &public_nonce.to_bytes()[..33],
+                &compressed(&(ProjectivePoint::GENERATOR * secret_nonce.k1).to_affine())
+            );
+        }
+
+        // no optional inputs at all
+        let public_key: [u8; 33] = bytes(SIGN_KEYS[1]).try_into().unwrap();
+        let (secret_nonce, public_nonce) = nonce_gen([0; 32], None, public_key, &[], None, &[]);
+        assert_eq!(
+            hex::encode_upper(&secret_nonce.to_bytes()[..64]),
+            "890E83616A3BC4640AB9B6374F21C81FF89CDDDBAFAA7475AE2A102A92E3EDB29FD7E874E23342813A60D9646948242646B7951CA046B4B36D7D6078506D3C94",
+        );
+        assert_eq!(
+            hex::encode_upper(public_nonce.to_bytes()),
+            "02237A448A2848DD07B3C01C618EB926DFA2F5C294ADC68CBAADA183F016E1EB0E03CA63E5E8EB6DA599C5605FC9340BE1AFAAAFED278500844132B562DB2B1E1ED3",
+        );
+    }
+
+    #[test]
+    fn nonce_aggregation() {
+        let nonces = public_nonces();
+        assert_eq!(
+            hex::encode_upper(AggregateNonce::sum(&nonces[..3]).unwrap().to_bytes()),
+            "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9",
+        );
+        // a nonce and its negation sum to infinity on both points
+        let infinity = AggregateNonce::sum(&[nonces[0], nonces[3]]).unwrap();
+        assert_eq!(infinity.to_bytes(), [0u8; 66]);
+        assert!(PublicNonce::from_bytes(&[0u8; 66]).is_err());
+    }
+
+    #[test]
+    fn sign_and_verify_vectors() {
+        let keys = sign_keys();
+        let nonces = public_nonces();
+        // the secret key is key 0 and its nonce is nonce 0
+        let vectors: [(&[usize], &[usize], &str); 4] = [
+            (&[0, 1, 2], &[0, 1, 2], "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB"),
+            (&[1, 0, 2], &[1, 0, 2], "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52"),
+            (&[1, 2, 0], &[1, 2, 0], "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900"),
+            (&[0, 1], &[0, 3], "AE386064B26105404798F75DE2EB9AF5EDA5387B064B83D049CB7C5E08879531"),
+        ];
+        for (key_indices, nonce_indices, expected) in vectors {
+            let key_agg = KeyAggContext::new(&select(&keys, key_indices)).unwrap();
+            let aggregate_nonce = AggregateNonce::sum(&select(&nonces, nonce_indices)).unwrap();
+            let session = MuSigSession::new(&key_agg, &aggregate_nonce, &message()).unwrap();
+            let secret_nonce = SecretNonce::from_bytes(&bytes(SECRET_NONCE)).unwrap();
+            let partial = session.sign(secret_nonce, &secret_key()).unwrap();
+            assert_eq!(hex::encode_upper(partial.to_bytes()), expected);
+            assert!(session.verify_partial(&partial, &nonces[0], &keys[0]));
+        }
+    }
+
+    #[test]
+    fn verify_fails_on_wrong_partial_signatures() {
+        let keys = sign_keys();
+        let nonces = public_nonces();
+        let key_agg = KeyAggContext::new(&keys).unwrap();
+        let aggregate_nonce = AggregateNonce::sum(&nonces[..3]).unwrap();
+        let session = MuSigSession::new(&key_agg, &aggregate_nonce, &message()).unwrap();
+        let partial = PartialSignature::from_bytes(&bytes(
+            "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+        ))
+        .unwrap();
+
+        // the negated signature
+        let negated = -partial.scalar().unwrap();
+        let negated = PartialSignature(negated.to_bytes().into());
+        assert!(!session.verify_partial(&negated, &nonces[0], &keys[0]));
+        // the right signature for another signer, or another nonce
+        assert!(!session.verify_partial(&partial, &nonces[0], &keys[1]));
+        assert!(!session.verify_partial(&partial, &nonces[1], &keys[0]));
+        // a signature that is not below the group order
+        assert!(PartialSignature::from_bytes(&bytes(
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn sign_errors() {
+        let keys = sign_keys();
+        let nonces = public_nonces();
+        // the signer's key is not among the aggregated ones
+        let key_agg = KeyAggContext::new(&keys[1..]).unwrap();
+        let aggregate_nonce = AggregateNonce::sum(&nonces[..3]).unwrap();
+        let session = MuSigSession::new(&key_agg, &aggregate_nonce, &message()).unwrap();
+        let secret_nonce = SecretNonce::from_bytes(&bytes(SECRET_NONCE)).unwrap();
+        assert!(session.sign(secret_nonce, &secret_key()).is_err());
+
+        // the nonce belongs to another key
+        let key_agg = KeyAggContext::new(&keys).unwrap();
+        let session = MuSigSession::new(&key_agg, &aggregate_nonce, &message()).unwrap();
+        let mut secret_nonce = bytes(SECRET_NONCE);
+        secret_nonce[64..].copy_from_slice(&bytes(SIGN_KEYS[1]));
+        let secret_nonce = SecretNonce::from_bytes(&secret_nonce).unwrap();
+        assert!(session.sign(secret_nonce, &secret_key()).is_err());
+    }
+
+    // every signer's share adds up to a BIP340 signature for the
+    // (tweaked) aggregate key
+    fn sign_together(tweaks: &[([u8; 32], bool)]) -> (SchnorrSignature, SchnorrPublicKey) {
+        let private_keys: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::new_key()).collect();
+        let mut public_keys: Vec<PublicKey> =
+            private_keys.iter().map(PrivateKey::public_key).collect();
+        KeyAggContext::sort_keys(&mut public_keys);
+        let mut key_agg = KeyAggContext::new(&public_keys).unwrap();
+        for (tweak, x_only) in tweaks {
+            key_agg = key_agg.tweak(tweak, *x_only).unwrap();
+        }
+        let txid = message();
+        let nonces: Vec<(SecretNonce, PublicNonce)> = private_keys
+            .iter()
+            .map(|key| SecretNonce::generate(Some(key), &key.public_key(), Some(&key_agg), Some(&txid)))
+            .collect();
+        let public_nonces: Vec<PublicNonce> = nonces.iter().map(|(_, nonce)| *nonce).collect();
+        let aggregate_nonce = AggregateNonce::sum(&public_nonces).unwrap();
+        let session = MuSigSession::new(&key_agg, &aggregate_nonce, &txid).unwrap();
+        let partials: Vec<PartialSignature> = private_keys
+            .iter()
+            .zip(nonces)
+            .map(|(key, (secret_nonce, public_nonce))| {
+                let partial = session.sign(secret_nonce, key).unwrap();
+                assert!(session.verify_partial(&partial, &public_nonce, &key.public_key()));
+                partial
+            })
+            .collect();
+        (session.aggregate(&partials).unwrap(), key_agg.aggregated_key())
+    }
+
+    #[test]
+    fn signing_round_trip() {
+        let (signature, key) = sign_together(&[]);
+        assert!(signature.verify(&message(), &key));
+    }
+
+    #[test]
+    fn tweaked_signing_round_trip() {
+        let tweaks: Vec<[u8; 32]> = [
+            "E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB",
+            "AE2EA797CC0FE72AC5B97B97F3C6957D7E4199A167A58EB08BCAFFDA70AC0455",
+            "F52ECBC565B3D8BEA2DFD5B75A4F457E54369809322E4120831626F290FA87E0",
+        ]
+        .iter()
+        .map(|tweak| bytes(tweak).try_into().unwrap())
+        .collect();
+        for x_only in [[false, false, false], [true, true, true], [true, false, true], [false, true, false]] {
+            let tweaks: Vec<([u8; 32], bool)> = tweaks.iter().copied().zip(x_only).collect();
+            let (signature, key) = sign_together(&tweaks);
+            assert!(signature.verify(&message(), &key));
+        }
+    }
+
+    #[test]
+    fn tweak_adds_to_the_key() {
+        let keys = sign_keys();
+        let key_agg = KeyAggContext::new(&keys).unwrap();
+        let tweak = [0x01; 32];
+        let scalar = parse_scalar(&tweak).unwrap();
+        let expected = key_agg.aggregate + ProjectivePoint::GENERATOR * scalar;
+        assert_eq!(key_agg.clone().tweak(&tweak, false).unwrap().aggregate, expected);
+
+        // an x-only tweak starts from the key with even y
+        let even = if has_even_y(&key_agg.aggregate) {
+            key_agg.aggregate
+        } else {
+            -key_agg.aggregate
+        };
+        let expected = even + ProjectivePoint::GENERATOR * scalar;
+        assert_eq!(key_agg.clone().tweak(&tweak, true).unwrap().aggregate, expected);
+
+        // tweaks have to be below the group order
+        let order: [u8; 32] = bytes("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141")
+            .try_into()
+            .unwrap();
+        assert!(key_agg.tweak(&order, false).is_err());
+    }
+}
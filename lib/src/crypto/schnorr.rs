@@ -0,0 +1,459 @@
+// BIP340 Schnorr signatures over secp256k1
+use crate::sha256::Hash;
+use k256::elliptic_curve::ops::{LinearCombinationExt, Reduce};
+use k256::elliptic_curve::point::DecompressPoint;
+use k256::elliptic_curve::subtle::Choice;
+use k256::elliptic_curve::{Field, PrimeField};
+use k256::schnorr::{Signature as BIP340Signature, SigningKey, VerifyingKey};
+use k256::{AffinePoint, FieldBytes, ProjectivePoint, Scalar, U256 as FieldU256};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+
+use super::{PrivateKey, PublicKey};
+
+const CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+
+/// BIP340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || data)
+pub(crate) fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher
+}
+
+/// X-only public key used by Schnorr signatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchnorrPublicKey(pub VerifyingKey);
+
+/// 64-byte BIP340 Schnorr signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchnorrSignature(pub BIP340Signature);
+
+impl SchnorrPublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        VerifyingKey::from_bytes(bytes).ok().map(SchnorrPublicKey)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes().into()
+    }
+}
+
+/// The x-only form of an ECDSA public key, the key the same
+/// private key signs for with Schnorr
+impl From<&PublicKey> for SchnorrPublicKey {
+    fn from(public_key: &PublicKey) -> Self {
+        let point = public_key.0.to_encoded_point(true);
+        let x = point.x().expect("BUG: compressed point has an x coordinate");
+        SchnorrPublicKey::from_bytes(x).expect("BUG: x coordinate of a valid point")
+    }
+}
+
+impl PrivateKey {
+    pub fn schnorr_public_key(&self) -> SchnorrPublicKey {
+        SchnorrPublicKey(*self.schnorr_signing_key().verifying_key())
+    }
+
+    fn schnorr_signing_key(&self) -> SigningKey {
+        SigningKey::from(self.0.as_nonzero_scalar().to_owned())
+    }
+}
+
+impl SchnorrSignature {
+    /// Sign a transaction id with auxiliary randomness, as
+    /// recommended by BIP340
+    pub fn sign(txid: &Hash, private_key: &PrivateKey) -> Self {
+        let mut aux_rand = [0u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut aux_rand);
+        let signature = private_key
+            .schnorr_signing_key()
+            .sign_raw(&txid.as_bytes(), &aux_rand)
+            .expect("BUG: signing with a valid key cannot fail");
+        SchnorrSignature(signature)
+    }
+
+    pub fn verify(&self, txid: &Hash, public_key: &SchnorrPublicKey) -> bool {
+        public_key.0.verify_raw(&txid.as_bytes(), &self.0).is_ok()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        BIP340Signature::try_from(bytes).ok().map(SchnorrSignature)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0.to_bytes()
+    }
+
+    /// Verify several signatures at once, as described in BIP340.
+    /// Every signature gets a random weight, so the whole batch
+    /// reduces to one multi-scalar multiplication. Returns true
+    /// only if every signature in the batch is valid.
+    pub fn verify_batch(items: &[(Hash, SchnorrSignature, SchnorrPublicKey)]) -> bool {
+        if items.len() < 2 {
+            return items
+                .iter()
+                .all(|(txid, signature, public_key)| signature.verify(txid, public_key));
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut s_sum = Scalar::ZERO;
+        let mut terms: Vec<(ProjectivePoint, Scalar)> = Vec::with_capacity(items.len() * 2 + 1);
+        for (i, (txid, signature, public_key)) in items.iter().enumerate() {
+            let bytes = signature.to_bytes();
+            let r_bytes: [u8; 32] = bytes[..32].try_into().expect("BUG: 64-byte signature");
+            let s_bytes: [u8; 32] = bytes[32..].try_into().expect("BUG: 64-byte signature");
+
+            // lift r to the point with even y, failing if it is not on the curve
+            let r = FieldBytes::from(r_bytes);
+            let r_point: Option<AffinePoint> =
+                AffinePoint::decompress(&r, Choice::from(0)).into();
+            let Some(r_point) = r_point else {
+                return false;
+            };
+            // s has to be below the group order, not reduced into it
+            let s: Option<Scalar> = Scalar::from_repr(FieldBytes::from(s_bytes)).into();
+            let Some(s) = s else {
+                return false;
+            };
+
+            let e = <Scalar as Reduce<FieldU256>>::reduce_bytes(
+                &tagged_hash(CHALLENGE_TAG)
+                    .chain_update(r_bytes)
+                    .chain_update(public_key.to_bytes())
+                    .chain_update(txid.as_bytes())
+                    .finalize(),
+            );
+
+            // the first weight can be 1 without loss of security
+            let a = if i == 0 { Scalar::ONE } else { Scalar::random(&mut rng) };
+            s_sum += a * s;
+            terms.push((ProjectivePoint::from(r_point), a));
+            terms.push((ProjectivePoint::from(*public_key.0.as_affine()), a * e));
+        }
+        // sum(a_i * R_i) + sum(a_i * e_i * P_i) - sum(a_i * s_i) * G == 0
+        terms.push((ProjectivePoint::GENERATOR, -s_sum));
+        ProjectivePoint::lincomb_ext(terms.as_slice()) == ProjectivePoint::IDENTITY
+    }
+}
+
+impl PartialOrd for SchnorrPublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SchnorrPublicKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.to_bytes().cmp(&other.to_bytes())
+    }
+}
+
+impl Serialize for SchnorrPublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for SchnorrPublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: Vec<u8> = serde_bytes_compat::deserialize(deserializer)?;
+        SchnorrPublicKey::from_bytes(&bytes)
+            .ok_or_else(|| serde::de::Error::custom("invalid Schnorr public key"))
+    }
+}
+
+impl Serialize for SchnorrSignature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for SchnorrSignature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: Vec<u8> = serde_bytes_compat::deserialize(deserializer)?;
+        SchnorrSignature::from_bytes(&bytes)
+            .ok_or_else(|| serde::de::Error::custom("invalid Schnorr signature"))
+    }
+}
+
+// accept both byte strings and sequences of bytes
+mod serde_bytes_compat {
+    use serde::de::{Deserializer, SeqAccess, Visitor};
+    use std::fmt;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "bytes")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = vec![];
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ecdsa::SigningKey as ECDSASigningKey;
+
+    // (secret key, public key, message, signature) from the BIP340 test vectors
+    const SIGN_VECTORS: [(&str, &str, &str, &str); 4] = [
+        (
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA8215\
+             25F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+        ),
+        (
+            "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE3341\
+             8906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+        ),
+        (
+            "C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+            "DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+            "7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+            "5831AAEED7B44BB74E5EAB94BA9D4294C49BCF2A60728D8B4C200F50DD313C1B\
+             AB745879A5AD954A72C45A91C3A51D3C7ADEA98D82F8481E0E1E03674A6F3FB7",
+        ),
+        (
+            "0B432B2677937381AEF05BB02A66ECD012773062CF3FA2549E44F58ED2401710",
+            "25D1DFF95105F5253C4022F628A996AD3A0D95FBF21D468A1B33F8C160D8F517",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+            "7EB0509757E246F19449885651611CB965ECC1A187DD51B64FDA1EDC9637D5EC\
+             97582B9CB13DB3933705B32BA982AF5AF25FD78881EBB32771FC5922EFC66EA3",
+        ),
+    ];
+
+    const KEY: &str = "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659";
+    const MESSAGE: &str = "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89";
+
+    // (public key, message, signature, valid) from the BIP340 test vectors
+    const VERIFY_VECTORS: [(&str, &str, &str, bool); 11] = [
+        (
+            "D69C3509BB99E412E68B0FE8544E72837DFA30746D8BE2AA65975F29D22DC7B9",
+            "4DF3C3F68FCC83B27E9D42C90431A72499F17875C81A599B566C9889B9696703",
+            "00000000000000000000003B78CE563F89A0ED9414F5AA28AD0D96D6795F9C63\
+             76AFB1548AF603B3EB45C9F8207DEE1060CB71C04E80F593060B07D28308D7F4",
+            true,
+        ),
+        // public key not on the curve
+        (
+            "EEFDEA4CDB677750A420FEE807EACF21EB9898AE79B9768766E4FAA04A2D4A34",
+            MESSAGE,
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+             69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+            false,
+        ),
+        // R has an odd y
+        (
+            KEY,
+            MESSAGE,
+            "FFF97BD5755EEEA420453A14355235D382F6472F8568A18B2F057A1460297556\
+             3CC27944640AC607CD107AE10923D9EF7A73C643E166BE5EBEAFA34B1AC553E2",
+            false,
+        ),
+        // negated message
+        (
+            KEY,
+            MESSAGE,
+            "1FA62E331EDBC21C394792D2AB1100A7B432B013DF3F6FF4F99FCB33E0E1515F\
+             28890B3EDB6E7189B630448B515CE4F8622A954CFE545735AAEA5134FCCDB2BD",
+            false,
+        ),
+        // negated s
+        (
+            KEY,
+            MESSAGE,
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+             961764B3AA9B2FFCB6EF947B6887A226E8D7C93E00C5ED0C1834FF0D0C2E6DA6",
+            false,
+        ),
+        // sG - eP is the point at infinity
+        (
+            KEY,
+            MESSAGE,
+            "0000000000000000000000000000000000000000000000000000000000000000\
+             123DDA8328AF9C23A94C1FEECFD123BA4FB73476F0D594DCB65C6425BD186051",
+            false,
+        ),
+        (
+            KEY,
+            MESSAGE,
+            "0000000000000000000000000000000000000000000000000000000000000001\
+             7615FBAF5AE28864013C099742DEADB4DBA87F11AC6754F93780D5A1837CF197",
+            false,
+        ),
+        // r is not the x coordinate of a point on the curve
+        (
+            KEY,
+            MESSAGE,
+            "4A298DACAE57395A15D0795DDBFD1DCB564DA82B0F269BC70A74F8220429BA1D\
+             69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+            false,
+        ),
+        // r equals the field size
+        (
+            KEY,
+            MESSAGE,
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F\
+             69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+            false,
+        ),
+        // s equals the group order
+        (
+            KEY,
+            MESSAGE,
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+             FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141",
+            false,
+        ),
+        // public key exceeds the field size
+        (
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC30",
+            MESSAGE,
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+             69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+            false,
+        ),
+    ];
+
+    fn parse(
+        public_key: &str,
+        message: &str,
+        signature: &str,
+    ) -> Option<(Hash, SchnorrSignature, SchnorrPublicKey)> {
+        let message: [u8; 32] = hex::decode(message).unwrap().try_into().unwrap();
+        Some((
+            Hash::from_bytes(message),
+            SchnorrSignature::from_bytes(&hex::decode(signature).unwrap())?,
+            SchnorrPublicKey::from_bytes(&hex::decode(public_key).unwrap())?,
+        ))
+    }
+
+    fn valid_batch() -> Vec<(Hash, SchnorrSignature, SchnorrPublicKey)> {
+        SIGN_VECTORS
+            .iter()
+            .map(|(_, public_key, message, signature)| parse(public_key, message, signature))
+            .chain(std::iter::once(parse(
+                VERIFY_VECTORS[0].0,
+                VERIFY_VECTORS[0].1,
+                VERIFY_VECTORS[0].2,
+            )))
+            .collect::<Option<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn verifies_bip340_vectors() {
+        for (secret_key, public_key, message, signature) in SIGN_VECTORS {
+            let private_key =
+                PrivateKey(ECDSASigningKey::from_slice(&hex::decode(secret_key).unwrap()).unwrap());
+            assert_eq!(
+                hex::encode_upper(private_key.schnorr_public_key().to_bytes()),
+                public_key
+            );
+            let (txid, signature, public_key) = parse(public_key, message, signature).unwrap();
+            assert!(signature.verify(&txid, &public_key));
+        }
+        for (i, (public_key, message, signature, valid)) in VERIFY_VECTORS.into_iter().enumerate() {
+            let verified = parse(public_key, message, signature)
+                .is_some_and(|(txid, signature, public_key)| signature.verify(&txid, &public_key));
+            assert_eq!(verified, valid, "verify vector {}", i + 4);
+        }
+    }
+
+    #[test]
+    fn signatures_verify_for_their_key_and_message_only() {
+        let private_key = PrivateKey::new_key();
+        let public_key = private_key.schnorr_public_key();
+        let txid = Hash::hash_bytes(b"tx");
+        let signature = SchnorrSignature::sign(&txid, &private_key);
+        assert!(signature.verify(&txid, &public_key));
+        assert!(!signature.verify(&Hash::hash_bytes(b"other tx"), &public_key));
+        assert!(!signature.verify(&txid, &PrivateKey::new_key().schnorr_public_key()));
+        assert_eq!(public_key, SchnorrPublicKey::from(&private_key.public_key()));
+        assert_eq!(SchnorrSignature::from_bytes(&signature.to_bytes()), Some(signature));
+    }
+
+    #[test]
+    fn batch_of_valid_signatures_verifies() {
+        let mut batch = valid_batch();
+        for i in 0..5 {
+            let private_key = PrivateKey::new_key();
+            let txid = Hash::hash_bytes(&[i]);
+            batch.push((
+                txid,
+                SchnorrSignature::sign(&txid, &private_key),
+                private_key.schnorr_public_key(),
+            ));
+        }
+        assert!(SchnorrSignature::verify_batch(&batch));
+        assert!(SchnorrSignature::verify_batch(&batch[..1]));
+    }
+
+    #[test]
+    fn batch_with_one_bad_signature_fails() {
+        let batch = valid_batch();
+        // every invalid vector that parses, at every position
+        let invalid: Vec<_> = VERIFY_VECTORS
+            .iter()
+            .filter(|(.., valid)| !valid)
+            .filter_map(|(public_key, message, signature, _)| parse(public_key, message, signature))
+            .collect();
+        assert!(!invalid.is_empty());
+        for bad in invalid {
+            for position in 0..=batch.len() {
+                let mut with_bad = batch.clone();
+                with_bad.insert(position, bad);
+                assert!(!SchnorrSignature::verify_batch(&with_bad));
+            }
+            assert!(!SchnorrSignature::verify_batch(&[bad]));
+        }
+
+        // a valid signature checked against the wrong message
+        let mut swapped = batch.clone();
+        swapped[1].0 = swapped[2].0;
+        assert!(!SchnorrSignature::verify_batch(&swapped));
+    }
+
+    #[test]
+    fn signatures_with_s_at_or_above_the_order_are_rejected() {
+        let (_, _, signature, _) = VERIFY_VECTORS[9];
+        assert!(SchnorrSignature::from_bytes(&hex::decode(signature).unwrap()).is_none());
+
+        // so no such signature can get into a batch, where s is summed mod n
+        let (_, public_key, message, signature) = SIGN_VECTORS[0];
+        let mut bytes = hex::decode(signature).unwrap();
+        bytes[32..].fill(0xff);
+        assert!(SchnorrSignature::from_bytes(&bytes).is_none());
+        assert!(parse(public_key, message, &hex::encode(&bytes)).is_none());
+    }
+
+    #[test]
+    fn empty_batch_verifies() {
+        assert!(SchnorrSignature::verify_batch(&[]));
+    }
+}
@@ -8,6 +8,10 @@ pub enum BtcError {
     InvalidBlock,
     #[error("Invalid block header")]
     InvalidBlockHeader,
+    #[error("Block does not extend the chain")]
+    OrphanBlock,
+    #[error("Transaction spends unknown outputs")]
+    MissingInputs,
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
     #[error("Invalid transaction output")]
@@ -22,6 +26,24 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid extended key")]
+    InvalidExtendedKey,
+    #[error("Invalid derivation path")]
+    InvalidDerivationPath,
+    #[error("Invalid mnemonic")]
+    InvalidMnemonic,
+    #[error("Wrong password or corrupted key file")]
+    InvalidPassword,
+    #[error("Invalid key file")]
+    InvalidKeyFile,
+    #[error("Invalid address")]
+    InvalidAddress,
+    #[error("Invalid nonce")]
+    InvalidNonce,
+    #[error("Invalid partial signature")]
+    InvalidPartialSignature,
+    #[error("Invalid tweak")]
+    InvalidTweak,
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
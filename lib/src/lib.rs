@@ -1,3 +1,4 @@
+pub mod address;
 pub mod crypto;
 pub mod sha256;
 pub mod types;
@@ -5,12 +6,18 @@ pub mod utils;
 pub mod error;
 pub mod network;
 
-use serde::{Deserialize, Serialize};
-use uint::construct_uint;
+pub use uint256::U256;
 
-construct_uint! {
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+// the code generated by construct_uint! trips newer clippy lints
+#[allow(clippy::manual_div_ceil)]
+mod uint256 {
+    use serde::{Deserialize, Serialize};
+    use uint::construct_uint;
+
+    construct_uint! {
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
 }
 
 // initial reward in bitcoin - multiply by 10^8 to get satoshis
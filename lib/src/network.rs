@@ -1,17 +1,115 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use std::fmt;
 use std::io::{Error as IoError, Read, Write};
+use std::str::FromStr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
+use crate::crypto::VersionedPublicKey;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, HeaderChain, OutPoint, Transaction, TransactionOutput};
+use crate::utils::MerkleProof;
+
+mod client;
+mod frame;
+mod handshake;
+mod secure;
+
+pub use client::{Client, ClientError, REQUEST_TIMEOUT};
+pub use frame::{FrameError, FrameHeader, FRAME_HEADER_SIZE, MAX_PAYLOAD_SIZE};
+pub use handshake::{
+    connect_handshake_async, handshake_async, Features, HandshakeError, Services, Version,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use secure::{
+    is_secure_handshake, secure_accept, secure_connect, SecureError, SecureStream, Security,
+    Transport,
+};
+
+/// Most headers a node sends in one `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+/// Most addresses in one `Addr` message
+pub const MAX_ADDR: usize = 1000;
+/// Request id of messages that neither ask nor answer one
+pub const NO_REQUEST: u32 = 0;
+
+/// The chain a node, wallet or address belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network: {}", s)),
+        }
+    }
+}
+
+impl Network {
+    /// Magic bytes that tell the networks apart on the wire
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    /// Hash of `Block::genesis` for the network. Chains that do
+    /// not start with it belong to another network, or are forged.
+    pub fn genesis_hash(&self) -> Hash {
+        let hash = match self {
+            Network::Mainnet => "00004416a20aee9bf60a873884a0bdf9979b00f9ffe21395ec8c49553081d772",
+            Network::Testnet => "00001661d577405d581c52864cad09d178f17cf106cc57941eb09a06856a32b4",
+            Network::Regtest => "0000d590893e9a3a3993ca80409369089c54ff8b13f32757286756ecc0f67eff",
+        };
+        hash.parse().expect("BUG: genesis hashes are valid hex")
+    }
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
+    /// The first message on a connection, from both sides
+    Version(Version),
+    /// Acknowledges the other side's Version, after which
+    /// other messages may follow
+    Verack,
+
     /// Fetch all UTXOs belonging to a public key
-    FetchUTXOs(PublicKey),
-    /// UTXOs belonging to a public key
-    UTXOs(Vec<(TransactionOutput, bool)>),
+    FetchUTXOs(VersionedPublicKey),
+    /// UTXOs belonging to a public key, each with the outpoint
+    /// that identifies it and whether the mempool already
+    /// spends it
+    UTXOs(Vec<(OutPoint, TransactionOutput, bool)>),
+    /// Fetch the UTXOs belonging to a public key along with
+    /// proofs that the transactions creating them are in the
+    /// chain, for light clients
+    FetchUTXOProofs(VersionedPublicKey),
+    /// This is the response to FetchUTXOProofs
+    UTXOProofs(Vec<UTXOProof>),
     /// Send a transaction to the network
     SubmitTransaction(Transaction),
     /// Broadcast a new transaction to other nodes
@@ -20,7 +118,7 @@ pub enum Message {
     /// Ask the node to prepare the optimal block template
     /// with the coinbase transaction paying the specified
     /// public key
-    FetchTemplate(PublicKey),
+    FetchTemplate(VersionedPublicKey),
     /// The template
     Template(Block),
     /// Ask the node to validate a block template.
@@ -33,25 +131,170 @@ pub enum Message {
     /// Submit a mined block to a node
     SubmitTemplate(Block),
 
-    /// Ask a node to report all the other nodes it knows
-    /// about
-    DiscoverNodes,
-    /// This is the response to DiscoverNodes
-    NodeList(Vec<String>),
-    /// Ask a node what s the highest block it knows about
-    /// in comparison to the local blockchain
-    AskDifference(u32),
-    /// This is the response to AskDifference
-    Difference(i32),
+    /// Ask a node for addresses of other nodes it knows about
+    GetAddr,
+    /// Up to MAX_ADDR node addresses. This is the response to
+    /// GetAddr, and is also sent unasked to pass on new ones.
+    Addr(Vec<PeerAddress>),
     /// Ask a node to send a block with the specified height
     FetchBlock(usize),
+    /// Ask a node for up to MAX_HEADERS block headers
+    /// following the first block of the locator it has in its
+    /// chain, or from genesis if it has none of them. The
+    /// headers end early at `stop_hash`; the zero hash means
+    /// as many as fit.
+    GetHeaders { locator: Vec<Hash>, stop_hash: Hash },
+    /// This is the response to GetHeaders
+    Headers(Vec<BlockHeader>),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
+
+    /// Tell a peer about blocks and transactions we have
+    Inv(Vec<InvItem>),
+    /// Ask a peer for announced items it has. They come back
+    /// as NewBlock and NewTransaction messages.
+    GetData(Vec<InvItem>),
+    /// The items of a GetData the peer does not have
+    NotFound(Vec<InvItem>),
+
+    /// Check that a peer is still there. It answers with a Pong
+    /// carrying the same nonce.
+    Ping(u64),
+    Pong(u64),
+}
+
+/// Where a node can be reached, as passed around between nodes
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerAddress {
+    /// host:port the node listens on
+    pub address: String,
+    pub services: Services,
+    /// When the node was last known to be up
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A block or a transaction, by hash, as announced between nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum InvItem {
+    Transaction(Hash),
+    Block(Hash),
+}
+
+/// An unspent output together with the transaction that
+/// created it and a merkle proof placing that transaction in
+/// the block at `height`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UTXOProof {
+    pub transaction: Transaction,
+    pub vout: u32,
+    pub height: u64,
+    pub proof: MerkleProof,
+    /// Whether the output is used in the mempool. This part is
+    /// not proven and only taken as a hint.
+    pub marked: bool,
+}
+
+impl UTXOProof {
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint::new(self.transaction.txid(), self.vout)
+    }
+
+    pub fn output(&self) -> Option<&TransactionOutput> {
+        self.transaction.outputs.get(self.vout as usize)
+    }
+
+    /// Check the proof against a verified header chain. Only
+    /// inclusion is proven: whether the output is still unspent
+    /// is up to the node.
+    pub fn verify(&self, headers: &HeaderChain) -> bool {
+        let Some(header) = headers.header(self.height) else {
+            return false;
+        };
+        self.output().is_some() && self.proof.verify(&self.transaction.txid(), header)
+    }
+}
+
+/// A message with the request it belongs to. A client gives
+/// each request an id of its own, and the answer carries the
+/// same id back, so several requests can be in flight on one
+/// connection.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub id: u32,
+    pub message: Message,
 }
 
-// We are going to use length-prefixed encoding for message
-// And we are going to use ciborium (CBOR) for serialization
+impl Envelope {
+    pub fn new(id: u32, message: Message) -> Self {
+        Envelope { id, message }
+    }
+
+    pub async fn send_async(
+        &self,
+        network: Network,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<(), FrameError> {
+        stream
+            .write_all(&self.message.to_frame(network, self.id)?)
+            .await?;
+        // transports that buffer hold on to it otherwise
+        stream.flush().await?;
+
+        Ok(())
+    }
+
+    pub async fn receive_async(
+        network: Network,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<Self, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header).await?;
+        let header = FrameHeader::from_bytes(&header, network)?;
+
+        // the length was checked against MAX_PAYLOAD_SIZE
+        let mut payload = vec![0u8; header.length as usize];
+        stream.read_exact(&mut payload).await?;
+
+        let message = Message::from_frame(&header, &payload)?;
+        Ok(Envelope::new(header.id, message))
+    }
+}
+
+// Every message goes out in a frame: a FrameHeader with the
+// network magic, command, request id, payload length and
+// checksum, then the message itself in CBOR
 impl Message {
+    /// Identifies the kind of message in the frame header
+    pub fn command(&self) -> u8 {
+        use Message::*;
+        match self {
+            Version(_) => 0,
+            Verack => 1,
+            FetchUTXOs(_) => 2,
+            UTXOs(_) => 3,
+            FetchUTXOProofs(_) => 4,
+            UTXOProofs(_) => 5,
+            SubmitTransaction(_) => 6,
+            NewTransaction(_) => 7,
+            FetchTemplate(_) => 8,
+            Template(_) => 9,
+            ValidateTemplate(_) => 10,
+            TemplateValidity(_) => 11,
+            SubmitTemplate(_) => 12,
+            GetAddr => 13,
+            Addr(_) => 14,
+            FetchBlock(_) => 15,
+            GetHeaders { .. } => 16,
+            Headers(_) => 17,
+            NewBlock(_) => 18,
+            Inv(_) => 19,
+            GetData(_) => 20,
+            NotFound(_) => 21,
+            Ping(_) => 22,
+            Pong(_) => 23,
+        }
+    }
+
     pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
         let mut bytes = Vec::new();
         ciborium::into_writer(self, &mut bytes)?;
@@ -63,48 +306,66 @@ impl Message {
         ciborium::from_reader(data)
     }
 
-    pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes())?;
-        stream.write_all(&bytes)?;
+    /// The message with its frame header in front
+    pub fn to_frame(&self, network: Network, id: u32) -> Result<Vec<u8>, FrameError> {
+        let payload = self.encode().map_err(FrameError::Encode)?;
+        let header = FrameHeader::new(network, self.command(), id, &payload)?;
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+
+        Ok(frame)
+    }
+
+    fn from_frame(header: &FrameHeader, payload: &[u8]) -> Result<Self, FrameError> {
+        header.check_payload(payload)?;
+        let message = Self::decode(payload).map_err(FrameError::Decode)?;
+        if message.command() != header.command {
+            return Err(FrameError::CommandMismatch {
+                header: header.command,
+                payload: message.command(),
+            });
+        }
+
+        Ok(message)
+    }
+
+    pub fn send(&self, network: Network, stream: &mut impl Write) -> Result<(), FrameError> {
+        stream.write_all(&self.to_frame(network, NO_REQUEST)?)?;
 
         Ok(())
     }
 
-    pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
-        stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+    pub fn receive(network: Network, stream: &mut impl Read) -> Result<Self, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header)?;
+        let header = FrameHeader::from_bytes(&header, network)?;
 
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data)?;
+        // the length was checked against MAX_PAYLOAD_SIZE
+        let mut payload = vec![0u8; header.length as usize];
+        stream.read_exact(&mut payload)?;
 
-        Self::decode(&data)
+        Self::from_frame(&header, &payload)
     }
 
+    /// Send a message that is not part of a request
     pub async fn send_async(
         &self,
+        network: Network,
         stream: &mut (impl AsyncWrite + Unpin),
-    ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes()).await?;
-        stream.write_all(&bytes).await?;
+    ) -> Result<(), FrameError> {
+        stream
+            .write_all(&self.to_frame(network, NO_REQUEST)?)
+            .await?;
+        stream.flush().await?;
 
         Ok(())
     }
 
+    /// Receive a message, whichever request it belongs to
     pub async fn receive_async(
+        network: Network,
         stream: &mut (impl AsyncRead + Unpin),
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
-        stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data).await?;
-
-        Self::decode(&data)
+    ) -> Result<Self, FrameError> {
+        Ok(Envelope::receive_async(network, stream).await?.message)
     }
 }
@@ -0,0 +1,220 @@
+// Client side of a connection to a node, with several requests
+// in flight at once
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+use super::{Envelope, FrameError, Message, Network, NO_REQUEST};
+
+/// Messages the node sent unasked that wait to be picked up.
+/// Past this many, new ones are dropped.
+const PUSH_QUEUE_SIZE: usize = 100;
+
+/// How long a request waits on its answer by default
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests waiting on an answer, by id. `None` once the
+/// connection is closed.
+type Pending = Arc<SyncMutex<Option<HashMap<u32, oneshot::Sender<Message>>>>>;
+
+/// A connection to a node that can be shared between tasks.
+///
+/// Each request gets an id, and a task of its own reads from the
+/// node and hands every answer to the request with the same id,
+/// so answers never end up with the wrong caller. Messages the
+/// node sends unasked go to a separate channel.
+pub struct Client<S> {
+    network: Network,
+    writer: Mutex<WriteHalf<S>>,
+    pending: Pending,
+    next_id: AtomicU32,
+    timeout: Duration,
+    reader: JoinHandle<()>,
+}
+
+impl<S> Client<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Take over a connection after the handshake. Returns the
+    /// client along with the channel that messages the node
+    /// sends unasked come in on.
+    pub fn new(stream: S, network: Network) -> (Self, mpsc::Receiver<Message>) {
+        let (reader, writer) = tokio::io::split(stream);
+        let pending: Pending = Arc::new(SyncMutex::new(Some(HashMap::new())));
+        let (pushes, pushed) = mpsc::channel(PUSH_QUEUE_SIZE);
+        let reader = tokio::spawn(route(reader, network, pending.clone(), pushes));
+        let client = Client {
+            network,
+            writer: Mutex::new(writer),
+            pending,
+            next_id: AtomicU32::new(1),
+            timeout: REQUEST_TIMEOUT,
+            reader,
+        };
+        (client, pushed)
+    }
+
+    /// Give up on requests the node takes longer than `timeout`
+    /// to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send a request and wait for the answer to it. An answer
+    /// that comes after the timeout is dropped.
+    pub async fn request(&self, message: Message) -> Result<Message, ClientError> {
+        let id = self.next_id();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("BUG: poisoned lock")
+            .as_mut()
+            .ok_or(ClientError::Closed)?
+            .insert(id, sender);
+
+        if let Err(e) = self.write(id, message).await {
+            self.forget(id);
+            return Err(e);
+        }
+        // only the wait is timed, as a frame cut off halfway
+        // would break the connection for every other request
+        match timeout(self.timeout, receiver).await {
+            Ok(answer) => answer.map_err(|_| ClientError::Closed),
+            Err(_) => {
+                self.forget(id);
+                Err(ClientError::TimedOut)
+            }
+        }
+    }
+
+    // stop waiting on the answer to request `id`
+    fn forget(&self, id: u32) {
+        if let Some(pending) = self.pending.lock().expect("BUG: poisoned lock").as_mut() {
+            pending.remove(&id);
+        }
+    }
+
+    /// Send a message that gets no answer
+    pub async fn send(&self, message: Message) -> Result<(), ClientError> {
+        self.write(NO_REQUEST, message).await
+    }
+
+    async fn write(&self, id: u32, message: Message) -> Result<(), ClientError> {
+        // one frame at a time, so frames of several requests do
+        // not get mixed up
+        let mut writer = self.writer.lock().await;
+        Envelope::new(id, message)
+            .send_async(self.network, &mut *writer)
+            .await?;
+        Ok(())
+    }
+
+    fn next_id(&self) -> u32 {
+        loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            // skip the id of messages outside of requests when
+            // the counter wraps around
+            if id != NO_REQUEST {
+                return id;
+            }
+        }
+    }
+}
+
+impl<S> Drop for Client<S> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// Hand what the node sends to whoever waits for it, until the
+// connection fails
+async fn route<S: AsyncRead>(
+    mut reader: ReadHalf<S>,
+    network: Network,
+    pending: Pending,
+    pushes: mpsc::Sender<Message>,
+) {
+    while let Ok(envelope) = Envelope::receive_async(network, &mut reader).await {
+        if envelope.id == NO_REQUEST {
+            // nobody may be listening, and a full queue is not
+            // worth blocking answers over
+            let _ = pushes.try_send(envelope.message);
+            continue;
+        }
+        let waiting = pending
+            .lock()
+            .expect("BUG: poisoned lock")
+            .as_mut()
+            .and_then(|pending| pending.remove(&envelope.id));
+        // answers to requests given up on are dropped
+        if let Some(waiting) = waiting {
+            let _ = waiting.send(envelope.message);
+        }
+    }
+    // fails every request still waiting, and every one after
+    pending.lock().expect("BUG: poisoned lock").take();
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Connection to the node is closed")]
+    Closed,
+    #[error("Node did not answer in time")]
+    TimedOut,
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_requests<S>(client: &Client<S>) -> usize {
+        client.pending.lock().unwrap().as_ref().map_or(0, HashMap::len)
+    }
+
+    #[test]
+    fn requests_time_out_and_are_forgotten() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (ours, mut node) = tokio::io::duplex(4096);
+            let (client, _) = Client::new(ours, Network::Regtest);
+            let client = client.with_timeout(Duration::from_millis(50));
+
+            let result = client.request(Message::GetAddr).await;
+            assert!(matches!(result, Err(ClientError::TimedOut)));
+            assert_eq!(pending_requests(&client), 0);
+
+            // the late answer is dropped, and the next request
+            // still gets its own
+            let late = Envelope::receive_async(Network::Regtest, &mut node).await.unwrap();
+            Envelope::new(late.id, Message::Addr(vec![]))
+                .send_async(Network::Regtest, &mut node)
+                .await
+                .unwrap();
+            let answer = tokio::spawn(async move {
+                let request = Envelope::receive_async(Network::Regtest, &mut node).await.unwrap();
+                Envelope::new(request.id, Message::Verack)
+                    .send_async(Network::Regtest, &mut node)
+                    .await
+                    .unwrap();
+                node
+            });
+            let result = client.request(Message::GetAddr).await;
+            assert!(matches!(result, Ok(Message::Verack)));
+            assert_eq!(pending_requests(&client), 0);
+            answer.await.unwrap();
+        });
+    }
+}
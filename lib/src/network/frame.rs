@@ -0,0 +1,210 @@
+// Framing of messages on the wire
+use std::io::Error as IoError;
+use thiserror::Error;
+
+use super::Network;
+use crate::sha256::Hash;
+
+/// Largest payload a frame may carry. Longer frames are refused
+/// before any memory is set aside for them.
+pub const MAX_PAYLOAD_SIZE: u32 = 8 * 1024 * 1024;
+
+/// magic (4) | command (1) | request id (4, big-endian) |
+/// payload length (4, big-endian) | checksum (4)
+pub const FRAME_HEADER_SIZE: usize = 17;
+
+/// Header in front of every message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub magic: [u8; 4],
+    /// Which kind of message the payload is
+    pub command: u8,
+    /// Request the message asks or answers, or `NO_REQUEST`
+    pub id: u32,
+    pub length: u32,
+    /// First four bytes of the payload's double-SHA256
+    pub checksum: [u8; 4],
+}
+
+impl FrameHeader {
+    pub fn new(network: Network, command: u8, id: u32, payload: &[u8]) -> Result<Self, FrameError> {
+        let length = u32::try_from(payload.len())
+            .ok()
+            .filter(|length| *length <= MAX_PAYLOAD_SIZE)
+            .ok_or(FrameError::TooLarge(payload.len() as u64))?;
+        Ok(FrameHeader {
+            magic: network.magic(),
+            command,
+            id,
+            length,
+            checksum: checksum(payload),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut bytes = [0u8; FRAME_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&self.magic);
+        bytes[4] = self.command;
+        bytes[5..9].copy_from_slice(&self.id.to_be_bytes());
+        bytes[9..13].copy_from_slice(&self.length.to_be_bytes());
+        bytes[13..].copy_from_slice(&self.checksum);
+        bytes
+    }
+
+    /// Parse a header, refusing frames for another network and
+    /// payloads over the size limit
+    pub fn from_bytes(bytes: &[u8; FRAME_HEADER_SIZE], network: Network) -> Result<Self, FrameError> {
+        let header = FrameHeader {
+            magic: bytes[..4].try_into().expect("BUG: 4 bytes"),
+            command: bytes[4],
+            id: u32::from_be_bytes(bytes[5..9].try_into().expect("BUG: 4 bytes")),
+            length: u32::from_be_bytes(bytes[9..13].try_into().expect("BUG: 4 bytes")),
+            checksum: bytes[13..].try_into().expect("BUG: 4 bytes"),
+        };
+        if header.magic != network.magic() {
+            return Err(FrameError::WrongMagic(header.magic));
+        }
+        if header.length > MAX_PAYLOAD_SIZE {
+            return Err(FrameError::TooLarge(header.length as u64));
+        }
+        Ok(header)
+    }
+
+    pub fn check_payload(&self, payload: &[u8]) -> Result<(), FrameError> {
+        if checksum(payload) != self.checksum {
+            return Err(FrameError::BadChecksum);
+        }
+        Ok(())
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = Hash::hash_bytes(payload).as_bytes();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Connection error: {0}")]
+    Io(#[from] IoError),
+    #[error("Frame is for another network (magic {0:02x?})")]
+    WrongMagic([u8; 4]),
+    #[error("Frame payload of {0} bytes is over the limit")]
+    TooLarge(u64),
+    #[error("Frame checksum does not match its payload")]
+    BadChecksum,
+    #[error("Frame says command {header} but carries command {payload}")]
+    CommandMismatch { header: u8, payload: u8 },
+    #[error("Failed to encode message: {0}")]
+    Encode(ciborium::ser::Error<IoError>),
+    #[error("Failed to decode message: {0}")]
+    Decode(ciborium::de::Error<IoError>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Envelope, Message};
+
+    fn frame(message: &Message, id: u32) -> Vec<u8> {
+        message.to_frame(Network::Mainnet, id).unwrap()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = FrameHeader::new(Network::Testnet, 17, 0xdead_beef, b"payload").unwrap();
+        let bytes = header.to_bytes();
+        assert_eq!(&bytes[..4], &Network::Testnet.magic());
+        assert_eq!(bytes[4], 17);
+        assert_eq!(&bytes[5..9], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&bytes[9..13], &[0, 0, 0, 7]);
+        assert_eq!(FrameHeader::from_bytes(&bytes, Network::Testnet).unwrap(), header);
+        assert!(header.check_payload(b"payload").is_ok());
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let bytes = frame(&Message::Ping(42), 7);
+        assert_eq!(bytes.len(), FRAME_HEADER_SIZE + Message::Ping(42).encode().unwrap().len());
+        let Message::Ping(nonce) = Message::receive(Network::Mainnet, &mut bytes.as_slice()).unwrap()
+        else {
+            panic!("not a ping");
+        };
+        assert_eq!(nonce, 42);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let envelope = runtime
+            .block_on(Envelope::receive_async(Network::Mainnet, &mut bytes.as_slice()))
+            .unwrap();
+        assert_eq!(envelope.id, 7);
+        assert!(matches!(envelope.message, Message::Ping(42)));
+    }
+
+    #[test]
+    fn other_networks_are_refused() {
+        let bytes = frame(&Message::Verack, 0);
+        let result = Message::receive(Network::Regtest, &mut bytes.as_slice());
+        assert!(matches!(result, Err(FrameError::WrongMagic(magic)) if magic == Network::Mainnet.magic()));
+    }
+
+    #[test]
+    fn oversized_payloads_are_refused_from_the_header() {
+        let mut bytes = frame(&Message::Verack, 0);
+        bytes[9..13].copy_from_slice(&(MAX_PAYLOAD_SIZE + 1).to_be_bytes());
+        // no payload follows, so only the header can be refused
+        let result = Message::receive(Network::Mainnet, &mut bytes.as_slice());
+        assert!(matches!(result, Err(FrameError::TooLarge(length)) if length == MAX_PAYLOAD_SIZE as u64 + 1));
+
+        let payload = vec![0u8; MAX_PAYLOAD_SIZE as usize + 1];
+        assert!(matches!(
+            FrameHeader::new(Network::Mainnet, 0, 0, &payload),
+            Err(FrameError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn corrupted_payloads_are_refused() {
+        let mut bytes = frame(&Message::Ping(42), 0);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let result = Message::receive(Network::Mainnet, &mut bytes.as_slice());
+        assert!(matches!(result, Err(FrameError::BadChecksum)));
+    }
+
+    #[test]
+    fn command_has_to_match_the_payload() {
+        let payload = Message::Ping(42).encode().unwrap();
+        let pong = Message::Pong(42).command();
+        let mut bytes = FrameHeader::new(Network::Mainnet, pong, 0, &payload)
+            .unwrap()
+            .to_bytes()
+            .to_vec();
+        bytes.extend_from_slice(&payload);
+        let result = Message::receive(Network::Mainnet, &mut bytes.as_slice());
+        assert!(matches!(
+            result,
+            Err(FrameError::CommandMismatch { header, payload }) if header == pong && payload == Message::Ping(0).command()
+        ));
+    }
+
+    #[test]
+    fn truncated_frames_are_refused() {
+        let bytes = frame(&Message::Ping(42), 0);
+        for length in [0, FRAME_HEADER_SIZE - 1, bytes.len() - 1] {
+            let result = Message::receive(Network::Mainnet, &mut &bytes[..length]);
+            assert!(matches!(result, Err(FrameError::Io(_))), "{length} bytes");
+        }
+    }
+
+    #[test]
+    fn garbage_payloads_are_refused() {
+        let payload = [0xffu8; 8];
+        let mut bytes = FrameHeader::new(Network::Mainnet, 0, 0, &payload)
+            .unwrap()
+            .to_bytes()
+            .to_vec();
+        bytes.extend_from_slice(&payload);
+        let result = Message::receive(Network::Mainnet, &mut bytes.as_slice());
+        assert!(matches!(result, Err(FrameError::Decode(_))));
+    }
+}
@@ -0,0 +1,282 @@
+// Version handshake every connection starts with
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{BitAnd, BitOr};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{FrameError, Message, Network};
+
+/// Version of the protocol spoken by this build. Version 2
+/// added request ids to the frame header.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version we still talk to. Every version we
+/// accept speaks the same messages, so nothing depends on the
+/// version of a peer once it is past the handshake.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// What a peer offers to the other side of the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Services(u64);
+
+impl Services {
+    pub const NONE: Services = Services(0);
+    /// Keeps the full chain and serves blocks and headers
+    pub const BLOCKS: Services = Services(1);
+    /// Answers UTXO queries, with merkle proofs for light clients
+    pub const UTXOS: Services = Services(1 << 1);
+    /// Hands out block templates to miners
+    pub const TEMPLATES: Services = Services(1 << 2);
+
+    pub fn contains(self, other: Services) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl BitOr for Services {
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+        Services(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Services {
+    type Output = Services;
+
+    fn bitand(self, other: Services) -> Services {
+        Services(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Services {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            (Services::BLOCKS, "blocks"),
+            (Services::UTXOS, "utxos"),
+            (Services::TEMPLATES, "templates"),
+        ];
+        let names: Vec<_> = names
+            .iter()
+            .filter(|(service, _)| self.contains(*service))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// The first message on every connection, introducing the
+/// sender
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Version {
+    pub version: u32,
+    pub magic: [u8; 4],
+    pub services: Services,
+    /// Number of blocks the sender has
+    pub best_height: u64,
+    pub user_agent: String,
+    /// Port the sender accepts connections on, or 0 if it does
+    /// not. Together with the address the connection comes from,
+    /// this is where other nodes can reach it.
+    pub listen_port: u16,
+    /// Random number that tells a connection to ourselves apart,
+    /// as we get our own nonce back
+    pub nonce: u64,
+    /// Whether the sender wants new blocks, transactions and
+    /// addresses relayed to it. Off for connections that only
+    /// ask questions, like the ones a sync opens.
+    pub relay: bool,
+}
+
+impl Version {
+    pub fn new(
+        network: Network,
+        services: Services,
+        best_height: u64,
+        user_agent: impl Into<String>,
+    ) -> Self {
+        Version {
+            version: PROTOCOL_VERSION,
+            magic: network.magic(),
+            services,
+            best_height,
+            user_agent: user_agent.into(),
+            listen_port: 0,
+            nonce: rand::random(),
+            relay: true,
+        }
+    }
+
+    pub fn with_listen_port(mut self, listen_port: u16) -> Self {
+        self.listen_port = listen_port;
+        self
+    }
+
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Use the same nonce on every connection, so one we make to
+    /// ourselves can be noticed from either end
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// What both sides of a connection take part in, with
+    /// `self` being our side and `theirs` the peer's
+    pub fn negotiate(&self, theirs: &Version) -> Features {
+        let services = self.services & theirs.services;
+        Features {
+            services,
+            // only nodes that keep blocks relay them, and only
+            // when both ends want it
+            relay: services.contains(Services::BLOCKS)
+                && self.relay
+                && theirs.relay,
+        }
+    }
+
+    fn check(&self, ours: &Version) -> Result<(), HandshakeError> {
+        if self.magic != ours.magic {
+            return Err(HandshakeError::WrongNetwork(self.magic));
+        }
+        if self.nonce == ours.nonce {
+            return Err(HandshakeError::ConnectedToSelf);
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(self.version));
+        }
+        Ok(())
+    }
+}
+
+/// What two sides agreed on in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// Services both sides offer
+    pub services: Services,
+    /// Whether new blocks, transactions and addresses are
+    /// passed on over the connection
+    pub relay: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("Peer is on another network (magic {0:02x?})")]
+    WrongNetwork([u8; 4]),
+    #[error("Peer speaks protocol version {0}, which is too old")]
+    UnsupportedVersion(u32),
+    #[error("Peer does not offer {0}")]
+    MissingServices(Services),
+    #[error("Connected to ourselves")]
+    ConnectedToSelf,
+    #[error("Peer did not follow the handshake")]
+    UnexpectedMessage,
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+}
+
+/// Introduce ourselves and learn who the peer is. Both sides
+/// run the same steps: send our `Version`, check theirs, then
+/// acknowledge it with `Verack` and wait for theirs.
+pub async fn handshake_async(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    network: Network,
+    ours: &Version,
+) -> Result<Version, HandshakeError> {
+    Message::Version(ours.clone()).send_async(network, stream).await?;
+    let Message::Version(theirs) = Message::receive_async(network, stream).await? else {
+        return Err(HandshakeError::UnexpectedMessage);
+    };
+    theirs.check(ours)?;
+    Message::Verack.send_async(network, stream).await?;
+    let Message::Verack = Message::receive_async(network, stream).await? else {
+        return Err(HandshakeError::UnexpectedMessage);
+    };
+    Ok(theirs)
+}
+
+/// Like `handshake_async`, for clients that need the peer to
+/// offer certain services
+pub async fn connect_handshake_async(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    network: Network,
+    ours: &Version,
+    required: Services,
+) -> Result<Version, HandshakeError> {
+    let theirs = handshake_async(stream, network, ours).await?;
+    if !theirs.services.contains(required) {
+        return Err(HandshakeError::MissingServices(required));
+    }
+    Ok(theirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(services: Services, relay: bool) -> Version {
+        Version::new(Network::Regtest, services, 0, "/test/").with_relay(relay)
+    }
+
+    #[test]
+    fn negotiation_keeps_what_both_sides_offer() {
+        let node = version(Services::BLOCKS | Services::UTXOS | Services::TEMPLATES, true);
+        let wallet = version(Services::NONE, true);
+        let light = version(Services::BLOCKS | Services::UTXOS, true);
+
+        let features = node.negotiate(&wallet);
+        assert_eq!(features.services, Services::NONE);
+        assert!(!features.relay);
+        assert_eq!(features, wallet.negotiate(&node));
+
+        let features = node.negotiate(&light);
+        assert_eq!(features.services, Services::BLOCKS | Services::UTXOS);
+        assert!(!features.services.contains(Services::TEMPLATES));
+        assert!(features.relay);
+        assert_eq!(features, light.negotiate(&node));
+    }
+
+    #[test]
+    fn relay_needs_both_sides() {
+        let relaying = version(Services::BLOCKS, true);
+        let syncing = version(Services::BLOCKS, false);
+        assert!(relaying.negotiate(&relaying).relay);
+        assert!(!relaying.negotiate(&syncing).relay);
+        assert!(!syncing.negotiate(&relaying).relay);
+    }
+
+    #[test]
+    fn both_ends_agree_after_a_handshake() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            let ours = version(Services::BLOCKS | Services::UTXOS, true);
+            let other = version(Services::BLOCKS | Services::TEMPLATES, false);
+            let other_side = other.clone();
+            let peer = tokio::spawn(async move {
+                handshake_async(&mut b, Network::Regtest, &other_side).await
+            });
+            let theirs = handshake_async(&mut a, Network::Regtest, &ours)
+                .await
+                .unwrap();
+            let seen = peer.await.unwrap().unwrap();
+            let features = ours.negotiate(&theirs);
+            assert_eq!(features, other.negotiate(&seen));
+            assert_eq!(features.services, Services::BLOCKS);
+            assert!(!features.relay);
+        });
+    }
+}
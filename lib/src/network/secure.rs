@@ -0,0 +1,715 @@
+// Encrypted and authenticated transport, following the Noise_XX
+// handshake pattern over secp256k1
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::point::AffineCoordinates;
+use k256::ProjectivePoint;
+use sha2::{Digest, Sha256};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use zeroize::Zeroizing;
+
+use super::Network;
+use crate::crypto::{PrivateKey, PublicKey};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Mixed into the handshake first, so both sides have to agree
+/// on every choice it names
+const PROTOCOL_NAME: &[u8] = b"Noise_XX_secp256k1_ChaChaPoly_SHA256";
+/// Public keys go over the wire in SEC1 compressed form
+const KEY_SIZE: usize = 33;
+/// Poly1305 tag at the end of every ciphertext
+const TAG_SIZE: usize = 16;
+/// Records carry their length in two bytes
+const MAX_RECORD_SIZE: usize = u16::MAX as usize;
+/// Most plaintext that fits in one record
+const MAX_RECORD_PLAINTEXT: usize = MAX_RECORD_SIZE - TAG_SIZE;
+/// How much is read from the connection at a time
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Whether a connection that starts with this byte opens with
+/// the encrypted handshake. Plaintext connections start with
+/// the network magic, and encrypted ones with a compressed
+/// public key, so a node can take both on one port.
+pub fn is_secure_handshake(first_byte: u8) -> bool {
+    first_byte == 0x02 || first_byte == 0x03
+}
+
+/// How a client protects its connection to a node
+#[derive(Debug, Clone, Default)]
+pub enum Security {
+    /// Plaintext, which every node speaks
+    #[default]
+    Plain,
+    /// Encrypted, with whichever node answers
+    Encrypted,
+    /// Encrypted, and only with the node holding this key
+    Trusted(PublicKey),
+}
+
+impl Security {
+    /// Knowing the node's key implies encrypting
+    pub fn new(encrypt: bool, node_key: Option<PublicKey>) -> Self {
+        match node_key {
+            Some(key) => Security::Trusted(key),
+            None if encrypt => Security::Encrypted,
+            None => Security::Plain,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SecureError {
+    #[error("Connection error: {0}")]
+    Io(#[from] IoError),
+    #[error("Peer sent an invalid public key")]
+    InvalidKey,
+    #[error("Failed to decrypt the handshake, the peer is not who it claims to be")]
+    Decrypt,
+    #[error("Node key {0} is not the one we trust")]
+    UntrustedKey(String),
+}
+
+// ChaCha20-Poly1305 with a counter for the nonce
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; 32]) -> Self {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            nonce: 0,
+        }
+    }
+
+    // 32 zero bits, then the counter in little-endian
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce = self
+            .nonce
+            .checked_add(1)
+            .expect("BUG: 2^64 messages on one connection");
+        nonce
+    }
+
+    fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+            .expect("BUG: plaintext fits in a record")
+    }
+
+    fn decrypt(&mut self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad })
+            .ok()
+    }
+}
+
+// What both sides of the handshake keep track of: a chaining key
+// that every key exchange is mixed into, and a hash of the whole
+// conversation that each encryption is bound to
+struct SymmetricState {
+    chaining_key: Zeroizing<[u8; 32]>,
+    hash: [u8; 32],
+    cipher: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new(prologue: &[u8]) -> Self {
+        let hash: [u8; 32] = Sha256::digest(PROTOCOL_NAME).into();
+        let mut state = SymmetricState {
+            chaining_key: Zeroizing::new(hash),
+            hash,
+            cipher: None,
+        };
+        state.mix_hash(prologue);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = Sha256::new()
+            .chain_update(self.hash)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let (chaining_key, key) = hkdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.cipher = Some(CipherState::new(&key));
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match &mut self.cipher {
+            Some(cipher) => cipher.encrypt(&self.hash, plaintext),
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, SecureError> {
+        let plaintext = match &mut self.cipher {
+            Some(cipher) => cipher
+                .decrypt(&self.hash, ciphertext)
+                .ok_or(SecureError::Decrypt)?,
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    // keys for the initiator's messages, then the responder's
+    fn split(self) -> (CipherState, CipherState) {
+        let (first, second) = hkdf(&self.chaining_key, &[]);
+        (CipherState::new(&first), CipherState::new(&second))
+    }
+}
+
+// HKDF with HMAC-SHA256, two outputs
+fn hkdf(
+    chaining_key: &[u8; 32],
+    input: &[u8],
+) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let mac = |key: &[u8], parts: &[&[u8]]| -> Zeroizing<[u8; 32]> {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(key).expect("BUG: HMAC takes keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+        Zeroizing::new(mac.finalize().into_bytes().into())
+    };
+    let temp = mac(chaining_key, &[input]);
+    let first = mac(&*temp, &[&[1]]);
+    let second = mac(&*temp, &[&*first, &[2]]);
+    (first, second)
+}
+
+// Elliptic curve Diffie-Hellman: the x coordinate of the shared
+// point
+fn dh(private: &PrivateKey, public: &PublicKey) -> Zeroizing<[u8; 32]> {
+    let point = ProjectivePoint::from(*public.0.as_affine())
+        * *private.0.as_nonzero_scalar().as_ref();
+    Zeroizing::new(point.to_affine().x().into())
+}
+
+fn read_key(bytes: &[u8]) -> Result<PublicKey, SecureError> {
+    PublicKey::from_sec1(bytes).ok_or(SecureError::InvalidKey)
+}
+
+/// Open the encrypted transport as the side that connected.
+/// The handshake goes
+///
+/// ```text
+/// -> e
+/// <- e, ee, s, es
+/// -> s, se
+/// ```
+///
+/// so the other side proves it holds its static key before we
+/// reveal ours. With `trusted` set, we hang up unless the other
+/// side's key is that one.
+pub async fn secure_connect<S>(
+    mut stream: S,
+    network: Network,
+    key: &PrivateKey,
+    trusted: Option<&PublicKey>,
+) -> Result<SecureStream<S>, SecureError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new(&network.magic());
+    let ephemeral = PrivateKey::new_key();
+
+    // -> e
+    let ephemeral_public = ephemeral.public_key().to_sec1();
+    state.mix_hash(&ephemeral_public);
+    let mut message = ephemeral_public.to_vec();
+    message.extend(state.encrypt_and_hash(&[]));
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    // <- e, ee, s, es
+    let mut message = [0u8; KEY_SIZE + KEY_SIZE + TAG_SIZE + TAG_SIZE];
+    stream.read_exact(&mut message).await?;
+    let (remote_ephemeral, rest) = message.split_at(KEY_SIZE);
+    state.mix_hash(remote_ephemeral);
+    let remote_ephemeral = read_key(remote_ephemeral)?;
+    state.mix_key(&*dh(&ephemeral, &remote_ephemeral));
+    let (remote_static, payload) = rest.split_at(KEY_SIZE + TAG_SIZE);
+    let remote_key = read_key(&state.decrypt_and_hash(remote_static)?)?;
+    state.mix_key(&*dh(&ephemeral, &remote_key));
+    state.decrypt_and_hash(payload)?;
+    if trusted.is_some_and(|trusted| *trusted != remote_key) {
+        return Err(SecureError::UntrustedKey(remote_key.to_sec1_hex()));
+    }
+
+    // -> s, se
+    let mut message = state.encrypt_and_hash(&key.public_key().to_sec1());
+    state.mix_key(&*dh(key, &remote_ephemeral));
+    message.extend(state.encrypt_and_hash(&[]));
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let (send, receive) = state.split();
+    Ok(SecureStream::new(stream, send, receive, remote_key))
+}
+
+/// Open the encrypted transport as the side that was connected
+/// to, proving we hold `key`
+pub async fn secure_accept<S>(
+    mut stream: S,
+    network: Network,
+    key: &PrivateKey,
+) -> Result<SecureStream<S>, SecureError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new(&network.magic());
+    let ephemeral = PrivateKey::new_key();
+
+    // -> e
+    let mut remote_ephemeral = [0u8; KEY_SIZE];
+    stream.read_exact(&mut remote_ephemeral).await?;
+    state.mix_hash(&remote_ephemeral);
+    state.decrypt_and_hash(&[])?;
+    let remote_ephemeral = read_key(&remote_ephemeral)?;
+
+    // <- e, ee, s, es
+    let ephemeral_public = ephemeral.public_key().to_sec1();
+    state.mix_hash(&ephemeral_public);
+    state.mix_key(&*dh(&ephemeral, &remote_ephemeral));
+    let mut message = ephemeral_public.to_vec();
+    message.extend(state.encrypt_and_hash(&key.public_key().to_sec1()));
+    state.mix_key(&*dh(key, &remote_ephemeral));
+    message.extend(state.encrypt_and_hash(&[]));
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    // -> s, se
+    let mut message = [0u8; KEY_SIZE + TAG_SIZE + TAG_SIZE];
+    stream.read_exact(&mut message).await?;
+    let (remote_static, payload) = message.split_at(KEY_SIZE + TAG_SIZE);
+    let remote_key = read_key(&state.decrypt_and_hash(remote_static)?)?;
+    state.mix_key(&*dh(&ephemeral, &remote_key));
+    state.decrypt_and_hash(payload)?;
+
+    let (receive, send) = state.split();
+    Ok(SecureStream::new(stream, send, receive, remote_key))
+}
+
+/// A connection after the encrypted handshake. Everything
+/// written to it goes out in records of a two-byte length and
+/// a ChaCha20-Poly1305 ciphertext, and reading from it fails
+/// on any record that was tampered with.
+pub struct SecureStream<S> {
+    inner: S,
+    send: CipherState,
+    receive: CipherState,
+    remote_key: PublicKey,
+    /// Bytes read from `inner` that do not make a whole record
+    /// yet
+    incoming: Vec<u8>,
+    /// The last record read, and how much of it was handed out
+    plaintext: Vec<u8>,
+    read: usize,
+    /// The last record written, and how much of it is out
+    outgoing: Vec<u8>,
+    written: usize,
+}
+
+impl<S> SecureStream<S> {
+    fn new(inner: S, send: CipherState, receive: CipherState, remote_key: PublicKey) -> Self {
+        SecureStream {
+            inner,
+            send,
+            receive,
+            remote_key,
+            incoming: vec![],
+            plaintext: vec![],
+            read: 0,
+            outgoing: vec![],
+            written: 0,
+        }
+    }
+
+    /// The static key the other side proved to hold
+    pub fn remote_key(&self) -> &PublicKey {
+        &self.remote_key
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    // take the next whole record off the incoming bytes
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>, IoError> {
+        let Some(length) = self.incoming.get(..2) else {
+            return Ok(None);
+        };
+        let end = 2 + u16::from_be_bytes([length[0], length[1]]) as usize;
+        if self.incoming.len() < end {
+            return Ok(None);
+        }
+        let plaintext = self
+            .receive
+            .decrypt(&[], &self.incoming[2..end])
+            .ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "Record failed to decrypt"))?;
+        self.incoming.drain(..end);
+        Ok(Some(plaintext))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SecureStream<S> {
+    fn poll_write_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        while self.written < self.outgoing.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(IoErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.outgoing.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SecureStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        loop {
+            if this.read < this.plaintext.len() {
+                let count = buf.remaining().min(this.plaintext.len() - this.read);
+                buf.put_slice(&this.plaintext[this.read..this.read + count]);
+                this.read += count;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(plaintext) = this.next_record()? {
+                this.plaintext = plaintext;
+                this.read = 0;
+                continue;
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                if this.incoming.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(IoError::new(
+                    IoErrorKind::UnexpectedEof,
+                    "Connection closed in the middle of a record",
+                )));
+            }
+            this.incoming.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let this = self.get_mut();
+        // one record waits at most, so writers feel backpressure
+        ready!(this.poll_write_outgoing(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let count = buf.len().min(MAX_RECORD_PLAINTEXT);
+        let ciphertext = this.send.encrypt(&[], &buf[..count]);
+        this.outgoing
+            .extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+        this.outgoing.extend_from_slice(&ciphertext);
+        // the bytes are taken either way, what does not go out
+        // now does on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_write_outgoing(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// A connection that may or may not be encrypted, so the code
+/// using it does not have to care
+pub enum Transport<S> {
+    Plain(S),
+    Secure(Box<SecureStream<S>>),
+}
+
+impl<S> Transport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Set up our side of a connection to a node as `security`
+    /// asks. Clients have no identity to prove, so they use a
+    /// new key every time.
+    pub async fn connect(
+        stream: S,
+        network: Network,
+        security: &Security,
+    ) -> Result<Self, SecureError> {
+        let trusted = match security {
+            Security::Plain => return Ok(Transport::Plain(stream)),
+            Security::Encrypted => None,
+            Security::Trusted(key) => Some(key),
+        };
+        let key = PrivateKey::new_key();
+        let stream = secure_connect(stream, network, &key, trusted).await?;
+        Ok(Transport::Secure(Box::new(stream)))
+    }
+}
+
+impl<S> Transport<S> {
+    pub fn get_ref(&self) -> &S {
+        match self {
+            Transport::Plain(stream) => stream,
+            Transport::Secure(stream) => stream.get_ref(),
+        }
+    }
+
+    /// The static key of the other side, if encrypted
+    pub fn remote_key(&self) -> Option<&PublicKey> {
+        match self {
+            Transport::Plain(_) => None,
+            Transport::Secure(stream) => Some(stream.remote_key()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Transport<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Secure(stream) => Pin::new(&mut **stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Transport<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Secure(stream) => Pin::new(&mut **stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Secure(stream) => Pin::new(&mut **stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Secure(stream) => Pin::new(&mut **stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Message;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::runtime::Runtime;
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread().build().unwrap()
+    }
+
+    type Handshaken = Result<SecureStream<DuplexStream>, SecureError>;
+
+    // run both sides of a handshake against each other
+    fn handshake(
+        client_network: Network,
+        server_network: Network,
+        trusted: Option<PublicKey>,
+    ) -> (PrivateKey, PrivateKey, Handshaken, Handshaken) {
+        let client_key = PrivateKey::new_key();
+        let server_key = PrivateKey::new_key();
+        let (client_side, server_side) = duplex(64 * 1024);
+        let accepting = server_key.clone();
+        let connecting = client_key.clone();
+        let (client, server) = runtime().block_on(async move {
+            let server = tokio::spawn(async move {
+                secure_accept(server_side, server_network, &accepting).await
+            });
+            // a client that gives up drops its end, so the
+            // server does not wait forever
+            let client =
+                secure_connect(client_side, client_network, &connecting, trusted.as_ref()).await;
+            (client, server.await.unwrap())
+        });
+        (client_key, server_key, client, server)
+    }
+
+    #[test]
+    fn handshake_proves_both_keys() {
+        let (client_key, server_key, client, server) =
+            handshake(Network::Mainnet, Network::Mainnet, None);
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(*client.remote_key(), server_key.public_key());
+        assert_eq!(*server.remote_key(), client_key.public_key());
+    }
+
+    #[test]
+    fn messages_go_both_ways() {
+        let (_, _, client, server) = handshake(Network::Mainnet, Network::Mainnet, None);
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        runtime().block_on(async {
+            Message::Ping(1).send_async(Network::Mainnet, &mut client).await.unwrap();
+            let Message::Ping(1) = Message::receive_async(Network::Mainnet, &mut server).await.unwrap()
+            else {
+                panic!("not our ping");
+            };
+            Message::Pong(1).send_async(Network::Mainnet, &mut server).await.unwrap();
+            let Message::Pong(1) = Message::receive_async(Network::Mainnet, &mut client).await.unwrap()
+            else {
+                panic!("not our pong");
+            };
+        });
+    }
+
+    #[test]
+    fn trusted_key_has_to_match() {
+        let stranger = PrivateKey::new_key().public_key();
+        let (_, _, client, _) = handshake(Network::Mainnet, Network::Mainnet, Some(stranger));
+        assert!(matches!(client, Err(SecureError::UntrustedKey(_))));
+    }
+
+    #[test]
+    fn networks_have_to_match() {
+        let (_, _, client, server) = handshake(Network::Testnet, Network::Mainnet, None);
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn handshakes_start_unlike_frames() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            assert!(!is_secure_handshake(network.magic()[0]));
+        }
+        let key = PrivateKey::new_key().public_key().to_sec1();
+        assert!(is_secure_handshake(key[0]));
+    }
+
+    // a stream that writes records into a buffer, and one that
+    // reads them back with the same key
+    fn writer() -> SecureStream<Vec<u8>> {
+        let key = PrivateKey::new_key().public_key();
+        SecureStream::new(vec![], CipherState::new(&[7; 32]), CipherState::new(&[8; 32]), key)
+    }
+
+    fn reader(records: &[u8]) -> SecureStream<&[u8]> {
+        let key = PrivateKey::new_key().public_key();
+        SecureStream::new(records, CipherState::new(&[8; 32]), CipherState::new(&[7; 32]), key)
+    }
+
+    fn seal(writes: &[&[u8]]) -> Vec<u8> {
+        let mut stream = writer();
+        runtime().block_on(async {
+            for data in writes {
+                stream.write_all(data).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+        });
+        stream.inner
+    }
+
+    fn open(records: &[u8]) -> Result<Vec<u8>, IoError> {
+        let mut stream = reader(records);
+        let mut plaintext = vec![];
+        runtime().block_on(stream.read_to_end(&mut plaintext))?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = seal(&[b"hello", b"world"]);
+        assert_eq!(records.len(), 2 * (2 + 5 + TAG_SIZE));
+        assert!(!records.windows(5).any(|window| window == b"hello"));
+        assert_eq!(open(&records).unwrap(), b"helloworld");
+    }
+
+    #[test]
+    fn long_writes_are_split_into_records() {
+        let data: Vec<u8> = (0..MAX_RECORD_PLAINTEXT * 2 + 100).map(|i| i as u8).collect();
+        let records = seal(&[&data]);
+        assert_eq!(records.len(), data.len() + 3 * (2 + TAG_SIZE));
+        assert_eq!(&records[..2], &(MAX_RECORD_SIZE as u16).to_be_bytes());
+        assert_eq!(open(&records).unwrap(), data);
+    }
+
+    #[test]
+    fn tampered_records_are_refused() {
+        let records = seal(&[b"hello"]);
+        for index in [2, records.len() - 1] {
+            let mut tampered = records.clone();
+            tampered[index] ^= 1;
+            let error = open(&tampered).unwrap_err();
+            assert_eq!(error.kind(), IoErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn reordered_records_are_refused() {
+        let records = seal(&[b"first", b"again"]);
+        let (first, second) = records.split_at(records.len() / 2);
+        let swapped = [second, first].concat();
+        assert_eq!(open(&swapped).unwrap_err().kind(), IoErrorKind::InvalidData);
+        // and so is one sent twice
+        let replayed = [first, first].concat();
+        assert_eq!(open(&replayed).unwrap_err().kind(), IoErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_records_are_refused() {
+        let records = seal(&[b"hello"]);
+        let error = open(&records[..records.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::UnexpectedEof);
+    }
+}
@@ -1,39 +1,262 @@
 use std::fmt;
-use serde::{Deserialize, Serialize};
-use sha256::digest;
+use std::io::{Result as IoResult, Write};
+use std::str::FromStr;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use crate::error::BtcError;
 use crate::U256;
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
-pub struct Hash(U256);
+/// A double-SHA256 digest.
+///
+/// The bytes are kept in the order the hash function produced
+/// them. Like in Bitcoin, the digest is read as a little-endian
+/// number when compared against a target, and is displayed
+/// and parsed byte-reversed so that mined hashes start with zeroes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Hash([u8; 32]);
+
 impl Hash {
+    /// Double-SHA256 of the CBOR serialization of `data`
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
-        let mut serialized: Vec<u8> = vec![];
-        if let Err(e) = ciborium::into_writer(data, &mut serialized) {
+        let mut engine = HashEngine::new();
+        if let Err(e) = ciborium::into_writer(data, &mut engine) {
             panic!(
                 "Failed to serialize hash: {:?} \
                     This should not happen",
                 e
             );
         }
-        let hash = digest(&serialized);
-        let hash_bytes = hex::decode(hash).unwrap();
-        let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap();
-        Hash(U256::from_big_endian(&hash_array))
+        engine.finalize()
+    }
+
+    /// Double-SHA256 of raw bytes
+    pub fn hash_bytes(data: &[u8]) -> Self {
+        let mut engine = HashEngine::new();
+        engine.input(data);
+        engine.finalize()
     }
 
     pub fn matches_target(&self, target: U256) -> bool {
-        self.0 <= target
+        self.to_u256() <= target
     }
     pub fn zero() -> Self {
-        Hash(U256::zero())
+        Hash([0u8; 32])
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(bytes)
     }
 
     pub fn as_bytes(&self) -> [u8; 32] {
-        self.0.to_little_endian()
+        self.0
+    }
+
+    /// The digest as a 256-bit number, used for proof-of-work
+    pub fn to_u256(&self) -> U256 {
+        U256::from_little_endian(&self.0)
+    }
+}
+
+/// Streaming double-SHA256. Implements `Write`, so serializers
+/// can feed it directly without an intermediate buffer.
+#[derive(Clone, Default)]
+pub struct HashEngine(Sha256);
+
+impl HashEngine {
+    pub fn new() -> Self {
+        HashEngine(Sha256::new())
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(self) -> Hash {
+        let first = self.0.finalize();
+        Hash(Sha256::digest(first).into())
     }
 }
+
+impl Write for HashEngine {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.input(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl From<[u8; 32]> for Hash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Hash(bytes)
+    }
+}
+
+impl From<Hash> for [u8; 32] {
+    fn from(hash: Hash) -> Self {
+        hash.0
+    }
+}
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        for byte in self.0.iter().rev() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hash({})", self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(s, &mut bytes)
+            .map_err(|_| BtcError::InvalidHash)?;
+        bytes.reverse();
+        Ok(Hash(bytes))
+    }
+}
+
+// hex in human-readable formats, raw bytes otherwise (e.g. CBOR)
+impl Serialize for Hash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct HashVisitor;
+
+        impl<'de> Visitor<'de> for HashVisitor {
+            type Value = Hash;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a 32-byte hash or its hex encoding")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Hash, E> {
+                v.parse()
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Hash, E> {
+                let bytes: [u8; 32] = v
+                    .try_into()
+                    .map_err(|_| E::invalid_length(v.len(), &self))?;
+                Ok(Hash(bytes))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Hash, A::Error> {
+                let mut bytes = [0u8; 32];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                Ok(Hash(bytes))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(HashVisitor)
+        } else {
+            deserializer.deserialize_bytes(HashVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // double-SHA256 of "hello", as displayed (byte-reversed)
+    const HELLO: &str = "503d8319a48348cdc610a582f7bf754b5833df65038606eb48510790dfc99595";
+
+    #[test]
+    fn hashes_known_vectors() {
+        let empty = Hash::hash_bytes(b"");
+        assert_eq!(
+            hex::encode(empty.as_bytes()),
+            "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456"
+        );
+        assert_eq!(Hash::hash_bytes(b"hello").to_string(), HELLO);
+
+        let mut engine = HashEngine::new();
+        engine.input(b"he");
+        engine.input(b"llo");
+        assert_eq!(engine.finalize(), Hash::hash_bytes(b"hello"));
+    }
+
+    #[test]
+    fn displays_and_parses_byte_reversed() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0xab;
+        let hash = Hash::from_bytes(bytes);
+        let shown = hash.to_string();
+        assert!(shown.ends_with("ab"));
+        assert!(shown.starts_with("00"));
+        assert_eq!(shown.parse::<Hash>().unwrap(), hash);
+
+        let hello: Hash = HELLO.parse().unwrap();
+        assert_eq!(hello, Hash::hash_bytes(b"hello"));
+        assert_eq!(hello.to_string(), HELLO);
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        assert!("".parse::<Hash>().is_err());
+        assert!(HELLO[2..].parse::<Hash>().is_err());
+        assert!(format!("{HELLO}00").parse::<Hash>().is_err());
+        assert!(format!("zz{}", &HELLO[2..]).parse::<Hash>().is_err());
+    }
+
+    #[test]
+    fn serializes_as_hex_or_bytes() {
+        let hash = Hash::hash_bytes(b"hello");
+
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{HELLO}\""));
+        assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), hash);
+        assert!(serde_json::from_str::<Hash>("\"00\"").is_err());
+
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&hash, &mut cbor).unwrap();
+        // a 32-byte CBOR byte string: 0x58 0x20 followed by the digest
+        assert_eq!(&cbor[..2], &[0x58, 0x20]);
+        assert_eq!(&cbor[2..], &hash.as_bytes());
+        assert_eq!(ciborium::from_reader::<Hash, _>(cbor.as_slice()).unwrap(), hash);
+
+        let mut short = Vec::new();
+        ciborium::into_writer(&ciborium::Value::Bytes(vec![0; 31]), &mut short).unwrap();
+        assert!(ciborium::from_reader::<Hash, _>(short.as_slice()).is_err());
     }
 }
@@ -1,5 +1,6 @@
 mod blockchain;
 mod block;
+mod header_chain;
 mod transaction;
 
 use crate::error::BtcError;
@@ -8,6 +9,7 @@ pub type Result<T> = std::result::Result<T, BtcError>;
 
 pub use block::{Block, BlockHeader};
 pub use blockchain::Blockchain;
+pub use header_chain::HeaderChain;
 pub use transaction::{
-    Transaction, TransactionInput, TransactionOutput,
+    OutPoint, OutputLock, Transaction, TransactionInput, TransactionOutput,
 };
\ No newline at end of file
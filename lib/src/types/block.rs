@@ -1,13 +1,22 @@
-use super::{Transaction, TransactionOutput};
+use super::{OutPoint, OutputLock, Transaction, TransactionOutput};
+use crate::address::PubkeyHash;
+use crate::crypto::{SchnorrSignature, VersionedPublicKey, VersionedSignature};
 use crate::error::BtcError;
+use crate::network::Network;
 use crate::sha256::Hash;
 use crate::utils::{MerkleRoot, Saveable};
 use crate::U256;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::{Read, Write, Error as IoError, Result as IoResult, ErrorKind as IoErrorKind};
 
+// found by mining the genesis blocks once; Network::genesis_hash
+// pins the resulting hashes
+const GENESIS_NONCE_MAINNET: u64 = 115_618;
+const GENESIS_NONCE_TESTNET: u64 = 24_315;
+const GENESIS_NONCE_REGTEST: u64 = 56_676;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Block {
     pub header: BlockHeader,
@@ -20,43 +29,87 @@ impl Block {
             transactions,
         }
     }
+    /// The first block of a network, which every chain on it
+    /// starts with. Its reward goes to a key hash nobody has a
+    /// key for, so it can never be spent.
+    pub fn genesis(network: Network) -> Self {
+        let (timestamp, nonce) = match network {
+            Network::Mainnet => (1_767_225_600, GENESIS_NONCE_MAINNET),
+            Network::Testnet => (1_767_312_000, GENESIS_NONCE_TESTNET),
+            Network::Regtest => (1_767_398_400, GENESIS_NONCE_REGTEST),
+        };
+        let transactions = vec![Transaction::coinbase(
+            0,
+            vec![TransactionOutput {
+                value: crate::INITIAL_REWARD * 10u64.pow(8),
+                lock: OutputLock::PubkeyHash(PubkeyHash::Ecdsa([0; 20])),
+            }],
+        )];
+        let header = BlockHeader::new(
+            Utc.timestamp_opt(timestamp, 0).unwrap(),
+            nonce,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            MerkleRoot::calculate_witness(&transactions),
+            crate::MIN_TARGET,
+        );
+        Block::new(header, transactions)
+    }
+
+    /// The block's hash is the hash of its header, which
+    /// commits to the transactions through the merkle roots
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
     }
 
     pub fn verify_transactions(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, (bool, TransactionOutput)>,
     ) -> crate::types::Result<()> {
-        let mut inputs: HashMap<Hash, (bool, TransactionOutput)> = HashMap::new();
+        let mut inputs: HashMap<OutPoint, (bool, TransactionOutput)> = HashMap::new();
         if self.transactions.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
         self.verify_coinbase_transaction(predicted_block_height, utxos)?;
+        // Schnorr signatures are collected and verified together
+        // once everything else about the block checks out
+        let mut schnorr_batch = vec![];
         for transaction in self.transactions.iter().skip(1) {
+            if transaction.is_coinbase() {
+                return Err(BtcError::InvalidTransaction);
+            }
             let mut input_value = 0;
             let mut output_value = 0;
+            let txid = transaction.txid();
             for input in &transaction.inputs {
                 let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
+                    .get(&input.previous_output)
                     .map(|(_, v)| v);
                 if prev_output.is_none() {
                     return Err(BtcError::InvalidTransaction);
                 }
                 let prev_output = prev_output.unwrap();
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
+                if inputs.contains_key(&input.previous_output) {
                     return Err(BtcError::InvalidTransaction);
                 }
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
-                {
+                let Some(key) = prev_output.lock.spending_key(input) else {
                     return Err(BtcError::InvalidSignature);
+                };
+                match (key, &input.signature) {
+                    (
+                        VersionedPublicKey::Schnorr(key),
+                        VersionedSignature::Schnorr(signature),
+                    ) => schnorr_batch.push((txid, *signature, *key)),
+                    (key, signature) => {
+                        if !key.verify(&txid, signature) {
+                            return Err(BtcError::InvalidSignature);
+                        }
+                    }
                 }
                 input_value += prev_output.value;
                 inputs.insert(
-                    input.prev_transaction_output_hash,
+                    input.previous_output,
                     (false, prev_output.clone()),
                 );
             }
@@ -67,18 +120,24 @@ impl Block {
                 return Err(BtcError::InvalidTransaction);
             }
         }
+        if !SchnorrSignature::verify_batch(&schnorr_batch) {
+            return Err(BtcError::InvalidSignature);
+        }
         Ok(())
     }
 
     pub fn verify_coinbase_transaction(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, (bool, TransactionOutput)>,
     ) -> crate::types::Result<()> {
         let coinbase_transaction = &self.transactions[0];
         if !coinbase_transaction.inputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
+        if coinbase_transaction.coinbase_height != Some(predicted_block_height) {
+            return Err(BtcError::InvalidTransaction);
+        }
         if coinbase_transaction.outputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
@@ -100,33 +159,31 @@ impl Block {
 
     pub fn calculate_miner_fees (
         &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, (bool, TransactionOutput)>,
     ) -> crate::types::Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut inputs: HashMap<OutPoint, TransactionOutput> = HashMap::new();
+        let mut output_value: u64 = 0;
         for transaction in self.transactions.iter().skip(1) {
             for input in &transaction.inputs {
                 let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
+                    .get(&input.previous_output)
                     .map(|(_, output)| output);
                 if prev_output.is_none() {
                     return Err(BtcError::InvalidTransaction);
                 }
                 let prev_output = prev_output.unwrap();
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-            }
-            for output in &transaction.outputs {
-                if outputs.contains_key(&output.hash()) {
+                if inputs.contains_key(&input.previous_output) {
                     return Err(BtcError::InvalidTransaction);
                 }
-                outputs.insert(output.hash(), output.clone());
+                inputs.insert(input.previous_output, prev_output.clone());
             }
+            output_value += transaction
+                .outputs
+                .iter()
+                .map(|output| output.value)
+                .sum::<u64>();
         }
         let input_value: u64 = inputs.values().map(|input| input.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
         Ok(input_value - output_value)
     }
 }
@@ -139,8 +196,11 @@ pub struct BlockHeader {
     pub nonce: u64,
     /// Hash of the previous block
     pub prev_block_hash: Hash,
-    /// Merkle root of the block's transactions
+    /// Merkle root of the block's transaction ids
     pub merkle_root: MerkleRoot,
+    /// Merkle root of the block's witness transaction ids,
+    /// committing to the signatures
+    pub witness_root: MerkleRoot,
     /// target
     pub target: U256,
 }
@@ -151,6 +211,7 @@ impl BlockHeader {
         nonce: u64,
         prev_block_hash: Hash,
         merkle_root: MerkleRoot,
+        witness_root: MerkleRoot,
         target: U256,
     ) -> Self {
         BlockHeader {
@@ -158,6 +219,7 @@ impl BlockHeader {
             nonce,
             prev_block_hash,
             merkle_root,
+            witness_root,
             target,
         }
     }
@@ -166,6 +228,17 @@ impl BlockHeader {
         Hash::hash(self)
     }
 
+    /// Expected number of hashes it took to mine a header with
+    /// this target, 2^256 / (target + 1). Zero for targets whose
+    /// work does not fit, like zero, which no header can meet.
+    pub fn work(&self) -> U256 {
+        self.target
+            .checked_add(U256::one())
+            .and_then(|divisor| (!self.target).checked_div(divisor))
+            .and_then(|work| work.checked_add(U256::one()))
+            .unwrap_or_default()
+    }
+
     pub fn mine(&mut self, steps: usize) -> bool {
         if self.hash().matches_target(self.target) {
             return true;
@@ -206,3 +279,34 @@ impl Saveable for Block {
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORKS: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Regtest];
+
+    #[test]
+    fn genesis_blocks_match_their_pinned_hashes() {
+        for network in NETWORKS {
+            let genesis = Block::genesis(network);
+            assert_eq!(genesis.hash(), network.genesis_hash(), "{network}");
+            assert!(genesis.hash().matches_target(genesis.header.target));
+            assert_eq!(genesis.header.prev_block_hash, Hash::zero());
+        }
+    }
+
+    #[test]
+    fn work_never_overflows() {
+        let mut header = Block::genesis(Network::Regtest).header;
+        header.target = U256::zero();
+        assert_eq!(header.work(), U256::zero());
+        header.target = U256::MAX;
+        assert_eq!(header.work(), U256::zero());
+        header.target = U256::MAX >> 1;
+        assert_eq!(header.work(), U256::from(2));
+        header.target = crate::MIN_TARGET;
+        assert_eq!(header.work(), U256::from(0x1_0000));
+    }
+}
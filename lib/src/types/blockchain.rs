@@ -8,8 +8,10 @@ use std::io::{
     Result as IoResult, Write,
 };
 
-use super::{Block, Transaction, TransactionOutput};
+use super::header_chain::locator_heights;
+use super::{Block, BlockHeader, OutPoint, Transaction, TransactionOutput};
 use crate::error::{BtcError, Result};
+use crate::network::Network;
 use crate::sha256::Hash;
 use crate::utils::MerkleRoot;
 use crate::U256;
@@ -18,95 +20,140 @@ use std::collections::{HashMap, HashSet};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    utxos: HashMap<OutPoint, (bool, TransactionOutput)>,
+    /// Height of the block that created each UTXO, rebuilt
+    /// along with the UTXOs when the chain is loaded
+    #[serde(default, skip_serializing)]
+    utxo_heights: HashMap<OutPoint, u64>,
     target: U256,
     blocks: Vec<Block>,
+    /// Height of every block by hash, rebuilt when the chain
+    /// is loaded
+    #[serde(default, skip_serializing)]
+    block_heights: HashMap<Hash, usize>,
     #[serde(default, skip_serializing)]
     mempool: Vec<(DateTime<Utc>, Transaction)>,
+    /// Position of every mempool transaction by txid
+    #[serde(default, skip_serializing)]
+    mempool_positions: HashMap<Hash, usize>,
 }
 
 impl Blockchain {
-    pub fn new() -> Self {
-        Blockchain {
+    // a chain holding the genesis block of the network, which
+    // every other block builds on
+    pub fn new(network: Network) -> Self {
+        let mut blockchain = Blockchain {
             utxos: HashMap::new(),
+            utxo_heights: HashMap::new(),
             blocks: vec![],
+            block_heights: HashMap::new(),
             target: crate::MIN_TARGET,
             mempool: vec![],
-        }
+            mempool_positions: HashMap::new(),
+        };
+        let genesis = Block::genesis(network);
+        blockchain.apply_utxos(&genesis);
+        blockchain.push_block(genesis);
+        blockchain
     }
 
     // try to add a new block to the blockchain,
     // return an error if it is not valid to insert this
     // block to this blockchain
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        // check if the block is valid
-        if self.blocks.is_empty() {
-            // if this is the first block, check if the
-            // block's prev_block_hash is all zeroes
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
-            }
-        } else {
-            // if this is not the first block, check if the
-            // block's prev_block_hash is the hash of the last block
-            let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash()
-            {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
-            }
+        // check if the block is valid. The genesis block comes
+        // with the chain and is never replaced, so a block always
+        // has to extend the last one.
+        let Some(last_block) = self.blocks.last() else {
+            return Err(BtcError::OrphanBlock);
+        };
+        if block.header.prev_block_hash != last_block.hash()
+        {
+            println!("prev hash is wrong");
+            return Err(BtcError::OrphanBlock);
+        }
 
-            // check if the block's hash is less than the target
-            if !block
-                .header
-                .hash()
-                .matches_target(block.header.target)
-            {
-                println!("does not match target");
-                return Err(BtcError::InvalidBlock);
-            }
+        // the target has to be one a block can meet, and no
+        // easier than the minimum difficulty
+        if block.header.target.is_zero()
+            || block.header.target > crate::MIN_TARGET
+        {
+            println!("target out of range");
+            return Err(BtcError::InvalidBlock);
+        }
 
-            // check if the block's merkle root is correct
-            let calculated_merkle_root =
-                MerkleRoot::calculate(&block.transactions);
-            if calculated_merkle_root != block.header.merkle_root
-            {
-                return Err(BtcError::InvalidMerkleRoot);
-            }
+        // check if the block's hash is less than the target
+        if !block
+            .header
+            .hash()
+            .matches_target(block.header.target)
+        {
+            println!("does not match target");
+            return Err(BtcError::InvalidBlock);
+        }
 
-            // check if the block's timestamp is after the
-            // last block's timestamp
-            if block.header.timestamp
-                <= last_block.header.timestamp
-            {
-                println!("old timestamp");
-                return Err(BtcError::InvalidBlock);
-            }
+        // check if the block's merkle root is correct
+        let calculated_merkle_root =
+            MerkleRoot::calculate(&block.transactions);
+        if calculated_merkle_root != block.header.merkle_root
+        {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
 
-            // Verify all transactions in the block
-            block.verify_transactions(
-                self.block_height(),
-                &self.utxos,
-            )?;
+        // check if the block's witness root is correct
+        let calculated_witness_root =
+            MerkleRoot::calculate_witness(&block.transactions);
+        if calculated_witness_root != block.header.witness_root
+        {
+            return Err(BtcError::InvalidMerkleRoot);
         }
 
+        // check if the block's timestamp is after the
+        // last block's timestamp
+        if block.header.timestamp
+            <= last_block.header.timestamp
+        {
+            println!("old timestamp");
+            return Err(BtcError::InvalidBlock);
+        }
+
+        // Verify all transactions in the block
+        block.verify_transactions(
+            self.block_height(),
+            &self.utxos,
+        )?;
+
         // Remove transactions from mempool that are now in the block
         let block_transactions: HashSet<_> = block
             .transactions
             .iter()
-            .map(|tx| tx.hash())
+            .map(|tx| tx.txid())
             .collect();
-        self.mempool.retain(|(_, tx)| {
-            !block_transactions.contains(&tx.hash())
-        });
+        self.retain_mempool(|txid, _| !block_transactions.contains(txid));
 
-        self.blocks.push(block);
+        self.apply_utxos(&block);
+        self.push_block(block);
         self.try_adjust_target();
 
         Ok(())
     }
 
+    fn push_block(&mut self, block: Block) {
+        self.block_heights.insert(block.hash(), self.blocks.len());
+        self.blocks.push(block);
+    }
+
+    // rebuild the hash index after blocks were removed, or
+    // after loading
+    fn index_blocks(&mut self) {
+        self.block_heights = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (block.hash(), height))
+            .collect();
+    }
+
     // try to adjust the target of the blockchain
     pub fn try_adjust_target(&mut self) {
         if self.blocks.len()
@@ -115,9 +162,10 @@ impl Blockchain {
             return;
         }
 
-        if self.blocks.len()
-            % crate::DIFFICULTY_UPDATE_INTERVAL as usize
-            != 0
+        if !self
+            .blocks
+            .len()
+            .is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL as usize)
         {
             println!("won't update on this block");
             return;
@@ -143,7 +191,7 @@ impl Blockchain {
 
         // multiply the current target by actual time divided by ideal time
         let new_target = BigDecimal::parse_bytes(
-            &self.target.to_string().as_bytes(),
+            self.target.to_string().as_bytes(),
             10,
         )
             .expect("BUG: impossible")
@@ -185,22 +233,137 @@ impl Blockchain {
 
     // Rebuild UTXO set from the blockchain
     pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(
-                        &input.prev_transaction_output_hash,
-                    );
-                }
+        let mut utxos = HashMap::new();
+        let mut utxo_heights = HashMap::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            Self::apply_block_utxos(
+                &mut utxos,
+                &mut utxo_heights,
+                block,
+                height as u64,
+            );
+        }
+        self.utxos = utxos;
+        self.utxo_heights = utxo_heights;
+    }
 
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(
-                        output.hash(),
-                        (false, output.clone()),
-                    );
-                }
+    // spend the block's inputs and add its outputs, so the next
+    // block can be verified without a full rebuild
+    fn apply_utxos(&mut self, block: &Block) {
+        Self::apply_block_utxos(
+            &mut self.utxos,
+            &mut self.utxo_heights,
+            block,
+            self.blocks.len() as u64,
+        );
+    }
+
+    fn apply_block_utxos(
+        utxos: &mut HashMap<OutPoint, (bool, TransactionOutput)>,
+        utxo_heights: &mut HashMap<OutPoint, u64>,
+        block: &Block,
+        height: u64,
+    ) {
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                utxos.remove(&input.previous_output);
+                utxo_heights.remove(&input.previous_output);
+            }
+
+            let txid = transaction.txid();
+            for (vout, output) in
+                transaction.outputs.iter().enumerate()
+            {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                utxos.insert(outpoint, (false, output.clone()));
+                utxo_heights.insert(outpoint, height);
+            }
+        }
+    }
+
+    // hashes going back from the tip, for a GetHeaders request
+    pub fn locator(&self) -> Vec<Hash> {
+        locator_heights(self.block_height())
+            .into_iter()
+            .map(|height| self.blocks[height as usize].hash())
+            .collect()
+    }
+
+    // headers following the first block of the locator that is
+    // in our chain, or from genesis if there is none, up to and
+    // including the stop hash
+    pub fn headers_after(
+        &self,
+        locator: &[Hash],
+        stop_hash: &Hash,
+        max: usize,
+    ) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.block_heights.get(hash))
+            .map_or(0, |height| height + 1);
+        let mut headers = vec![];
+        for block in self.blocks.iter().skip(start).take(max) {
+            headers.push(block.header.clone());
+            if block.hash() == *stop_hash {
+                break;
             }
         }
+        headers
+    }
+
+    // switch to another branch: drop our blocks from `fork_height`
+    // on and add `blocks` in their place. If one of them turns out
+    // invalid, the old branch is put back and the error comes with
+    // the index of that block in `blocks`. Branches share the
+    // genesis block, so they fork at height 1 at the earliest.
+    pub fn reorganize(
+        &mut self,
+        fork_height: u64,
+        blocks: Vec<Block>,
+    ) -> std::result::Result<(), (usize, BtcError)> {
+        if fork_height == 0 {
+            return Err((0, BtcError::InvalidBlock));
+        }
+        let old_blocks = self.blocks.split_off(fork_height as usize);
+        let old_mempool = std::mem::take(&mut self.mempool);
+        let old_positions = std::mem::take(&mut self.mempool_positions);
+        self.reset_chain_state();
+
+        for (index, block) in blocks.into_iter().enumerate() {
+            if let Err(e) = self.add_block(block) {
+                self.blocks.truncate(fork_height as usize);
+                self.blocks.extend(old_blocks);
+                self.mempool = old_mempool;
+                self.mempool_positions = old_positions;
+                self.reset_chain_state();
+                return Err((index, e));
+            }
+        }
+
+        // transactions of the dropped blocks go back to the mempool,
+        // as long as they still fit on the new branch
+        let transactions = old_blocks
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .chain(old_mempool.into_iter().map(|(_, tx)| tx));
+        for transaction in transactions {
+            let _ = self.add_to_mempool(transaction);
+        }
+        Ok(())
+    }
+
+    // recompute the UTXO set, the hash index and the target for
+    // the next block after blocks were removed from the tip
+    fn reset_chain_state(&mut self) {
+        self.index_blocks();
+        self.target = self
+            .blocks
+            .last()
+            .map_or(crate::MIN_TARGET, |block| block.header.target);
+        self.try_adjust_target();
+        self.rebuild_utxos();
     }
 
     pub fn calculate_block_reward(&self) -> u64 {
@@ -215,13 +378,24 @@ impl Blockchain {
         }
     }
 
+    // hash of the first block, which tells the network the
+    // chain belongs to
+    pub fn genesis_hash(&self) -> Hash {
+        self.blocks[0].hash()
+    }
+
     // utxos
     pub fn utxos(
         &self,
-    ) -> &HashMap<Hash, (bool, TransactionOutput)> {
+    ) -> &HashMap<OutPoint, (bool, TransactionOutput)> {
         &self.utxos
     }
 
+    // height of the block that created an unspent output
+    pub fn utxo_height(&self, outpoint: &OutPoint) -> Option<u64> {
+        self.utxo_heights.get(outpoint).copied()
+    }
+
     // target
     pub fn target(&self) -> U256 {
         self.target
@@ -232,6 +406,23 @@ impl Blockchain {
         self.blocks.iter()
     }
 
+    // block with the given hash, if it is in the chain
+    pub fn block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        self.block_heights
+            .get(hash)
+            .map(|&height| &self.blocks[height])
+    }
+
+    // transaction with the given txid, if it is in the mempool
+    pub fn mempool_transaction(
+        &self,
+        txid: &Hash,
+    ) -> Option<&Transaction> {
+        self.mempool_positions
+            .get(txid)
+            .map(|&position| &self.mempool[position].1)
+    }
+
     // block height
     pub fn block_height(&self) -> u64 {
         self.blocks.len() as u64
@@ -247,27 +438,44 @@ impl Blockchain {
         &mut self,
         transaction: Transaction,
     ) -> Result<()> {
+        // coinbase transactions only make sense inside a block
+        if transaction.is_coinbase() {
+            println!("coinbase transaction outside of a block");
+            return Err(BtcError::InvalidTransaction);
+        }
+
         // validate transaction before insertion
         // all inputs must match known UTXOs, and must be unique
         let mut known_inputs = HashSet::new();
         for input in &transaction.inputs {
             if !self.utxos.contains_key(
-                &input.prev_transaction_output_hash,
+                &input.previous_output,
             ) {
                 println!("UTXO not found");
                 dbg!(&self.utxos);
-                return Err(BtcError::InvalidTransaction);
+                return Err(BtcError::MissingInputs);
             }
 
             if known_inputs
-                .contains(&input.prev_transaction_output_hash)
+                .contains(&input.previous_output)
             {
                 println!("duplicate input");
                 return Err(BtcError::InvalidTransaction);
             }
 
             known_inputs
-                .insert(input.prev_transaction_output_hash);
+                .insert(input.previous_output);
+        }
+
+        // all signatures must be valid and in low-S form
+        let txid = transaction.txid();
+        for input in &transaction.inputs {
+            let (_, prev_output) = &self.utxos
+                [&input.previous_output];
+            if !prev_output.lock.verify(&txid, input) {
+                println!("invalid signature");
+                return Err(BtcError::InvalidSignature);
+            }
         }
 
         // check if any of the utxos have the bool mark set to true
@@ -277,21 +485,21 @@ impl Blockchain {
         for input in &transaction.inputs {
             if let Some((true, _)) = self
                 .utxos
-                .get(&input.prev_transaction_output_hash)
+                .get(&input.previous_output)
             {
-                // find the transaction that references the UTXO
-                // we are trying to reference
+                // find the transaction that spends the UTXO
+                // we are trying to spend
                 let referencing_transaction = self.mempool
                     .iter()
                     .enumerate()
                     .find(
                         |(_, (_, transaction))| {
                             transaction
-                                .outputs
+                                .inputs
                                 .iter()
-                                .any(|output| {
-                                    output.hash()
-                                        == input.prev_transaction_output_hash
+                                .any(|other| {
+                                    other.previous_output
+                                        == input.previous_output
                                 })
                         },
                     );
@@ -306,20 +514,24 @@ impl Blockchain {
                     {
                         // set all utxos from this transaction to false
                         self.utxos
-                            .entry(input.prev_transaction_output_hash)
+                            .entry(input.previous_output)
                             .and_modify(|(marked, _)| {
                                 *marked = false;
                             });
                     }
 
                     // remove the transaction from the mempool
-                    self.mempool.remove(idx);
+                    let mut position = 0;
+                    self.retain_mempool(|_, _| {
+                        position += 1;
+                        position - 1 != idx
+                    });
                 } else {
                     // if, somehow, there is no matching transaction,
                     // set this utxo to false
                     self.utxos
                         .entry(
-                            input.prev_transaction_output_hash,
+                            input.previous_output,
                         )
                         .and_modify(|(marked, _)| {
                             *marked = false;
@@ -334,7 +546,7 @@ impl Blockchain {
             .iter()
             .map(|input| {
                 self.utxos
-                    .get(&input.prev_transaction_output_hash)
+                    .get(&input.previous_output)
                     .expect("BUG: impossible")
                     .1
                     .value
@@ -354,23 +566,29 @@ impl Blockchain {
         // Mark the UTXOs as used
         for input in &transaction.inputs {
             self.utxos
-                .entry(input.prev_transaction_output_hash)
+                .entry(input.previous_output)
                 .and_modify(|(marked, _)| {
                     *marked = true;
                 });
         }
 
-        // push the transaction to the mempool
-        self.mempool.push((Utc::now(), transaction));
+        // push the transaction to the mempool, keeping the txids
+        // next to the transactions while they are sorted
+        let mut entries: Vec<_> = self
+            .mempool_txids()
+            .into_iter()
+            .zip(std::mem::take(&mut self.mempool))
+            .collect();
+        entries.push((txid, (Utc::now(), transaction)));
 
         // sort by miner fee
-        self.mempool.sort_by_key(|(_, transaction)| {
+        entries.sort_by_key(|(_, (_, transaction))| {
             let all_inputs = transaction
                 .inputs
                 .iter()
                 .map(|input| {
                     self.utxos
-                        .get(&input.prev_transaction_output_hash)
+                        .get(&input.previous_output)
                         .expect("BUG: impossible")
                         .1
                         .value
@@ -383,20 +601,59 @@ impl Blockchain {
                 .map(|output| output.value)
                 .sum();
 
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
+            all_inputs - all_outputs
         });
+        let (txids, mempool) = entries.into_iter().unzip();
+        self.mempool = mempool;
+        self.index_mempool(txids);
 
         Ok(())
     }
 
+    // txids of the mempool transactions in order, taken from
+    // the index rather than hashing every transaction again
+    fn mempool_txids(&self) -> Vec<Hash> {
+        let mut txids = vec![Hash::zero(); self.mempool.len()];
+        for (txid, &position) in &self.mempool_positions {
+            txids[position] = *txid;
+        }
+        txids
+    }
+
+    fn index_mempool(&mut self, txids: Vec<Hash>) {
+        self.mempool_positions = txids
+            .into_iter()
+            .enumerate()
+            .map(|(position, txid)| (txid, position))
+            .collect();
+    }
+
+    // keep the mempool transactions `keep` returns true for,
+    // along with their place in the index
+    fn retain_mempool(
+        &mut self,
+        mut keep: impl FnMut(&Hash, &(DateTime<Utc>, Transaction)) -> bool,
+    ) {
+        let mut txids = self.mempool_txids().into_iter();
+        let mut kept = Vec::with_capacity(self.mempool.len());
+        self.mempool.retain(|entry| {
+            let txid = txids.next().expect("BUG: txid index out of sync");
+            let keep = keep(&txid, entry);
+            if keep {
+                kept.push(txid);
+            }
+            keep
+        });
+        self.index_mempool(kept);
+    }
+
     // Cleanup mempool - remove transactions older than
     // MAX_MEMPOOL_TRANSACTION_AGE
     pub fn cleanup_mempool(&mut self) {
         let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        let mut utxos_to_unmark: Vec<OutPoint> = vec![];
 
-        self.mempool.retain(|(timestamp, transaction)| {
+        self.retain_mempool(|_, (timestamp, transaction)| {
             if now - *timestamp
                 > chrono::Duration::seconds(
                 crate::MAX_MEMPOOL_TRANSACTION_AGE as i64,
@@ -404,9 +661,9 @@ impl Blockchain {
             {
                 // push all utxos to unmark to the vector
                 // so we can unmark them later
-                utxo_hashes_to_unmark.extend(
+                utxos_to_unmark.extend(
                     transaction.inputs.iter().map(|input| {
-                        input.prev_transaction_output_hash
+                        input.previous_output
                     }),
                 );
 
@@ -417,8 +674,8 @@ impl Blockchain {
         });
 
         // unmark all of the UTXOs
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
+        for outpoint in utxos_to_unmark {
+            self.utxos.entry(outpoint).and_modify(|(marked, _)| {
                 *marked = false;
             });
         }
@@ -428,12 +685,15 @@ impl Blockchain {
 // save and load expecting CBOR from ciborium as format
 impl Saveable for Blockchain {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(
-                IoErrorKind::InvalidData,
-                "Failed to deserialize Blockchain",
-            )
-        })
+        let mut blockchain: Blockchain =
+            ciborium::de::from_reader(reader).map_err(|_| {
+                IoError::new(
+                    IoErrorKind::InvalidData,
+                    "Failed to deserialize Blockchain",
+                )
+            })?;
+        blockchain.index_blocks();
+        Ok(blockchain)
     }
 
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -445,3 +705,224 @@ impl Saveable for Blockchain {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::PubkeyHash;
+    use crate::crypto::{PrivateKey, VersionedPublicKey, VersionedSignature};
+    use crate::types::{OutputLock, TransactionInput};
+    use chrono::Duration;
+
+    // a block on top of the chain's tip, `seconds` after it,
+    // paying the reward to nobody. Different gaps give different
+    // branches.
+    fn next_block(blockchain: &Blockchain, seconds: i64) -> Block {
+        let nobody = OutputLock::PubkeyHash(PubkeyHash::Ecdsa([0; 20]));
+        mine(blockchain, seconds, nobody, vec![], 0)
+    }
+
+    // a block with `transactions`, paying the reward and their
+    // `fees` to `lock`
+    fn mine(
+        blockchain: &Blockchain,
+        seconds: i64,
+        lock: OutputLock,
+        transactions: Vec<Transaction>,
+        fees: u64,
+    ) -> Block {
+        let parent = &blockchain.blocks.last().unwrap().header;
+        let coinbase = Transaction::coinbase(
+            blockchain.block_height(),
+            vec![TransactionOutput {
+                value: blockchain.calculate_block_reward() + fees,
+                lock,
+            }],
+        );
+        let transactions: Vec<Transaction> =
+            std::iter::once(coinbase).chain(transactions).collect();
+        let mut header = BlockHeader::new(
+            parent.timestamp + Duration::seconds(seconds),
+            0,
+            parent.hash(),
+            MerkleRoot::calculate(&transactions),
+            MerkleRoot::calculate_witness(&transactions),
+            blockchain.target(),
+        );
+        while !header.hash().matches_target(header.target) {
+            header.nonce += 1;
+        }
+        Block::new(header, transactions)
+    }
+
+    fn grow(blockchain: &mut Blockchain, length: usize, seconds: i64) {
+        for _ in 0..length {
+            let block = next_block(blockchain, seconds);
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    fn hash_at(blockchain: &Blockchain, height: usize) -> Hash {
+        blockchain.blocks[height].hash()
+    }
+
+    // spend `outpoint`, worth `value`, back to the key, leaving
+    // `fee` to the miner
+    fn spend(private_key: &PrivateKey, outpoint: OutPoint, value: u64, fee: u64) -> Transaction {
+        let public_key = VersionedPublicKey::from(private_key.public_key());
+        let outputs = vec![TransactionOutput {
+            value: value - fee,
+            lock: OutputLock::from(public_key.clone()),
+        }];
+        let txid = Transaction::compute_txid(&[outpoint], &outputs);
+        let inputs = vec![TransactionInput {
+            previous_output: outpoint,
+            signature: VersionedSignature::sign(&txid, private_key, &public_key),
+            public_key: None,
+        }];
+        Transaction::new(inputs, outputs)
+    }
+
+    fn assert_mempool_indexed(blockchain: &Blockchain) {
+        assert_eq!(blockchain.mempool_positions.len(), blockchain.mempool.len());
+        for (_, transaction) in blockchain.mempool() {
+            let found = blockchain.mempool_transaction(&transaction.txid()).unwrap();
+            assert_eq!(found.wtxid(), transaction.wtxid());
+        }
+    }
+
+    #[test]
+    fn new_chain_holds_the_genesis_block() {
+        let blockchain = Blockchain::new(Network::Regtest);
+        assert_eq!(blockchain.block_height(), 1);
+        assert_eq!(blockchain.genesis_hash(), Network::Regtest.genesis_hash());
+        assert_eq!(blockchain.utxos().len(), 1);
+        let outpoint = blockchain.utxos().keys().next().unwrap();
+        assert_eq!(blockchain.utxo_height(outpoint), Some(0));
+    }
+
+    #[test]
+    fn genesis_blocks_cannot_be_added() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        for network in [Network::Regtest, Network::Mainnet] {
+            assert!(blockchain.add_block(Block::genesis(network)).is_err());
+        }
+        assert_eq!(blockchain.block_height(), 1);
+    }
+
+    #[test]
+    fn reorganize_keeps_the_genesis_block() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        let forged = Block::genesis(Network::Mainnet);
+        assert!(matches!(blockchain.reorganize(0, vec![forged]), Err((0, _))));
+        assert_eq!(blockchain.block_height(), 1);
+        assert_eq!(blockchain.genesis_hash(), Network::Regtest.genesis_hash());
+    }
+
+    #[test]
+    fn headers_follow_the_first_known_locator_hash() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        grow(&mut blockchain, 5, 60);
+        let hash = |height| hash_at(&blockchain, height);
+
+        let headers = blockchain.headers_after(&[hash(2)], &Hash::zero(), 100);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0].prev_block_hash, hash(2));
+
+        // unknown hashes are skipped, and with none known the
+        // headers start at genesis
+        let unknown = Hash::hash_bytes(b"unknown");
+        let headers = blockchain.headers_after(&[unknown, hash(4)], &Hash::zero(), 100);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(blockchain.headers_after(&[unknown], &Hash::zero(), 100).len(), 6);
+
+        // up to the stop hash or the maximum
+        assert_eq!(blockchain.headers_after(&[hash(0)], &hash(3), 100).len(), 3);
+        assert_eq!(blockchain.headers_after(&[hash(0)], &Hash::zero(), 2).len(), 2);
+        assert!(blockchain.headers_after(&[hash(5)], &Hash::zero(), 100).is_empty());
+    }
+
+    #[test]
+    fn hash_index_follows_reorganizations_and_loads() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        grow(&mut blockchain, 3, 60);
+        let old_tip = hash_at(&blockchain, 3);
+
+        let mut other = blockchain.clone();
+        other.reorganize(2, vec![]).unwrap();
+        grow(&mut other, 3, 90);
+        let branch: Vec<Block> = other.blocks().skip(2).cloned().collect();
+        blockchain.reorganize(2, branch).unwrap();
+        assert_eq!(blockchain.block_height(), 5);
+
+        // the dropped block is not found any more
+        let locator = [old_tip, hash_at(&blockchain, 1)];
+        let headers = blockchain.headers_after(&locator, &Hash::zero(), 100);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0].prev_block_hash, hash_at(&blockchain, 1));
+
+        let mut bytes = vec![];
+        blockchain.save(&mut bytes).unwrap();
+        let loaded = Blockchain::load(bytes.as_slice()).unwrap();
+        let tip = hash_at(&loaded, 4);
+        assert_eq!(loaded.headers_after(&[tip], &Hash::zero(), 100).len(), 0);
+        assert_eq!(loaded.headers_after(&[hash_at(&loaded, 3)], &Hash::zero(), 100).len(), 1);
+    }
+
+    #[test]
+    fn blocks_are_found_by_hash() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        grow(&mut blockchain, 3, 60);
+        for height in 0..4 {
+            let block = blockchain.block_by_hash(&hash_at(&blockchain, height)).unwrap();
+            assert_eq!(block.hash(), hash_at(&blockchain, height));
+        }
+        assert!(blockchain.block_by_hash(&Hash::hash_bytes(b"unknown")).is_none());
+
+        let dropped = hash_at(&blockchain, 3);
+        blockchain.reorganize(3, vec![]).unwrap();
+        assert!(blockchain.block_by_hash(&dropped).is_none());
+    }
+
+    #[test]
+    fn mempool_index_follows_inserts_and_removals() {
+        let private_key = PrivateKey::new_key();
+        let lock = OutputLock::from(VersionedPublicKey::from(private_key.public_key()));
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        let mut outpoints = vec![];
+        for _ in 0..2 {
+            let funding = mine(&blockchain, 60, lock.clone(), vec![], 0);
+            outpoints.push(OutPoint::new(funding.transactions[0].txid(), 0));
+            blockchain.add_block(funding).unwrap();
+        }
+        let reward = blockchain.calculate_block_reward();
+        let other = spend(&private_key, outpoints[1], reward, 30);
+        blockchain.add_to_mempool(other.clone()).unwrap();
+        let outpoint = outpoints[0];
+
+        // a higher fee sorts the replacement to another place,
+        // and the transaction it replaces is no longer found
+        let first = spend(&private_key, outpoint, reward, 10);
+        blockchain.add_to_mempool(first.clone()).unwrap();
+        assert_mempool_indexed(&blockchain);
+        let replacement = spend(&private_key, outpoint, reward, 20);
+        blockchain.add_to_mempool(replacement.clone()).unwrap();
+        assert_mempool_indexed(&blockchain);
+        assert!(blockchain.mempool_transaction(&first.txid()).is_none());
+        assert_eq!(blockchain.mempool().len(), 2);
+        assert_eq!(blockchain.mempool()[1].1.txid(), other.txid());
+
+        // mined transactions leave the mempool
+        let block = mine(&blockchain, 60, lock, vec![replacement.clone()], 20);
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.mempool_transaction(&replacement.txid()).is_none());
+        assert!(blockchain.mempool_transaction(&other.txid()).is_some());
+        assert_mempool_indexed(&blockchain);
+
+        // and come back when their block is dropped
+        blockchain.reorganize(3, vec![]).unwrap();
+        assert!(blockchain.mempool_transaction(&replacement.txid()).is_some());
+        assert!(blockchain.mempool_transaction(&other.txid()).is_some());
+        assert_mempool_indexed(&blockchain);
+    }
+}
@@ -0,0 +1,384 @@
+use super::{Block, BlockHeader};
+use crate::error::{BtcError, Result};
+use crate::network::Network;
+use crate::sha256::Hash;
+use crate::utils::Saveable;
+use crate::U256;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+/// A chain of block headers without the transactions, as kept
+/// by light clients. It starts at the network's genesis block,
+/// and every header after it has to link to the one before,
+/// meet its own target, stay within the minimum difficulty and
+/// be newer than its parent. Whether the target follows the
+/// difficulty adjustment is not checked, so the chain is only
+/// as trustworthy as the work that went into it.
+#[derive(Clone, Debug)]
+pub struct HeaderChain {
+    network: Network,
+    headers: Vec<BlockHeader>,
+    /// Height of each header by hash
+    heights: HashMap<Hash, u64>,
+    work: U256,
+}
+
+impl HeaderChain {
+    /// A chain holding only the genesis header of `network`
+    pub fn new(network: Network) -> Self {
+        let genesis = Block::genesis(network).header;
+        HeaderChain {
+            network,
+            work: genesis.work(),
+            heights: HashMap::from([(genesis.hash(), 0)]),
+            headers: vec![genesis],
+        }
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Number of headers in the chain, counting the genesis
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers.last().expect("BUG: the genesis header is never removed")
+    }
+
+    /// Hash of the last header, which the next header has to
+    /// point back to
+    pub fn tip_hash(&self) -> Hash {
+        self.tip().hash()
+    }
+
+    pub fn header(&self, height: u64) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    /// Height of the header with this hash, if it is in the chain
+    pub fn height_of(&self, hash: &Hash) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &BlockHeader> {
+        self.headers.iter()
+    }
+
+    /// Hashes of headers going back from the tip, for a
+    /// `GetHeaders` request
+    pub fn locator(&self) -> Vec<Hash> {
+        locator_heights(self.height())
+            .into_iter()
+            .map(|height| self.headers[height as usize].hash())
+            .collect()
+    }
+
+    /// Sum of the work of every header in the chain
+    pub fn total_work(&self) -> U256 {
+        self.work
+    }
+
+    /// Append a header after checking that it extends the tip
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<()> {
+        let tip = self.tip();
+        let hash = header.hash();
+        if header.prev_block_hash != tip.hash()
+            || header.target.is_zero()
+            || header.target > crate::MIN_TARGET
+            || !hash.matches_target(header.target)
+            || header.timestamp <= tip.timestamp
+        {
+            return Err(BtcError::InvalidBlockHeader);
+        }
+        self.work = self.work.saturating_add(header.work());
+        self.heights.insert(hash, self.height());
+        self.headers.push(header);
+        Ok(())
+    }
+
+    /// Add headers a peer sent in answer to our locator. Ones
+    /// we already have are skipped. If the rest branch off below
+    /// the tip, our headers after the fork point are dropped
+    /// first, so compare `total_work` with the chain as it was to
+    /// decide which one to keep. The genesis header is never
+    /// replaced, so headers starting another chain are rejected.
+    pub fn connect(&mut self, headers: Vec<BlockHeader>) -> Result<()> {
+        let known = headers
+            .iter()
+            .take_while(|header| self.height_of(&header.hash()).is_some())
+            .count();
+        let mut headers = headers.into_iter().skip(known).peekable();
+        if let Some(first) = headers.peek() {
+            if first.prev_block_hash != self.tip_hash() {
+                let fork = self
+                    .height_of(&first.prev_block_hash)
+                    .ok_or(BtcError::InvalidBlockHeader)?;
+                self.truncate(fork + 1);
+            }
+        }
+        self.extend(headers)
+    }
+
+    /// Drop every header from `height` on. The genesis header
+    /// always stays.
+    pub fn truncate(&mut self, height: u64) {
+        let height = height.max(1) as usize;
+        for header in self.headers.iter().skip(height) {
+            self.heights.remove(&header.hash());
+        }
+        self.headers.truncate(height);
+        self.work = self
+            .headers
+            .iter()
+            .fold(U256::zero(), |work, header| work.saturating_add(header.work()));
+    }
+
+    /// Number of headers this chain shares with `other`, which
+    /// is the height of the first block after their common
+    /// ancestor
+    pub fn fork_point(&self, other: &HeaderChain) -> u64 {
+        self.headers
+            .iter()
+            .zip(&other.headers)
+            .take_while(|(ours, theirs)| ours.hash() == theirs.hash())
+            .count() as u64
+    }
+
+    /// Append headers in order, stopping at the first invalid
+    /// one. The headers before it stay in the chain.
+    pub fn extend(&mut self, headers: impl IntoIterator<Item = BlockHeader>) -> Result<()> {
+        for header in headers {
+            self.add_header(header)?;
+        }
+        Ok(())
+    }
+}
+
+// saved with the network it belongs to, and checked again
+// header by header when loaded
+impl Saveable for HeaderChain {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        let (network, headers): (Network, Vec<BlockHeader>) =
+            ciborium::de::from_reader(reader).map_err(|_| {
+                IoError::new(IoErrorKind::InvalidData, "Failed to deserialize HeaderChain")
+            })?;
+        let mut chain = HeaderChain::new(network);
+        chain.connect(headers).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Invalid header in HeaderChain")
+        })?;
+        Ok(chain)
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(&(self.network, &self.headers), writer).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to serialize HeaderChain")
+        })
+    }
+}
+
+/// Heights a block locator refers to in a chain of `height`
+/// blocks: the last ten one by one, then twice as far apart
+/// each step, always ending with the genesis block. This keeps
+/// locators short while a peer still finds a recent block it
+/// shares with us.
+pub(crate) fn locator_heights(height: u64) -> Vec<u64> {
+    let mut heights = vec![];
+    let Some(mut current) = height.checked_sub(1) else {
+        return heights;
+    };
+    let mut step = 1;
+    loop {
+        heights.push(current);
+        if current == 0 {
+            break;
+        }
+        if heights.len() >= 10 {
+            step *= 2;
+        }
+        current = current.saturating_sub(step);
+    }
+    heights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    // a header on top of `parent`, `seconds` after it. Different
+    // gaps give different branches.
+    fn child(parent: &BlockHeader, seconds: i64) -> BlockHeader {
+        let mut header = parent.clone();
+        header.prev_block_hash = parent.hash();
+        header.timestamp = parent.timestamp + Duration::seconds(seconds);
+        header.nonce = 0;
+        while !header.hash().matches_target(header.target) {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn branch(parent: &BlockHeader, length: usize, seconds: i64) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for _ in 0..length {
+            let header = child(headers.last().unwrap_or(parent), seconds);
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn genesis() -> BlockHeader {
+        Block::genesis(Network::Regtest).header
+    }
+
+    #[test]
+    fn new_chain_holds_the_genesis_header() {
+        let chain = HeaderChain::new(Network::Regtest);
+        assert_eq!(chain.height(), 1);
+        assert_eq!(chain.tip_hash(), Network::Regtest.genesis_hash());
+        assert_eq!(chain.height_of(&Network::Regtest.genesis_hash()), Some(0));
+        assert_eq!(chain.locator(), vec![Network::Regtest.genesis_hash()]);
+        assert_eq!(chain.total_work(), genesis().work());
+    }
+
+    #[test]
+    fn valid_headers_extend_the_chain() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let headers = branch(&genesis(), 3, 60);
+        chain.extend(headers.clone()).unwrap();
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.tip_hash(), headers[2].hash());
+        for (height, header) in headers.iter().enumerate() {
+            assert_eq!(chain.height_of(&header.hash()), Some(height as u64 + 1));
+        }
+        assert_eq!(chain.total_work(), genesis().work() * 4);
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let good = child(&genesis(), 60);
+
+        let mut unlinked = good.clone();
+        unlinked.prev_block_hash = Hash::zero();
+        let mut too_easy = good.clone();
+        too_easy.target = crate::MIN_TARGET + U256::one();
+        let mut zero_target = good.clone();
+        zero_target.target = U256::zero();
+        let mut unmined = good.clone();
+        while unmined.hash().matches_target(unmined.target) {
+            unmined.nonce += 1;
+        }
+        let too_old = child(&genesis(), 0);
+
+        for header in [unlinked, too_easy, zero_target, unmined, too_old] {
+            assert!(chain.add_header(header).is_err());
+        }
+        assert_eq!(chain.height(), 1);
+        chain.add_header(good).unwrap();
+        assert_eq!(chain.height(), 2);
+    }
+
+    #[test]
+    fn another_genesis_is_rejected() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        chain.extend(branch(&genesis(), 2, 60)).unwrap();
+        let tip = chain.tip_hash();
+
+        let testnet = Block::genesis(Network::Testnet).header;
+        assert!(chain.connect(vec![testnet.clone()]).is_err());
+        assert!(chain.connect(branch(&testnet, 3, 60)).is_err());
+
+        // a forged genesis with a target no work can be
+        // computed for must not panic either
+        let mut forged = genesis();
+        forged.target = U256::zero();
+        assert!(chain.connect(vec![forged]).is_err());
+
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.tip_hash(), tip);
+    }
+
+    #[test]
+    fn known_headers_are_skipped() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let headers = branch(&genesis(), 3, 60);
+        chain.extend(headers[..2].to_vec()).unwrap();
+        let mut answer = vec![genesis()];
+        answer.extend(headers.clone());
+        chain.connect(answer).unwrap();
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.tip_hash(), headers[2].hash());
+    }
+
+    #[test]
+    fn connect_switches_to_a_branch() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let ours = branch(&genesis(), 3, 60);
+        chain.extend(ours.clone()).unwrap();
+        let before = chain.clone();
+
+        // forks off after our first header
+        let theirs = branch(&ours[0], 3, 30);
+        chain.connect(theirs.clone()).unwrap();
+        assert_eq!(chain.height(), 5);
+        assert_eq!(chain.tip_hash(), theirs[2].hash());
+        assert_eq!(chain.fork_point(&before), 2);
+        assert!(chain.total_work() > before.total_work());
+        for header in &ours[1..] {
+            assert_eq!(chain.height_of(&header.hash()), None);
+        }
+        assert_eq!(chain.height_of(&ours[0].hash()), Some(1));
+
+        // and back to ours from the genesis up
+        chain.connect(ours.clone()).unwrap();
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.tip_hash(), ours[2].hash());
+        for header in &theirs {
+            assert_eq!(chain.height_of(&header.hash()), None);
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_the_genesis_header() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let headers = branch(&genesis(), 2, 60);
+        chain.extend(headers.clone()).unwrap();
+        chain.truncate(0);
+        assert_eq!(chain.height(), 1);
+        assert_eq!(chain.total_work(), genesis().work());
+        assert_eq!(chain.height_of(&headers[0].hash()), None);
+        chain.extend(headers).unwrap();
+        assert_eq!(chain.height(), 3);
+    }
+
+    #[test]
+    fn locator_heights_thin_out() {
+        assert!(locator_heights(0).is_empty());
+        assert_eq!(locator_heights(1), vec![0]);
+        assert_eq!(
+            locator_heights(20),
+            vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10, 8, 4, 0]
+        );
+    }
+
+    #[test]
+    fn saved_chains_are_checked_on_load() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        chain.extend(branch(&genesis(), 2, 60)).unwrap();
+        let mut bytes = vec![];
+        chain.save(&mut bytes).unwrap();
+        let loaded = HeaderChain::load(bytes.as_slice()).unwrap();
+        assert_eq!(loaded.network(), Network::Regtest);
+        assert_eq!(loaded.tip_hash(), chain.tip_hash());
+        assert_eq!(loaded.total_work(), chain.total_work());
+
+        // headers saved as another network do not load
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&(Network::Mainnet, &chain.headers), &mut bytes).unwrap();
+        assert!(HeaderChain::load(bytes.as_slice()).is_err());
+    }
+}
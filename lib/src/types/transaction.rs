@@ -1,7 +1,8 @@
+use std::fmt;
 use std::io::{Read, Result as IoResult, Error as IoError, ErrorKind as IoErrorKind, Write};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use crate::crypto::{PublicKey, Signature};
+use crate::address::{Address, PubkeyHash};
+use crate::crypto::{VersionedPublicKey, VersionedSignature};
 use crate::sha256::Hash;
 use crate::utils::Saveable;
 
@@ -9,29 +10,138 @@ use crate::utils::Saveable;
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// Height of the block a coinbase transaction belongs to.
+    /// Coinbase transactions have no inputs, so this is what keeps
+    /// their txids unique. Always `None` for other transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase_height: Option<u64>,
 }
 
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Transaction { inputs, outputs }
+        Transaction { inputs, outputs, coinbase_height: None }
     }
 
-    pub fn hash(&self) -> Hash {
+    pub fn coinbase(height: u64, outputs: Vec<TransactionOutput>) -> Self {
+        Transaction { inputs: vec![], outputs, coinbase_height: Some(height) }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.coinbase_height.is_some()
+    }
+
+    /// Transaction id. Commits to the inputs and outputs but not
+    /// to the signatures, so re-encoding a signature cannot change it.
+    pub fn txid(&self) -> Hash {
+        let prev_outputs: Vec<OutPoint> = self
+            .inputs
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        Hash::hash(&(self.coinbase_height, &prev_outputs, &self.outputs))
+    }
+
+    /// Witness transaction id. Commits to the whole transaction,
+    /// signatures included.
+    pub fn wtxid(&self) -> Hash {
         Hash::hash(self)
     }
+
+    /// Compute the txid of a transaction before it has been signed,
+    /// which is the message every input has to sign.
+    pub fn compute_txid(prev_outputs: &[OutPoint], outputs: &[TransactionOutput]) -> Hash {
+        Hash::hash(&(None::<u64>, prev_outputs, outputs))
+    }
+}
+
+/// Reference to an output of an earlier transaction
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    /// Id of the transaction that created the output
+    pub txid: Hash,
+    /// Index of the output in that transaction
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, vout: u32) -> Self {
+        OutPoint { txid, vout }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TransactionInput {
-    pub prev_transaction_output_hash: Hash,
-    pub signature: Signature,
+    pub previous_output: OutPoint,
+    pub signature: VersionedSignature,
+    /// The public key, revealed when spending an output locked
+    /// to its hash. Like the signature, it is not part of the txid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<VersionedPublicKey>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct TransactionOutput {
     pub value: u64,
-    pub unique_id: Uuid,
-    pub pubkey: PublicKey,
+    pub lock: OutputLock,
+}
+
+/// What it takes to spend an output
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OutputLock {
+    /// Pay to a public key known up front
+    PublicKey(VersionedPublicKey),
+    /// Pay to the hash of a public key, which the spending
+    /// input has to reveal
+    PubkeyHash(PubkeyHash),
+}
+
+impl OutputLock {
+    /// The key `input` has to be signed with to spend this
+    /// output, or None if the input does not provide it
+    pub fn spending_key<'a>(
+        &'a self,
+        input: &'a TransactionInput,
+    ) -> Option<&'a VersionedPublicKey> {
+        match (self, &input.public_key) {
+            (OutputLock::PublicKey(key), None) => Some(key),
+            (OutputLock::PubkeyHash(hash), Some(key)) if PubkeyHash::of(key) == *hash => {
+                Some(key)
+            }
+            _ => None,
+        }
+    }
+
+    /// Check that `input` is allowed to spend this output
+    pub fn verify(&self, txid: &Hash, input: &TransactionInput) -> bool {
+        self.spending_key(input)
+            .is_some_and(|key| key.verify(txid, &input.signature))
+    }
+
+    /// Whether `public_key` can spend outputs with this lock
+    pub fn is_owned_by(&self, public_key: &VersionedPublicKey) -> bool {
+        match self {
+            OutputLock::PublicKey(key) => key == public_key,
+            OutputLock::PubkeyHash(hash) => PubkeyHash::of(public_key) == *hash,
+        }
+    }
+}
+
+impl From<VersionedPublicKey> for OutputLock {
+    fn from(public_key: VersionedPublicKey) -> Self {
+        OutputLock::PublicKey(public_key)
+    }
+}
+
+impl From<Address> for OutputLock {
+    fn from(address: Address) -> Self {
+        OutputLock::PubkeyHash(address.hash)
+    }
 }
 
 impl TransactionOutput {
@@ -59,3 +169,82 @@ impl Saveable for Transaction {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::utils::MerkleRoot;
+    use ecdsa::Signature as ECDSASignature;
+
+    fn signed_spend(private_key: &PrivateKey) -> Transaction {
+        let public_key = VersionedPublicKey::from(private_key.public_key());
+        let prev_outputs = [OutPoint::new(Hash::hash_bytes(b"funding"), 0)];
+        let outputs = vec![TransactionOutput {
+            value: 1_000,
+            lock: OutputLock::from(public_key.clone()),
+        }];
+        let txid = Transaction::compute_txid(&prev_outputs, &outputs);
+        let inputs = vec![TransactionInput {
+            previous_output: prev_outputs[0],
+            signature: VersionedSignature::sign(&txid, private_key, &public_key),
+            public_key: None,
+        }];
+        Transaction::new(inputs, outputs)
+    }
+
+    #[test]
+    fn txid_is_the_signed_message() {
+        let tx = signed_spend(&PrivateKey::new_key());
+        let prev_outputs: Vec<OutPoint> =
+            tx.inputs.iter().map(|input| input.previous_output).collect();
+        assert_eq!(tx.txid(), Transaction::compute_txid(&prev_outputs, &tx.outputs));
+        assert_ne!(tx.txid(), tx.wtxid());
+    }
+
+    #[test]
+    fn changing_a_signature_only_changes_the_wtxid() {
+        let tx = signed_spend(&PrivateKey::new_key());
+
+        // the same signature with s negated, which still satisfies
+        // the ECDSA equation
+        let mut malleated = tx.clone();
+        let VersionedSignature::Ecdsa(Signature(signature)) = &tx.inputs[0].signature else {
+            panic!("expected an ECDSA signature");
+        };
+        let (r, s) = signature.split_scalars();
+        let high_s = ECDSASignature::from_scalars(r, -s).unwrap();
+        malleated.inputs[0].signature = VersionedSignature::Ecdsa(Signature(high_s));
+
+        // a signature by another key
+        let mut resigned = tx.clone();
+        let other = signed_spend(&PrivateKey::new_key());
+        resigned.inputs[0].signature = other.inputs[0].signature.clone();
+
+        for changed in [malleated, resigned] {
+            assert_eq!(changed.txid(), tx.txid());
+            assert_ne!(changed.wtxid(), tx.wtxid());
+            let (txs, changed_txs) = (vec![tx.clone()], vec![changed]);
+            assert_eq!(MerkleRoot::calculate(&changed_txs), MerkleRoot::calculate(&txs));
+            assert_ne!(
+                MerkleRoot::calculate_witness(&changed_txs),
+                MerkleRoot::calculate_witness(&txs)
+            );
+        }
+    }
+
+    #[test]
+    fn coinbases_at_different_heights_have_different_txids() {
+        let outputs = vec![TransactionOutput {
+            value: 50,
+            lock: OutputLock::from(VersionedPublicKey::from(PrivateKey::new_key().public_key())),
+        }];
+        let first = Transaction::coinbase(1, outputs.clone());
+        let second = Transaction::coinbase(2, outputs.clone());
+        assert!(first.is_coinbase());
+        assert_ne!(first.txid(), second.txid());
+        assert_eq!(first.txid(), Transaction::coinbase(1, outputs.clone()).txid());
+        // nor does a coinbase share its txid with a regular transaction
+        assert_ne!(first.txid(), Transaction::new(vec![], outputs).txid());
+    }
+}
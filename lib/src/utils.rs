@@ -1,5 +1,5 @@
 use crate::sha256::Hash;
-use crate::types::Transaction;
+use crate::types::{BlockHeader, Transaction};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{Read,Result as IoResult, Write};
@@ -9,24 +9,120 @@ use std::path::Path;
 pub struct MerkleRoot(Hash);
 
 impl MerkleRoot {
+    /// Merkle root over the txids of the transactions
     pub fn calculate(
         transactions: &[Transaction],
     ) -> MerkleRoot {
-        let mut layer: Vec<Hash> = vec![];
-        for transaction in transactions {
-            layer.push(Hash::hash(transaction));
+        Self::from_hashes(transactions.iter().map(|tx| tx.txid()).collect())
+    }
+
+    /// Merkle root over the wtxids of the transactions, which
+    /// commits to their signatures
+    pub fn calculate_witness(
+        transactions: &[Transaction],
+    ) -> MerkleRoot {
+        Self::from_hashes(transactions.iter().map(|tx| tx.wtxid()).collect())
+    }
+
+    /// Inclusion proof for the transaction at `index`, or None
+    /// if there is no such transaction
+    pub fn prove(
+        transactions: &[Transaction],
+        index: usize,
+    ) -> Option<MerkleProof> {
+        if index >= transactions.len() {
+            return None;
         }
+        let mut layer: Vec<Hash> = transactions.iter().map(|tx| tx.txid()).collect();
+        let mut position = index;
+        let mut siblings = vec![];
         while layer.len() > 1 {
-            let mut new_layer: Vec<Hash> = vec![];
-            for pair in layer.chunks(2) {
-                let left = pair[0];
-                let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+            // a promoted node has no sibling at this level
+            if let Some(sibling) = layer.get(position ^ 1) {
+                siblings.push(*sibling);
             }
-            layer = new_layer;
+            layer = Self::next_layer(&layer);
+            position /= 2;
+        }
+        Some(MerkleProof {
+            index: index as u32,
+            leaf_count: transactions.len() as u32,
+            siblings,
+        })
+    }
+
+    fn from_hashes(mut layer: Vec<Hash>) -> MerkleRoot {
+        while layer.len() > 1 {
+            layer = Self::next_layer(&layer);
         }
         MerkleRoot(layer[0])
     }
+
+    // Pairs are hashed together. An odd node out is promoted to
+    // the next layer as is: duplicating it instead would let two
+    // different transaction lists share a root (CVE-2012-2459).
+    fn next_layer(layer: &[Hash]) -> Vec<Hash> {
+        layer
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => Hash::hash(&[*left, *right]),
+                [single] => *single,
+                _ => unreachable!("BUG: chunks of two"),
+            })
+            .collect()
+    }
+}
+
+/// Proof that a transaction is part of a block, checked
+/// against the merkle root in the block header
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Index of the transaction in the block
+    pub index: u32,
+    /// Number of transactions in the block, which fixes the
+    /// shape of the tree
+    pub leaf_count: u32,
+    /// Sibling hashes from the leaf up. Levels where the node
+    /// was promoted have none.
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// The root this proof leads to from `txid`, or None if the
+    /// proof does not fit the tree it claims to be from
+    pub fn root(&self, txid: &Hash) -> Option<MerkleRoot> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = *txid;
+        let mut position = self.index;
+        let mut width = self.leaf_count;
+        while width > 1 {
+            let sibling = position ^ 1;
+            if sibling < width {
+                let sibling = siblings.next()?;
+                hash = if position.is_multiple_of(2) {
+                    Hash::hash(&[hash, *sibling])
+                } else {
+                    Hash::hash(&[*sibling, hash])
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        // every sibling has to be used up
+        match siblings.next() {
+            Some(_) => None,
+            None => Some(MerkleRoot(hash)),
+        }
+    }
+
+    /// Check that the transaction with `txid` is in the block
+    /// with this header
+    pub fn verify(&self, txid: &Hash, header: &BlockHeader) -> bool {
+        self.root(txid) == Some(header.merkle_root)
+    }
 }
 
 pub trait Saveable
@@ -42,4 +138,78 @@ Self: Sized,{
         let file = File::open(path)?;
         Self::load(file)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::types::Block;
+
+    // coinbases at different heights, so every txid differs
+    fn transactions(count: u64) -> Vec<Transaction> {
+        (0..count).map(|height| Transaction::coinbase(height, vec![])).collect()
+    }
+
+    fn header_for(transactions: &[Transaction]) -> BlockHeader {
+        let mut header = Block::genesis(Network::Regtest).header;
+        header.merkle_root = MerkleRoot::calculate(transactions);
+        header
+    }
+
+    #[test]
+    fn proofs_for_every_index_verify() {
+        for count in [1, 2, 3, 5, 7] {
+            let txs = transactions(count);
+            let header = header_for(&txs);
+            for (index, tx) in txs.iter().enumerate() {
+                let proof = MerkleRoot::prove(&txs, index).unwrap();
+                assert!(proof.verify(&tx.txid(), &header), "{index} of {count}");
+                // and only for their own transaction
+                if count > 1 {
+                    let other = &txs[(index + 1) % txs.len()];
+                    assert!(!proof.verify(&other.txid(), &header), "{index} of {count}");
+                }
+            }
+            assert!(MerkleRoot::prove(&txs, txs.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn proofs_that_do_not_fit_the_tree_fail() {
+        let txs = transactions(5);
+        let header = header_for(&txs);
+        let txid = txs[4].txid();
+        let proof = MerkleRoot::prove(&txs, 4).unwrap();
+        assert!(proof.verify(&txid, &header));
+
+        for index in [5, 6, u32::MAX] {
+            let out_of_range = MerkleProof { index, ..proof.clone() };
+            assert!(out_of_range.root(&txid).is_none());
+            assert!(!out_of_range.verify(&txid, &header));
+        }
+
+        let mut extra = proof.clone();
+        extra.siblings.push(txs[0].txid());
+        assert!(!extra.verify(&txid, &header));
+
+        let mut missing = MerkleRoot::prove(&txs, 0).unwrap();
+        missing.siblings.pop();
+        assert!(missing.root(&txs[0].txid()).is_none());
+
+        // the same siblings under another leaf count
+        let wider = MerkleProof { leaf_count: 6, ..proof.clone() };
+        assert!(!wider.verify(&txid, &header));
+    }
+
+    #[test]
+    fn duplicating_the_last_transaction_changes_the_root() {
+        let txs = transactions(3);
+        let mut duplicated = txs.clone();
+        duplicated.push(txs[2].clone());
+        assert_ne!(MerkleRoot::calculate(&txs), MerkleRoot::calculate(&duplicated));
+        assert_ne!(
+            MerkleRoot::calculate_witness(&txs),
+            MerkleRoot::calculate_witness(&duplicated)
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use anyhow::{anyhow, Result};
-use lib::crypto::PublicKey;
-use lib::network::Message;
+use lib::crypto::{PublicKey, SchnorrPublicKey, VersionedPublicKey};
+use lib::network::{
+    connect_handshake_async, Client, Message, Network, Security, Services, Transport, Version,
+};
 use lib::types::Block;
 use lib::utils::Saveable;
 use clap::Parser;
@@ -10,7 +12,6 @@ use std::sync::{
 };
 use std::thread;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
 use tokio::time::{interval, Duration};
 
 #[tokio::main]
@@ -22,7 +23,21 @@ async fn main() -> Result<()> {
                 anyhow!("Error reading public key: {}", e)
             })?;
     
-    let miner = Miner::new(cli.address, public_key).await?;
+    let public_key = if cli.schnorr {
+        VersionedPublicKey::Schnorr(SchnorrPublicKey::from(&public_key))
+    } else {
+        VersionedPublicKey::Ecdsa(public_key)
+    };
+
+    let node_key = cli
+        .node_key
+        .as_deref()
+        .map(PublicKey::from_sec1_hex)
+        .transpose()
+        .map_err(|e| anyhow!("Error reading node key: {}", e))?;
+    let security = Security::new(cli.encrypt, node_key);
+
+    let miner = Miner::new(cli.address, cli.network, &security, public_key).await?;
     miner.run().await
 }
 
@@ -33,11 +48,24 @@ struct Cli {
     address: String,
     #[arg(short, long)]
     public_key_file: String,
+    /// Lock block rewards to the Schnorr form of the key
+    #[arg(long)]
+    schnorr: bool,
+    /// Network the node is on
+    #[arg(long, default_value = "mainnet")]
+    network: Network,
+    /// Encrypt the connection to the node
+    #[arg(long)]
+    encrypt: bool,
+    /// Only mine for the node with this key, as SEC1 hex.
+    /// Implies --encrypt.
+    #[arg(long, value_name = "HEX")]
+    node_key: Option<String>,
 }
 
 struct Miner {
-    public_key: PublicKey,
-    stream: Mutex<TcpStream>,
+    public_key: VersionedPublicKey,
+    client: Client<Transport<TcpStream>>,
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
     mining: Arc<AtomicBool>,
     mined_block_sender: flume::Sender<Block>,
@@ -48,13 +76,25 @@ struct Miner {
 impl Miner {
     async fn new(
         address: String,
-        public_key: PublicKey,
+        network: Network,
+        security: &Security,
+        public_key: VersionedPublicKey,
     ) -> Result<Self> {
         let stream = TcpStream::connect(&address).await?;
+        let mut stream = Transport::connect(stream, network, security).await?;
+        let version = Version::new(
+            network,
+            Services::NONE,
+            0,
+            concat!("/miner:", env!("CARGO_PKG_VERSION"), "/"),
+        );
+        connect_handshake_async(&mut stream, network, &version, Services::TEMPLATES).await?;
+        // the node sends miners nothing unasked
+        let (client, _) = Client::new(stream, network);
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
         Ok(Self{
             public_key,
-            stream: Mutex::new(stream),
+            client,
             current_template: Arc::new(std::sync::Mutex::new(None)),
             mining: Arc::new(AtomicBool::new(false)),
             mined_block_sender,
@@ -119,13 +159,8 @@ impl Miner {
     async fn fetch_template(&self) -> Result<()> {
         println!("Fetching template...");
         let message = Message::FetchTemplate(self.public_key.clone());
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
-        drop(stream_lock);
-        let mut stream_lock = self.stream.lock().await;
-        match Message::receive_async(&mut *stream_lock).await? {
+        match self.client.request(message).await? {
             Message::Template(template) => {
-                drop(stream_lock);
                 println!("Received new template with target: {}", template.header.target);
                 *self.current_template.lock().unwrap() = Some(template);
                 self.mining.store(true, Ordering::Relaxed);
@@ -135,15 +170,11 @@ impl Miner {
         }
     }
     async fn validate_template(&self) -> Result<()> {
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
+        let template = self.current_template.lock().unwrap().clone();
+        if let Some(template) = template {
             let message = Message::ValidateTemplate(template);
-            let mut stream_lock = self.stream.lock().await;
-            message.send_async(&mut *stream_lock).await?;
-            drop(stream_lock);
-            let mut stream_lock = self.stream.lock().await;
-            match Message::receive_async(&mut *stream_lock).await? {
+            match self.client.request(message).await? {
                 Message::TemplateValidity(valid) => {
-                    drop(stream_lock);
                     if !valid {
                         println!("Current template is no longer valid");
                         self.mining.store(false, Ordering::Relaxed);
@@ -161,8 +192,7 @@ impl Miner {
     async fn submit_block(&self, block: Block) -> Result<()> {
         println!("Submitting mined block");
         let message = Message::SubmitTemplate(block);
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
+        self.client.send(message).await?;
         self.mining.store(false, Ordering::Relaxed);
         Ok(())
     }
@@ -0,0 +1,580 @@
+use chrono::{Duration, Utc};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{
+    Error as IoError, ErrorKind as IoErrorKind, Read,
+    Result as IoResult, Write,
+};
+use std::net::{IpAddr, SocketAddr};
+
+use lib::network::{PeerAddress, Services};
+use lib::sha256::Hash;
+use lib::utils::Saveable;
+use tokio::time;
+
+/// Buckets of addresses we only heard about
+const NEW_BUCKETS: u64 = 256;
+/// Buckets of addresses we connected to before
+const TRIED_BUCKETS: u64 = 64;
+/// Addresses per bucket
+const BUCKET_SIZE: usize = 16;
+/// How many new buckets addresses from one source can land in
+const SOURCE_BUCKETS: u64 = 16;
+/// How many tried buckets addresses from one group can land in
+const GROUP_BUCKETS: u64 = 4;
+/// Addresses not seen for longer than this are dropped
+const MAX_AGE_DAYS: i64 = 30;
+/// Addresses that failed this often in a row are dropped
+const MAX_FAILURES: u32 = 10;
+
+/// An address with what we know about connecting to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    address: PeerAddress,
+    /// Group of the peer that told us about it
+    source: String,
+    /// Failed attempts since the last success
+    failures: u32,
+    /// Given on the command line or in the seed list, so kept
+    /// however long it has been down
+    #[serde(default)]
+    seed: bool,
+}
+
+impl Entry {
+    /// Worth forgetting to make room for another address
+    fn is_terrible(&self) -> bool {
+        !self.seed
+            && (self.failures >= MAX_FAILURES
+                || self.address.last_seen
+                    < Utc::now() - Duration::days(MAX_AGE_DAYS))
+    }
+}
+
+/// Addresses of other nodes, kept across restarts to pick
+/// outbound peers from.
+///
+/// Addresses land in buckets picked by a keyed hash. One source
+/// can only fill a few of the buckets for new addresses, and a
+/// full bucket keeps its entries rather than taking newcomers,
+/// so a single peer flooding us with addresses cannot crowd
+/// out the rest. Seeds are the exception, and always stay. Outbound peers are picked bucket first, each
+/// from a different group.
+#[derive(Serialize, Deserialize)]
+pub struct AddressBook {
+    /// Secret input to the bucket hashes, so no one can aim
+    /// addresses at a bucket of their choosing
+    key: [u8; 32],
+    new: Vec<Vec<Entry>>,
+    tried: Vec<Vec<Entry>>,
+    /// Addresses that turned out to be our own, which peers
+    /// will keep telling us about
+    #[serde(default)]
+    ours: HashSet<String>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook {
+            key: rand::thread_rng().gen(),
+            new: vec![vec![]; NEW_BUCKETS as usize],
+            tried: vec![vec![]; TRIED_BUCKETS as usize],
+            ours: HashSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.new.iter().chain(&self.tried).map(Vec::len).sum()
+    }
+
+    fn bucket_hash(&self, parts: &[&str]) -> u64 {
+        let hash = Hash::hash(&(self.key, parts));
+        let bytes = hash.as_bytes();
+        u64::from_be_bytes(bytes[..8].try_into().expect("BUG: 8 bytes"))
+    }
+
+    fn new_bucket(&self, address: &str, source: &str) -> usize {
+        let group = group(address);
+        let spread = self.bucket_hash(&[&group, source]) % SOURCE_BUCKETS;
+        (self.bucket_hash(&[source, &spread.to_string()]) % NEW_BUCKETS)
+            as usize
+    }
+
+    fn tried_bucket(&self, address: &str) -> usize {
+        let spread = self.bucket_hash(&[address]) % GROUP_BUCKETS;
+        (self.bucket_hash(&[&group(address), &spread.to_string()])
+            % TRIED_BUCKETS) as usize
+    }
+
+    fn find_mut(&mut self, address: &str) -> Option<&mut Entry> {
+        self.new
+            .iter_mut()
+            .chain(&mut self.tried)
+            .flatten()
+            .find(|entry| entry.address.address == address)
+    }
+
+    fn take(&mut self, address: &str) -> Option<Entry> {
+        for bucket in self.new.iter_mut().chain(&mut self.tried) {
+            if let Some(index) = bucket
+                .iter()
+                .position(|entry| entry.address.address == address)
+            {
+                return Some(bucket.swap_remove(index));
+            }
+        }
+        None
+    }
+
+    /// Add an address from the command line or the seed list.
+    /// Ones we know already get a fresh start, since we were
+    /// told to use them, and are kept from then on.
+    pub fn add_seed(&mut self, address: &str) {
+        if let Some(entry) = self.find_mut(address) {
+            entry.address.last_seen = Utc::now();
+            entry.failures = 0;
+            entry.seed = true;
+            return;
+        }
+        let entry = Entry {
+            address: PeerAddress {
+                address: address.to_string(),
+                services: Services::NONE,
+                last_seen: Utc::now(),
+            },
+            source: group(address),
+            failures: 0,
+            seed: true,
+        };
+        let index = self.new_bucket(address, &entry.source);
+        insert(&mut self.new[index], entry);
+    }
+
+    /// Add an address `source` told us about. Returns true if
+    /// it was new to us. Only IP addresses are taken: a host
+    /// name could point anywhere, and would be looked up by
+    /// everyone it is passed on to.
+    pub fn add(&mut self, mut address: PeerAddress, source: &str) -> bool {
+        let Ok(socket_address) = address.address.parse::<SocketAddr>() else {
+            return false;
+        };
+        // one spelling per address, so it is not taken twice
+        address.address = socket_address.to_string();
+        if self.ours.contains(&address.address) {
+            return false;
+        }
+        // no one gets to claim a node is up in the future
+        address.last_seen = address.last_seen.min(Utc::now());
+        if let Some(entry) = self.find_mut(&address.address) {
+            if address.last_seen > entry.address.last_seen {
+                entry.address.last_seen = address.last_seen;
+                entry.address.services = address.services;
+            }
+            return false;
+        }
+
+        let entry = Entry {
+            source: group(source),
+            address,
+            failures: 0,
+            seed: false,
+        };
+        if entry.is_terrible() {
+            return false;
+        }
+        let index = self.new_bucket(&entry.address.address, &entry.source);
+        insert(&mut self.new[index], entry)
+    }
+
+    /// Record a successful connection, moving the address to
+    /// the tried table
+    pub fn mark_good(&mut self, address: &str, services: Services) {
+        let mut entry = self.take(address).unwrap_or_else(|| Entry {
+            address: PeerAddress {
+                address: address.to_string(),
+                services,
+                last_seen: Utc::now(),
+            },
+            source: group(address),
+            failures: 0,
+            seed: false,
+        });
+        entry.address.services = services;
+        entry.address.last_seen = Utc::now();
+        entry.failures = 0;
+
+        let index = self.tried_bucket(address);
+        let bucket = &mut self.tried[index];
+        if bucket.len() >= BUCKET_SIZE {
+            // the one seen longest ago goes back to the new table
+            let oldest = bucket
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.address.last_seen)
+                .map(|(index, _)| index)
+                .expect("BUG: bucket is full");
+            let evicted = bucket.swap_remove(oldest);
+            let index =
+                self.new_bucket(&evicted.address.address, &evicted.source);
+            insert(&mut self.new[index], evicted);
+        }
+        self.tried[index].push(entry);
+    }
+
+    /// Drop an address we connected to ourselves on, and do not
+    /// take it again
+    pub fn mark_ours(&mut self, address: &str) {
+        self.take(address);
+        self.ours.insert(address.to_string());
+    }
+
+    /// Record a failed connection attempt
+    pub fn mark_failed(&mut self, address: &str) {
+        if let Some(entry) = self.find_mut(address) {
+            entry.failures += 1;
+        }
+    }
+
+    /// Pick an address to connect to, outside of the groups we
+    /// are connected to already and not `excluded`. Tried and
+    /// new addresses are picked about equally often.
+    pub fn select(
+        &self,
+        groups: &HashSet<String>,
+        excluded: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        let mut rng = rand::thread_rng();
+        let mut tables = [&self.tried, &self.new];
+        if rng.gen_bool(0.5) {
+            tables.reverse();
+        }
+
+        for table in tables {
+            let buckets: Vec<Vec<&Entry>> = table
+                .iter()
+                .map(|bucket| {
+                    bucket
+                        .iter()
+                        .filter(|entry| {
+                            !entry.is_terrible()
+                                && !groups.contains(&group(&entry.address.address))
+                                && !excluded(&entry.address.address)
+                        })
+                        .collect()
+                })
+                .filter(|bucket: &Vec<&Entry>| !bucket.is_empty())
+                .collect();
+            // buckets first, so a bucket full of addresses has
+            // no better odds than one with a single address
+            if let Some(bucket) = buckets.choose(&mut rng) {
+                let entry = bucket.choose(&mut rng).expect("BUG: not empty");
+                return Some(entry.address.address.clone());
+            }
+        }
+        None
+    }
+
+    /// Up to `max` random addresses to hand out to a peer
+    pub fn sample(&self, max: usize) -> Vec<PeerAddress> {
+        let entries: Vec<&Entry> = self
+            .new
+            .iter()
+            .chain(&self.tried)
+            .flatten()
+            .filter(|entry| !entry.is_terrible())
+            .collect();
+        entries
+            .choose_multiple(&mut rand::thread_rng(), max)
+            .map(|entry| entry.address.clone())
+            .collect()
+    }
+}
+
+// add to a bucket, making room by dropping a terrible entry. A
+// full bucket of good entries keeps them, unless a seed comes
+// in: it takes the place of the entry seen longest ago that is
+// not a seed, or goes in on top if they all are.
+fn insert(bucket: &mut Vec<Entry>, entry: Entry) -> bool {
+    if bucket.len() >= BUCKET_SIZE {
+        let index = bucket.iter().position(Entry::is_terrible).or_else(|| {
+            bucket
+                .iter()
+                .enumerate()
+                .filter(|(_, old)| entry.seed && !old.seed)
+                .min_by_key(|(_, old)| old.address.last_seen)
+                .map(|(index, _)| index)
+        });
+        match index {
+            Some(index) => {
+                bucket.swap_remove(index);
+            }
+            None if entry.seed => {}
+            None => return false,
+        }
+    }
+    bucket.push(entry);
+    true
+}
+
+/// The network an address belongs to, as far as spreading
+/// connections goes: the /16 for IPv4 and the /32 for IPv6.
+/// Local addresses are each a group of their own, so a test
+/// cluster on one machine or LAN can still fill all its slots.
+pub fn group(address: &str) -> String {
+    let Ok(socket) = address.parse::<SocketAddr>() else {
+        // a host name, or an address without a port
+        let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+        return host.to_string();
+    };
+    match socket.ip() {
+        IpAddr::V4(ip) if ip.is_loopback() || ip.is_private() || ip.is_link_local() => {
+            address.to_string()
+        }
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            format!("{a}.{b}")
+        }
+        IpAddr::V6(ip) if ip.is_loopback() => address.to_string(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}", segments[0], segments[1])
+        }
+    }
+}
+
+impl Saveable for AddressBook {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize AddressBook",
+            )
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize AddressBook",
+            )
+        })
+    }
+}
+
+pub async fn save(name: String) {
+    let mut interval =
+        time::interval(time::Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        // write a copy first, so a restart never finds a
+        // half-written file
+        let tmp = format!("{}.tmp", name);
+        let result = crate::ADDRESS_BOOK
+            .lock()
+            .expect("BUG: poisoned lock")
+            .save_to_file(&tmp)
+            .and_then(|_| std::fs::rename(&tmp, &name));
+        if let Err(e) = result {
+            println!("failed to save addresses: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_address(address: &str) -> PeerAddress {
+        PeerAddress {
+            address: address.to_string(),
+            services: Services::BLOCKS,
+            last_seen: Utc::now(),
+        }
+    }
+
+    fn contains(book: &mut AddressBook, address: &str) -> bool {
+        book.find_mut(address).is_some()
+    }
+
+    fn everyone(_: &str) -> bool {
+        false
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(group("8.8.8.8:9000"), "8.8");
+        assert_eq!(group("8.8.4.4:9001"), "8.8");
+        assert_eq!(group("127.0.0.1:9000"), "127.0.0.1:9000");
+        assert_eq!(group("192.168.1.2:9000"), "192.168.1.2:9000");
+        assert_eq!(group("[2001:db8::1]:9000"), "2001:db8");
+        assert_eq!(group("[::1]:9000"), "[::1]:9000");
+        assert_eq!(group("node.example.com:9000"), "node.example.com");
+    }
+
+    #[test]
+    fn addresses_are_added_once() {
+        let mut book = AddressBook::new();
+        assert!(book.add(peer_address("8.8.8.8:9000"), "1.1.1.1:9000"));
+        assert!(!book.add(peer_address("8.8.8.8:9000"), "2.2.2.2:9000"));
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn last_seen_is_not_in_the_future() {
+        let mut book = AddressBook::new();
+        let mut address = peer_address("8.8.8.8:9000");
+        address.last_seen = Utc::now() + Duration::days(365);
+        book.add(address, "1.1.1.1:9000");
+        let entry = book.find_mut("8.8.8.8:9000").unwrap();
+        assert!(entry.address.last_seen <= Utc::now());
+    }
+
+    #[test]
+    fn old_addresses_are_not_taken() {
+        let mut book = AddressBook::new();
+        let mut address = peer_address("8.8.8.8:9000");
+        address.last_seen = Utc::now() - Duration::days(MAX_AGE_DAYS + 1);
+        assert!(!book.add(address, "1.1.1.1:9000"));
+        assert_eq!(book.len(), 0);
+    }
+
+    #[test]
+    fn one_source_fills_few_buckets() {
+        let mut book = AddressBook::new();
+        for i in 0..2000u32 {
+            let [_, _, c, d] = i.to_be_bytes();
+            let address = format!("{}.{}.{}.1:9000", 10 + c, d, c);
+            book.add(peer_address(&address), "6.6.6.6:9000");
+        }
+        let used = book.new.iter().filter(|bucket| !bucket.is_empty()).count();
+        assert!(used as u64 <= SOURCE_BUCKETS);
+        assert!(book.len() <= SOURCE_BUCKETS as usize * BUCKET_SIZE);
+        assert!(book.new.iter().all(|bucket| bucket.len() <= BUCKET_SIZE));
+    }
+
+    #[test]
+    fn seeds_survive_full_buckets() {
+        let mut book = AddressBook::new();
+        // seeds land in the buckets of their own group, so flood
+        // those from the same group first
+        for i in 0..2000u32 {
+            let [_, _, c, d] = i.to_be_bytes();
+            let address = format!("{}.{}.{}.1:9000", 10 + c, d, c);
+            book.add(peer_address(&address), "5.5.9.9:9000");
+        }
+        let seeds: Vec<String> = (0..40).map(|i| format!("5.5.5.{i}:9000")).collect();
+        for seed in &seeds {
+            book.add_seed(seed);
+        }
+        for seed in &seeds {
+            assert!(contains(&mut book, seed), "{seed} was dropped");
+        }
+    }
+
+    #[test]
+    fn full_buckets_keep_good_entries() {
+        let mut bucket: Vec<Entry> = (0..BUCKET_SIZE)
+            .map(|i| Entry {
+                address: peer_address(&format!("8.8.8.{i}:9000")),
+                source: "1.1".to_string(),
+                failures: 0,
+                seed: false,
+            })
+            .collect();
+        let newcomer = Entry {
+            address: peer_address("9.9.9.9:9000"),
+            source: "1.1".to_string(),
+            failures: 0,
+            seed: false,
+        };
+        assert!(!insert(&mut bucket, newcomer.clone()));
+
+        bucket[3].failures = MAX_FAILURES;
+        assert!(insert(&mut bucket, newcomer));
+        assert_eq!(bucket.len(), BUCKET_SIZE);
+        assert!(bucket.iter().all(|entry| entry.address.address != "8.8.8.3:9000"));
+    }
+
+    #[test]
+    fn seeds_are_never_terrible() {
+        let mut book = AddressBook::new();
+        book.add_seed("8.8.8.8:9000");
+        for _ in 0..MAX_FAILURES * 2 {
+            book.mark_failed("8.8.8.8:9000");
+        }
+        assert_eq!(book.select(&HashSet::new(), everyone).as_deref(), Some("8.8.8.8:9000"));
+
+        book.add(peer_address("9.9.9.9:9000"), "1.1.1.1:9000");
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed("9.9.9.9:9000");
+        }
+        for _ in 0..20 {
+            assert_eq!(book.select(&HashSet::new(), everyone).as_deref(), Some("8.8.8.8:9000"));
+        }
+    }
+
+    #[test]
+    fn select_skips_groups_and_excluded_addresses() {
+        let mut book = AddressBook::new();
+        book.add(peer_address("8.8.8.8:9000"), "1.1.1.1:9000");
+        book.add(peer_address("9.9.9.9:9000"), "1.1.1.1:9000");
+        let groups = HashSet::from(["8.8".to_string()]);
+        for _ in 0..20 {
+            assert_eq!(book.select(&groups, everyone).as_deref(), Some("9.9.9.9:9000"));
+        }
+        assert_eq!(book.select(&groups, |address| address == "9.9.9.9:9000"), None);
+    }
+
+    #[test]
+    fn good_addresses_move_to_tried() {
+        let mut book = AddressBook::new();
+        book.add(peer_address("8.8.8.8:9000"), "1.1.1.1:9000");
+        book.mark_good("8.8.8.8:9000", Services::BLOCKS);
+        assert!(book.new.iter().all(Vec::is_empty));
+        assert_eq!(book.tried.iter().map(Vec::len).sum::<usize>(), 1);
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn our_own_address_is_not_taken_again() {
+        let mut book = AddressBook::new();
+        book.add(peer_address("8.8.8.8:9000"), "1.1.1.1:9000");
+        book.mark_ours("8.8.8.8:9000");
+        assert_eq!(book.len(), 0);
+        assert!(!book.add(peer_address("8.8.8.8:9000"), "1.1.1.1:9000"));
+        assert_eq!(book.len(), 0);
+    }
+
+    #[test]
+    fn saved_books_load() {
+        let mut book = AddressBook::new();
+        book.add_seed("8.8.8.8:9000");
+        book.add(peer_address("9.9.9.9:9000"), "1.1.1.1:9000");
+        let mut bytes = vec![];
+        book.save(&mut bytes).unwrap();
+        let mut loaded = AddressBook::load(bytes.as_slice()).unwrap();
+        assert_eq!(loaded.key, book.key);
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.find_mut("8.8.8.8:9000").unwrap().seed);
+    }
+
+    #[test]
+    fn only_ip_addresses_are_taken_from_peers() {
+        let mut book = AddressBook::new();
+        for address in ["seed.example.com:9000", "localhost:9000", "8.8.8.8", "nonsense"] {
+            assert!(!book.add(peer_address(address), "1.1.1.1:9000"));
+            assert!(!contains(&mut book, address));
+        }
+        assert!(book.add(peer_address("[2001:4860::8888]:9000"), "1.1.1.1:9000"));
+        assert!(!book.add(peer_address("[2001:4860:0:0::8888]:9000"), "1.1.1.1:9000"));
+
+        // seeds from the command line may be host names
+        book.add_seed("seed.example.com:9000");
+        assert!(contains(&mut book, "seed.example.com:9000"));
+    }
+}
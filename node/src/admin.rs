@@ -0,0 +1,157 @@
+use anyhow::Result;
+use chrono::Duration;
+use std::time::Duration as StdDuration;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::bans::{self, DEFAULT_BAN_HOURS};
+
+const HELP: &str = "\
+commands:
+  status                     height, tip, mempool, peer count and node key
+  peers                      connected nodes with their ping times
+  bans                       list banned addresses
+  ban <ip> [hours] [reason]  ban an address
+  unban <ip>                 lift a ban
+  help                       show this
+";
+
+/// Serve the admin interface on `port`. It only listens on the
+/// loopback address, and speaks a line of text per command,
+/// so `nc 127.0.0.1 <port>` is all it takes to use it.
+pub async fn serve(port: u16) -> Result<()> {
+    let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    let listener = TcpListener::bind(address).await?;
+    println!("admin interface listening on {}", address);
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin(socket).await {
+                println!("admin connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_admin(socket: TcpStream) -> Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let reply = run(&words).await;
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// the reply to one command, ending in a newline
+async fn run(words: &[&str]) -> String {
+    match words {
+        ["status"] => status().await,
+        ["peers"] => peers(),
+        ["bans"] => {
+            let bans = crate::BANS
+                .lock()
+                .expect("BUG: poisoned lock")
+                .list();
+            if bans.is_empty() {
+                return "no bans\n".to_string();
+            }
+            let mut reply = String::new();
+            for (ip, ban) in bans {
+                let _ = writeln!(
+                    reply,
+                    "{} until {} ({})",
+                    ip,
+                    ban.until.format("%Y-%m-%d %H:%M:%S UTC"),
+                    ban.reason
+                );
+            }
+            reply
+        }
+        ["ban", ip, rest @ ..] => {
+            let Ok(ip) = ip.parse::<IpAddr>() else {
+                return format!("invalid address {}\n", ip);
+            };
+            let (hours, reason) = match rest {
+                [] => (DEFAULT_BAN_HOURS, vec![]),
+                [hours, reason @ ..] => match hours.parse::<i64>() {
+                    Ok(hours) if hours > 0 => (hours, reason.to_vec()),
+                    _ => return format!("invalid number of hours {}\n", hours),
+                },
+            };
+            let reason = if reason.is_empty() {
+                "banned by admin".to_string()
+            } else {
+                reason.join(" ")
+            };
+            bans::ban(ip, Duration::hours(hours), &reason);
+            format!("banned {} for {} hours\n", ip, hours)
+        }
+        ["unban", ip] => {
+            let Ok(ip) = ip.parse::<IpAddr>() else {
+                return format!("invalid address {}\n", ip);
+            };
+            if bans::unban(&ip) {
+                format!("unbanned {}\n", ip)
+            } else {
+                format!("{} is not banned\n", ip)
+            }
+        }
+        ["help"] => HELP.to_string(),
+        _ => format!("unknown command\n{}", HELP),
+    }
+}
+
+async fn status() -> String {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let tip = blockchain
+        .blocks()
+        .last()
+        .map(|block| block.hash().to_string())
+        .unwrap_or_else(|| "none".to_string());
+    format!(
+        "height {}\ntip {}\nmempool {} transactions\npeers {}\nkey {}\n",
+        blockchain.block_height(),
+        tip,
+        blockchain.mempool().len(),
+        crate::NODES.len(),
+        crate::util::node_key().public_key().to_sec1_hex()
+    )
+}
+
+fn peers() -> String {
+    let mut peers: Vec<_> =
+        crate::NODES.iter().map(|x| x.value().clone()).collect();
+    if peers.is_empty() {
+        return "no peers\n".to_string();
+    }
+    peers.sort_by_key(|peer| peer.latency().unwrap_or(StdDuration::MAX));
+
+    let mut reply = String::new();
+    for peer in peers {
+        let ping = peer
+            .latency()
+            .map(|latency| format!("{:.1}ms", latency.as_secs_f64() * 1000.0))
+            .unwrap_or_else(|| "-".to_string());
+        let _ = writeln!(
+            reply,
+            "{} {:?} {} started at height {} ping {} score {}{}",
+            peer.address,
+            peer.direction,
+            peer.version.user_agent,
+            peer.version.best_height,
+            ping,
+            peer.score(),
+            if peer.encrypted { " encrypted" } else { "" }
+        );
+    }
+    reply
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{
+    Error as IoError, ErrorKind as IoErrorKind, Read,
+    Result as IoResult, Write,
+};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use lib::error::BtcError;
+use lib::utils::Saveable;
+
+/// Misbehavior score at which a peer gets banned
+pub const BAN_SCORE: u32 = 100;
+/// How long a peer stays banned, unless told otherwise
+pub const DEFAULT_BAN_HOURS: i64 = 24;
+
+/// Where the ban list is saved, once it was loaded
+static FILE: OnceLock<String> = OnceLock::new();
+
+/// Points for sending data that failed validation. Data that
+/// breaks consensus rules gets a peer banned right away. Data
+/// that may be fine, but which we cannot use right now, adds a
+/// few points or none.
+pub fn penalty(error: &BtcError) -> u32 {
+    match error {
+        // we may just be behind, or on another branch
+        BtcError::OrphanBlock => 0,
+        // the outputs may have been spent in the meantime, or
+        // be in a transaction we have not seen yet
+        BtcError::MissingInputs => 10,
+        _ => BAN_SCORE,
+    }
+}
+
+/// Peers on this machine are never banned for misbehaving, as
+/// Bitcoin Core does. They all share one address, so banning
+/// one would lock out every local wallet, miner and node with it.
+pub fn is_exempt(ip: &IpAddr) -> bool {
+    ip.is_loopback()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub until: DateTime<Utc>,
+    pub reason: String,
+}
+
+/// Addresses we refuse to talk to, until their ban expires
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans
+            .get(ip)
+            .is_some_and(|ban| ban.until > Utc::now())
+    }
+
+    /// Bans that have not expired yet, soonest to expire first
+    pub fn list(&self) -> Vec<(IpAddr, Ban)> {
+        let now = Utc::now();
+        let mut bans: Vec<_> = self
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.until > now)
+            .map(|(ip, ban)| (*ip, ban.clone()))
+            .collect();
+        bans.sort_by_key(|(_, ban)| ban.until);
+        bans
+    }
+}
+
+impl Saveable for BanList {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize BanList",
+            )
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize BanList",
+            )
+        })
+    }
+}
+
+/// Load the ban list, and keep saving it to the same file
+/// whenever it changes
+pub fn load(path: String) -> Result<()> {
+    if Path::new(&path).exists() {
+        let list = BanList::load_from_file(&path)?;
+        println!("loaded {} bans", list.list().len());
+        *crate::BANS.lock().expect("BUG: poisoned lock") = list;
+    }
+    let _ = FILE.set(path);
+    Ok(())
+}
+
+/// Ban an address and drop every connection from it. A ban
+/// that is already in place is only ever extended.
+pub fn ban(ip: IpAddr, duration: Duration, reason: &str) {
+    {
+        let mut list = crate::BANS.lock().expect("BUG: poisoned lock");
+        let until = Utc::now() + duration;
+        let ban = list.bans.entry(ip).or_insert_with(|| Ban {
+            until,
+            reason: reason.to_string(),
+        });
+        if ban.until < until {
+            ban.until = until;
+            ban.reason = reason.to_string();
+        }
+        let now = Utc::now();
+        list.bans.retain(|_, ban| ban.until > now);
+        save(&list);
+    }
+    println!("banned {} for {} hours: {}", ip, duration.num_hours(), reason);
+
+    for peer in crate::NODES.iter() {
+        if peer.ip == Some(ip) {
+            peer.disconnect();
+        }
+    }
+}
+
+/// Lift a ban. Returns false if the address was not banned.
+pub fn unban(ip: &IpAddr) -> bool {
+    let mut list = crate::BANS.lock().expect("BUG: poisoned lock");
+    let removed = list.bans.remove(ip).is_some();
+    if removed {
+        save(&list);
+    }
+    removed
+}
+
+pub fn is_banned(ip: &IpAddr) -> bool {
+    crate::BANS
+        .lock()
+        .expect("BUG: poisoned lock")
+        .is_banned(ip)
+}
+
+fn save(list: &BanList) {
+    let Some(name) = FILE.get() else {
+        return;
+    };
+    // write a copy first, so a restart never finds a
+    // half-written file
+    let tmp = format!("{}.tmp", name);
+    let result = list
+        .save_to_file(&tmp)
+        .and_then(|_| std::fs::rename(&tmp, name));
+    if let Err(e) = result {
+        println!("failed to save bans: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ban(hours: i64) -> Ban {
+        Ban {
+            until: Utc::now() + Duration::hours(hours),
+            reason: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn only_loopback_peers_are_exempt() {
+        assert!(is_exempt(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(is_exempt(&IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))));
+        assert!(is_exempt(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!(!is_exempt(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))));
+        assert!(!is_exempt(&IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let banned = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        let expired = IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9));
+        let list = BanList {
+            bans: HashMap::from([(banned, ban(1)), (expired, ban(-1))]),
+        };
+        assert!(list.is_banned(&banned));
+        assert!(!list.is_banned(&expired));
+        assert!(!list.is_banned(&IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))));
+        let listed: Vec<IpAddr> = list.list().into_iter().map(|(ip, _)| ip).collect();
+        assert_eq!(listed, vec![banned]);
+    }
+
+    #[test]
+    fn list_is_soonest_first() {
+        let later = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        let sooner = IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9));
+        let list = BanList {
+            bans: HashMap::from([(later, ban(48)), (sooner, ban(1))]),
+        };
+        let listed: Vec<IpAddr> = list.list().into_iter().map(|(ip, _)| ip).collect();
+        assert_eq!(listed, vec![sooner, later]);
+    }
+
+    #[test]
+    fn penalties() {
+        assert_eq!(penalty(&BtcError::OrphanBlock), 0);
+        assert!(penalty(&BtcError::MissingInputs) < BAN_SCORE);
+        assert_eq!(penalty(&BtcError::InvalidSignature), BAN_SCORE);
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::task::JoinSet;
+use tokio::time::{self, timeout, Duration};
+
+use lib::network::{
+    Envelope, Features, HandshakeError, Message, Network, PeerAddress,
+    Version,
+};
+
+use crate::address_book::group;
+use crate::bans;
+use crate::peer::{self, Direction};
+use crate::util::Stream;
+
+/// How long connecting and the handshake may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait after the first failure. It doubles with every failure
+/// in a row, up to `MAX_RETRY_BACKOFF`.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How often the manager checks on its peers
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often peers are pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Request id of the GetAddr sent on a new connection
+const GETADDR_REQUEST: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// What we know about an address we open connections to
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub state: ConnectionState,
+    /// Failed attempts in a row, reset once a connection is made
+    pub failures: u32,
+    /// Not tried again before this
+    pub retry_at: Instant,
+}
+
+impl Connection {
+    fn new() -> Self {
+        Connection {
+            state: ConnectionState::Disconnected,
+            failures: 0,
+            retry_at: Instant::now(),
+        }
+    }
+
+    fn failed(&mut self) {
+        self.state = ConnectionState::Disconnected;
+        self.failures += 1;
+        self.retry_at = Instant::now() + backoff(self.failures);
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    RETRY_BACKOFF
+        .saturating_mul(1 << doublings)
+        .min(MAX_RETRY_BACKOFF)
+}
+
+/// Keep `target` outbound connections open: notice peers that
+/// went away or stopped answering pings, and replace them with
+/// the addresses we know about, retrying failed ones with a
+/// growing backoff
+pub async fn manage(target: usize, network: Network) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        check_peers();
+        for peer in crate::NODES.iter() {
+            peer.keep_alive(PING_INTERVAL);
+        }
+        // peers that got ahead while we were apart have blocks
+        // for us
+        let ahead = fill(target, network).await;
+        if !ahead.is_empty() {
+            tokio::spawn(async move {
+                if let Err(e) =
+                    crate::util::sync_blockchain(ahead, network).await
+                {
+                    println!("sync failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+// peers leave the pool when their connection dies or they are
+// dropped for being too slow
+fn check_peers() {
+    for mut connection in crate::ADDRESSES.iter_mut() {
+        if connection.state == ConnectionState::Connected
+            && !crate::NODES.contains_key(connection.key())
+        {
+            println!("lost connection to {}", connection.key());
+            connection.failed();
+        }
+    }
+}
+
+/// Open connections until there are `target` of them, or no
+/// address is due for another attempt. Returns the new peers
+/// that have more blocks than we do.
+pub async fn fill(target: usize, network: Network) -> Vec<String> {
+    let outbound: Vec<String> = crate::NODES
+        .iter()
+        .filter(|x| x.direction == Direction::Outbound)
+        .map(|x| x.key().clone())
+        .collect();
+    let missing = target.saturating_sub(outbound.len());
+    if missing == 0 {
+        return vec![];
+    }
+
+    // one peer per group, so no single network can take all
+    // of our slots
+    let mut groups: HashSet<String> =
+        outbound.iter().map(|address| group(address)).collect();
+    let now = Instant::now();
+    let busy = |address: &str| {
+        crate::NODES.contains_key(address)
+            || address
+                .parse::<SocketAddr>()
+                .is_ok_and(|address| bans::is_banned(&address.ip()))
+            || crate::ADDRESSES.get(address).is_some_and(|connection| {
+                connection.state != ConnectionState::Disconnected
+                    || connection.retry_at > now
+            })
+    };
+    let mut due = vec![];
+    {
+        let book = crate::ADDRESS_BOOK.lock().expect("BUG: poisoned lock");
+        while due.len() < missing {
+            let Some(address) = book.select(&groups, busy) else {
+                break;
+            };
+            groups.insert(group(&address));
+            due.push(address);
+        }
+    }
+    for address in &due {
+        crate::ADDRESSES
+            .entry(address.clone())
+            .or_insert_with(Connection::new)
+            .state = ConnectionState::Connecting;
+    }
+
+    let mut attempts = JoinSet::new();
+    for address in due {
+        attempts.spawn(connect_peer(address, network));
+    }
+    let height = crate::BLOCKCHAIN.read().await.block_height();
+    let mut ahead = vec![];
+    while let Some(attempt) = attempts.join_next().await {
+        if let Ok(Some((address, best_height))) = attempt {
+            if best_height > height {
+                ahead.push(address);
+            }
+        }
+    }
+    ahead
+}
+
+// returns the address and the height the peer reported
+async fn connect_peer(
+    address: String,
+    network: Network,
+) -> Option<(String, u64)> {
+    println!("connecting to {}", address);
+    let result = match timeout(CONNECT_TIMEOUT, open(&address, network)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("timed out")),
+    };
+    let (stream, version, features, addresses) = match result {
+        Ok(opened) => opened,
+        Err(e) => {
+            println!("failed to connect to {}: {e}", address);
+            if let Some(mut connection) = crate::ADDRESSES.get_mut(&address) {
+                connection.failed();
+            }
+            let mut book =
+                crate::ADDRESS_BOOK.lock().expect("BUG: poisoned lock");
+            if let Some(HandshakeError::ConnectedToSelf) = e.downcast_ref() {
+                book.mark_ours(&address);
+            } else {
+                book.mark_failed(&address);
+            }
+            return None;
+        }
+    };
+
+    let best_height = version.best_height;
+    {
+        let mut book =
+            crate::ADDRESS_BOOK.lock().expect("BUG: poisoned lock");
+        book.mark_good(&address, version.services);
+        for peer_address in addresses {
+            book.add(peer_address, &address);
+        }
+    }
+    peer::register(address.clone(), network, version, features, stream);
+    if let Some(mut connection) = crate::ADDRESSES.get_mut(&address) {
+        connection.state = ConnectionState::Connected;
+        connection.failures = 0;
+    }
+
+    Some((address, best_height))
+}
+
+// connect, shake hands, and ask which other nodes the peer knows.
+// The peer may send other things first, like announcements or
+// addresses it passes on, which are skipped until the answer
+// to our request comes.
+async fn open(
+    address: &str,
+    network: Network,
+) -> Result<(Stream, Version, Features, Vec<PeerAddress>)> {
+    let (mut stream, version, features) =
+        crate::util::connect_relay(address, network).await?;
+
+    Envelope::new(GETADDR_REQUEST, Message::GetAddr)
+        .send_async(network, &mut stream)
+        .await?;
+    loop {
+        let envelope = Envelope::receive_async(network, &mut stream).await?;
+        if let Message::Addr(addresses) = envelope.message {
+            if envelope.id == GETADDR_REQUEST {
+                return Ok((stream, version, features, addresses));
+            }
+        }
+    }
+}
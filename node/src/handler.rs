@@ -1,65 +1,241 @@
+use anyhow::Result;
 use lib::sha256::Hash;
-use chrono::Utc;
-use uuid::Uuid;
+use chrono::{Duration, Utc};
 
+use tokio::io::ReadHalf;
 use tokio::net::TcpStream;
+use tokio::time::timeout;
 
-use lib::network::Message;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use lib::network::{
+    handshake_async, Envelope, InvItem, Network, PeerAddress,
+    UTXOProof, MAX_ADDR, MAX_HEADERS,
+};
 use lib::types::{
     Block, BlockHeader, Transaction, TransactionOutput,
 };
 use lib::utils::MerkleRoot;
 
-pub async fn handle_connection(mut socket: TcpStream) {
+use crate::bans;
+use crate::peer::{self, Direction, Peer};
+use crate::util::Stream;
+
+/// Addr messages this short carry news worth passing on, and
+/// longer ones answer a GetAddr
+const MAX_ADDR_RELAY: usize = 10;
+/// Addresses seen longer ago than this are not passed on
+const ADDR_RELAY_MAX_AGE_MINUTES: i64 = 10;
+/// Points for breaking the protocol in ways that do not harm us
+const PROTOCOL_PENALTY: u32 = 10;
+/// Points for flooding us with addresses
+const ADDR_FLOOD_PENALTY: u32 = 20;
+/// How long each step of the handshake with a peer that
+/// connects to us may take, so silent ones do not hold on to
+/// a connection
+const HANDSHAKE_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(10);
+
+pub async fn handle_connection(
+    socket: TcpStream,
+    network: Network,
+) {
+    let address = match socket.peer_addr() {
+        Ok(address) => address,
+        Err(e) => {
+            println!("failed to get peer address: {e}");
+            return;
+        }
+    };
+    if bans::is_banned(&address.ip()) {
+        println!("{} is banned, closing that connection", address);
+        return;
+    }
+    let address = address.to_string();
+    let accepted =
+        timeout(HANDSHAKE_TIMEOUT, crate::util::accept(socket, network)).await;
+    let mut socket = match accepted {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(e)) => {
+            println!("encrypted handshake failed: {e}, closing that connection");
+            return;
+        }
+        Err(_) => {
+            println!("{address} timed out in the encrypted handshake, closing that connection");
+            return;
+        }
+    };
+    let ours = crate::util::our_version(network).await;
+    let handshake = timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake_async(&mut socket, network, &ours),
+    )
+    .await;
+    let version = match handshake {
+        Ok(Ok(version)) => version,
+        Ok(Err(e)) => {
+            println!("handshake failed: {e}, closing that connection");
+            return;
+        }
+        Err(_) => {
+            println!("{address} timed out in the handshake, closing that connection");
+            return;
+        }
+    };
+    println!(
+        "{} connected, offering {}",
+        version.user_agent, version.services
+    );
+
+    let features = ours.negotiate(&version);
+    let (peer, reader) = Peer::start(
+        address,
+        network,
+        version,
+        features,
+        Direction::Inbound,
+        socket,
+    );
+    // other nodes get what we relay, just like the ones we
+    // connected to
+    if peer.relays() {
+        crate::NODES.insert(peer.address.clone(), peer.clone());
+        announce_listen_address(&peer);
+    }
+    handle_messages(reader, peer).await;
+}
+
+// A node that connects to us tells us the port it listens on,
+// so we can pass its address on to others
+fn announce_listen_address(peer: &Peer) {
+    if peer.version.listen_port == 0 {
+        return;
+    }
+    let Ok(mut address) = peer.address.parse::<SocketAddr>() else {
+        return;
+    };
+    address.set_port(peer.version.listen_port);
+    let address = PeerAddress {
+        address: address.to_string(),
+        services: peer.version.services,
+        last_seen: Utc::now(),
+    };
+    let new = crate::ADDRESS_BOOK
+        .lock()
+        .expect("BUG: poisoned lock")
+        .add(address.clone(), &peer.address);
+    if new {
+        peer::relay_addresses(vec![address], &peer.address);
+    }
+}
+
+// Serve a peer until it disconnects. Connections we opened run
+// this too, so the other side can ask us for data in turn.
+pub async fn handle_messages(reader: ReadHalf<Stream>, peer: Arc<Peer>) {
+    if let Err(e) = serve(reader, &peer).await {
+        println!("{e}");
+    }
+    peer.disconnect();
+    // only remove the entry if it is still this connection
+    crate::NODES.remove_if(&peer.address, |_, pooled| {
+        Arc::ptr_eq(pooled, &peer)
+    });
+}
+
+async fn serve(mut reader: ReadHalf<Stream>, peer: &Peer) -> Result<()> {
     loop {
-        // read a message from the socket
-        let message = match Message::receive_async(&mut socket)
-            .await
-        {
-            Ok(message) => message,
+        // read a message from the socket, unless we hang up first
+        let envelope = tokio::select! {
+            _ = peer.closed() => return Ok(()),
+            envelope = Envelope::receive_async(peer.network, &mut reader) => envelope,
+        };
+        // answers go out under the id of the request, so a
+        // client with several requests in flight can match them
+        let Envelope { id, message } = match envelope {
+            Ok(envelope) => envelope,
             Err(e) => {
                 println!("invalid message from peer: {e}, closing that connection");
-                return;
+                return Ok(());
             }
         };
 
         use lib::network::Message::*;
         match message {
-            UTXOs(_) | Template(_) | Difference(_)
-            | TemplateValidity(_) | NodeList(_) => {
+            Version(_) | Verack => {
+                println!("handshake is already done, closing that connection");
+                peer.misbehaving(PROTOCOL_PENALTY, "repeated handshake");
+                return Ok(());
+            }
+            UTXOs(_) | UTXOProofs(_) | Template(_)
+            | TemplateValidity(_) | Headers(_) => {
                 println!(
                     "I am neither a miner nor a \
                           wallet! Goodbye"
                 );
-                return;
+                peer.misbehaving(PROTOCOL_PENALTY, "unrequested response");
+                return Ok(());
             }
             FetchBlock(height) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
                 let Some(block) = blockchain
                     .blocks()
-                    .nth(height as usize)
+                    .nth(height)
                     .cloned()
                 else {
-                    return;
+                    return Ok(());
                 };
 
                 let message = NewBlock(block);
-                message.send_async(&mut socket).await.unwrap();
+                peer.reply(id, message)?;
             }
-            DiscoverNodes => {
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-                let message = NodeList(nodes);
-                message.send_async(&mut socket).await.unwrap();
-            }
-            AskDifference(height) => {
+            GetHeaders { locator, stop_hash } => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
-                let count = blockchain.block_height() as i32
-                    - height as i32;
-                let message = Difference(count);
-                message.send_async(&mut socket).await.unwrap();
+                let headers = blockchain.headers_after(
+                    &locator,
+                    &stop_hash,
+                    MAX_HEADERS,
+                );
+
+                let message = Headers(headers);
+                peer.reply(id, message)?;
+            }
+            GetAddr => {
+                let addresses = crate::ADDRESS_BOOK
+                    .lock()
+                    .expect("BUG: poisoned lock")
+                    .sample(MAX_ADDR);
+                let message = Addr(addresses);
+                peer.reply(id, message)?;
+            }
+            Addr(addresses) => {
+                if addresses.len() > MAX_ADDR {
+                    println!(
+                        "{} sent {} addresses, ignoring them",
+                        peer.address,
+                        addresses.len()
+                    );
+                    peer.misbehaving(ADDR_FLOOD_PENALTY, "too many addresses");
+                    continue;
+                }
+                let relay = addresses.len() <= MAX_ADDR_RELAY;
+                let fresh = Utc::now()
+                    - Duration::minutes(ADDR_RELAY_MAX_AGE_MINUTES);
+                let mut new = vec![];
+                {
+                    let mut book = crate::ADDRESS_BOOK
+                        .lock()
+                        .expect("BUG: poisoned lock");
+                    for address in addresses {
+                        if book.add(address.clone(), &peer.address)
+                            && relay
+                            && address.last_seen > fresh
+                        {
+                            new.push(address);
+                        }
+                    }
+                }
+                peer::relay_addresses(new, &peer.address);
             }
             FetchUTXOs(key) => {
                 println!("received request to fetch UTXOs");
@@ -69,36 +245,166 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     .utxos()
                     .iter()
                     .filter(|(_, (_, txout))| {
-                        txout.pubkey == key
+                        txout.lock.is_owned_by(&key)
                     })
-                    .map(|(_, (marked, txout))| {
-                        (txout.clone(), *marked)
+                    .map(|(outpoint, (marked, txout))| {
+                        (*outpoint, txout.clone(), *marked)
                     })
                     .collect::<Vec<_>>();
 
                 let message = UTXOs(utxos);
-                message.send_async(&mut socket).await.unwrap();
+                peer.reply(id, message)?;
+            }
+            FetchUTXOProofs(key) => {
+                println!("received request to fetch UTXO proofs");
+                let blockchain = crate::BLOCKCHAIN.read().await;
+
+                // each UTXO knows the block that created it, so
+                // only that block is searched for the transaction
+                let mut proofs = vec![];
+                for (outpoint, (marked, txout)) in blockchain.utxos() {
+                    if !txout.lock.is_owned_by(&key) {
+                        continue;
+                    }
+                    let height = blockchain
+                        .utxo_height(outpoint)
+                        .expect("BUG: every UTXO has a height");
+                    let block = blockchain
+                        .blocks()
+                        .nth(height as usize)
+                        .expect("BUG: UTXO heights are in the chain");
+                    let index = block
+                        .transactions
+                        .iter()
+                        .position(|tx| tx.txid() == outpoint.txid)
+                        .expect("BUG: a UTXO's block has its transaction");
+                    let proof = MerkleRoot::prove(&block.transactions, index)
+                        .expect("BUG: index is in range");
+                    proofs.push(UTXOProof {
+                        transaction: block.transactions[index].clone(),
+                        vout: outpoint.vout,
+                        height,
+                        proof,
+                        marked: *marked,
+                    });
+                }
+
+                let message = UTXOProofs(proofs);
+                peer.reply(id, message)?;
             }
 
             NewBlock(block) => {
-                let mut blockchain =
-                    crate::BLOCKCHAIN.write().await;
-                println!("received new block");
+                let hash = block.hash();
+                peer.mark_known(InvItem::Block(hash));
+                {
+                    let mut blockchain =
+                        crate::BLOCKCHAIN.write().await;
+                    if blockchain.block_by_hash(&hash).is_some() {
+                        continue;
+                    }
+                    println!("received new block");
 
-                if blockchain.add_block(block).is_err() {
-                    println!("block rejected");
+                    if let Err(e) = blockchain.add_block(block) {
+                        println!("block rejected: {e}");
+                        peer.misbehaving(bans::penalty(&e), &e.to_string());
+                        continue;
+                    }
                 }
+
+                peer::announce(&[InvItem::Block(hash)]);
             }
             NewTransaction(tx) => {
-                let mut blockchain =
-                    crate::BLOCKCHAIN.write().await;
+                let txid = tx.txid();
+                peer.mark_known(InvItem::Transaction(txid));
+                {
+                    let mut blockchain =
+                        crate::BLOCKCHAIN.write().await;
+                    if blockchain.mempool_transaction(&txid).is_some() {
+                        continue;
+                    }
 
-                println!("received transaction from friend");
+                    println!("received transaction from friend");
 
-                if blockchain.add_to_mempool(tx).is_err() {
-                    println!("transaction rejected, closing connection");
-                    return;
+                    if let Err(e) = blockchain.add_to_mempool(tx) {
+                        println!("transaction rejected: {e}");
+                        peer.misbehaving(bans::penalty(&e), &e.to_string());
+                        continue;
+                    }
                 }
+
+                peer::announce(&[InvItem::Transaction(txid)]);
+            }
+            Inv(items) => {
+                // ask for what we do not have yet
+                let wanted = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+                    items
+                        .into_iter()
+                        .filter(|item| {
+                            peer.mark_known(*item);
+                            match item {
+                                InvItem::Block(hash) => {
+                                    blockchain.block_by_hash(hash).is_none()
+                                }
+                                InvItem::Transaction(txid) => blockchain
+                                    .mempool_transaction(txid)
+                                    .is_none(),
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                };
+                if !wanted.is_empty() {
+                    let message = GetData(wanted);
+                    peer.send(message)?;
+                }
+            }
+            GetData(items) => {
+                let mut found = vec![];
+                let mut not_found = vec![];
+                {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+                    for item in items {
+                        let message = match item {
+                            InvItem::Block(hash) => blockchain
+                                .block_by_hash(&hash)
+                                .cloned()
+                                .map(NewBlock),
+                            InvItem::Transaction(txid) => blockchain
+                                .mempool_transaction(&txid)
+                                .cloned()
+                                .map(NewTransaction),
+                        };
+                        match message {
+                            Some(message) => {
+                                peer.mark_known(item);
+                                found.push(message);
+                            }
+                            None => not_found.push(item),
+                        }
+                    }
+                }
+
+                for message in found {
+                    peer.reply(id, message)?;
+                }
+                if !not_found.is_empty() {
+                    let message = NotFound(not_found);
+                    peer.reply(id, message)?;
+                }
+            }
+            Ping(nonce) => {
+                let message = Pong(nonce);
+                peer.reply(id, message)?;
+            }
+            Pong(nonce) => {
+                peer.pong(nonce);
+            }
+            NotFound(items) => {
+                println!(
+                    "{} no longer has {} announced items",
+                    peer.address,
+                    items.len()
+                );
             }
             ValidateTemplate(block_template) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
@@ -112,87 +418,42 @@ pub async fn handle_connection(mut socket: TcpStream) {
                         .unwrap_or(Hash::zero());
 
                 let message = TemplateValidity(status);
-                message.send_async(&mut socket).await.unwrap();
+                peer.reply(id, message)?;
             }
             SubmitTemplate(block) => {
                 println!("received allegedly mined template");
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
-                if let Err(e) =
-                    blockchain.add_block(block.clone())
-                {
+                let hash = block.hash();
+                if let Err(e) = blockchain.add_block(block) {
                     println!(
                         "block rejected: {e}, closing connection"
                     );
-                    return;
+                    peer.misbehaving(bans::penalty(&e), &e.to_string());
+                    return Ok(());
                 }
 
-                blockchain.rebuild_utxos();
-
                 println!("block looks good, broadcasting");
+                drop(blockchain);
 
-                // send block to all friend nodes
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-
-                for node in nodes {
-                    if let Some(mut stream) =
-                        crate::NODES.get_mut(&node)
-                    {
-                        let message =
-                            Message::NewBlock(block.clone());
-                        if message
-                            .send_async(&mut *stream)
-                            .await
-                            .is_err()
-                        {
-                            println!(
-                                "failed to send block to {}",
-                                node
-                            );
-                        }
-                    }
-                }
+                peer::announce(&[InvItem::Block(hash)]);
             }
             SubmitTransaction(tx) => {
                 println!("submmit tx");
                 let mut blockchain =
                     crate::BLOCKCHAIN.write().await;
-                if let Err(e) =
-                    blockchain.add_to_mempool(tx.clone())
-                {
+                let txid = tx.txid();
+                if let Err(e) = blockchain.add_to_mempool(tx) {
                     println!("transaction rejected, closing connection: {e}");
-                    return;
+                    peer.misbehaving(bans::penalty(&e), &e.to_string());
+                    return Ok(());
                 }
 
                 println!("added transaction to mempool");
+                drop(blockchain);
 
-                // send transaction to all friend nodes
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-
-                for node in nodes {
-                    println!("sending to friend: {node}");
-                    if let Some(mut stream) =
-                        crate::NODES.get_mut(&node)
-                    {
-                        let message =
-                            Message::NewTransaction(tx.clone());
-                        if message
-                            .send_async(&mut *stream)
-                            .await
-                            .is_err()
-                        {
-                            println!("failed to send transaction to {}", node);
-                        }
-                    }
-                }
-
-                println!("transaction sent to friends");
+                peer::announce(&[InvItem::Transaction(txid)]);
+                println!("transaction announced to friends");
             }
             FetchTemplate(pubkey) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
@@ -211,18 +472,19 @@ pub async fn handle_connection(mut socket: TcpStream) {
                 // insert coinbase tx with pubkey
                 transactions.insert(
                     0,
-                    Transaction {
-                        inputs: vec![],
-                        outputs: vec![TransactionOutput {
-                            pubkey,
-                            unique_id: Uuid::new_v4(),
+                    Transaction::coinbase(
+                        blockchain.block_height(),
+                        vec![TransactionOutput {
+                            lock: pubkey.into(),
                             value: 0,
                         }],
-                    },
+                    ),
                 );
 
                 let merkle_root =
                     MerkleRoot::calculate(&transactions);
+                let witness_root =
+                    MerkleRoot::calculate_witness(&transactions);
 
                 let mut block = Block::new(
                     BlockHeader {
@@ -235,6 +497,7 @@ pub async fn handle_connection(mut socket: TcpStream) {
                         nonce: 0,
                         target: blockchain.target(),
                         merkle_root,
+                        witness_root,
                     },
                     transactions,
                 );
@@ -245,7 +508,7 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     Ok(fees) => fees,
                     Err(e) => {
                         eprintln!("{e}");
-                        return;
+                        return Ok(());
                     }
                 };
 
@@ -255,12 +518,14 @@ pub async fn handle_connection(mut socket: TcpStream) {
                 block.transactions[0].outputs[0].value =
                     reward + miner_fees;
 
-                // recalculate merkle root
+                // recalculate merkle roots
                 block.header.merkle_root =
                     MerkleRoot::calculate(&block.transactions);
+                block.header.witness_root =
+                    MerkleRoot::calculate_witness(&block.transactions);
 
                 let message = Template(block);
-                message.send_async(&mut socket).await.unwrap();
+                peer.reply(id, message)?;
             }
         }
     }
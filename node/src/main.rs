@@ -3,23 +3,61 @@ use dashmap::DashMap;
 use static_init::dynamic;
 
 use anyhow::Result;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 
+use lib::network::Network;
 use lib::types::Blockchain;
 
 use std::path::Path;
-
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use address_book::AddressBook;
+use bans::BanList;
+use lib::crypto::PrivateKey;
+use lib::utils::Saveable;
+
+mod address_book;
+mod admin;
+mod bans;
+mod connections;
 mod handler;
+mod peer;
 mod util;
 
+// replaced in main with the chain of the network we join
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> =
-    RwLock::new(Blockchain::new());
+    RwLock::new(Blockchain::new(Network::Mainnet));
 
 // Node pool
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static NODES: DashMap<String, Arc<peer::Peer>> =
+    DashMap::new();
+
+// Addresses of other nodes, to pick outbound peers from
+#[dynamic]
+pub static ADDRESS_BOOK: Mutex<AddressBook> =
+    Mutex::new(AddressBook::new());
+
+// Addresses we do not talk to
+#[dynamic]
+pub static BANS: Mutex<BanList> = Mutex::new(BanList::default());
+
+// Port we accept connections on, told to our peers
+pub static PORT: AtomicU16 = AtomicU16::new(0);
+
+// Key we prove to hold on encrypted connections
+pub static NODE_KEY: OnceLock<PrivateKey> = OnceLock::new();
+
+// Whether connections we open to other nodes are encrypted
+pub static ENCRYPT: AtomicBool = AtomicBool::new(false);
+
+// Addresses we open connections to, with how that went
+#[dynamic]
+pub static ADDRESSES: DashMap<String, connections::Connection> =
+    DashMap::new();
 
 #[derive(FromArgs)]
 /// A toy blockchain node
@@ -35,6 +73,50 @@ struct Args {
     /// blockchain file location
     blockchain_file: String,
 
+    #[argh(
+        option,
+        default = "String::from(\"./peers.cbor\")"
+    )]
+    /// address book location
+    peers_file: String,
+
+    #[argh(option)]
+    /// file listing nodes to connect to, one address per line
+    seeds_file: Option<String>,
+
+    #[argh(
+        option,
+        default = "String::from(\"./banlist.cbor\")"
+    )]
+    /// ban list location
+    bans_file: String,
+
+    #[argh(
+        option,
+        default = "String::from(\"./node_key.cbor\")"
+    )]
+    /// location of the key the node proves its identity with on
+    /// encrypted connections. Created if missing.
+    node_key_file: String,
+
+    #[argh(switch)]
+    /// encrypt the connections we open to other nodes. Others
+    /// can always connect to us encrypted.
+    encrypt: bool,
+
+    #[argh(option)]
+    /// port for the admin interface, which only listens on
+    /// 127.0.0.1. Off unless given.
+    admin_port: Option<u16>,
+
+    #[argh(option, default = "8")]
+    /// number of connections to other nodes to keep open
+    outbound: usize,
+
+    #[argh(option, default = "Network::Mainnet")]
+    /// network to join: mainnet, testnet or regtest
+    network: Network,
+
     #[argh(positional)]
     /// addresses of initial nodes
     nodes: Vec<String>,
@@ -49,46 +131,68 @@ async fn main() -> Result<()> {
     let port = args.port;
     let blockchain_file = args.blockchain_file;
     let nodes = args.nodes;
+    let network = args.network;
+    let outbound = args.outbound;
+    let peers_file = args.peers_file;
+    PORT.store(port, Ordering::Relaxed);
+    ENCRYPT.store(args.encrypt, Ordering::Relaxed);
+    let _ = NODE_KEY.set(util::load_node_key(&args.node_key_file)?);
 
-    util::populate_connections(&nodes).await?;
-    println!("total amount of known nodes: {}", NODES.len());
     // Check if the blockchain_file exists
     if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file).await?;
+        util::load_blockchain(&blockchain_file, network).await?;
     } else {
         println!("blockchain file does not exist!");
+        *BLOCKCHAIN.write().await = Blockchain::new(network);
 
         if nodes.is_empty() {
             println!("no initial nodes provided, starting as a seed node");
-        } else {
-            let (longest_name, longest_count) =
-                util::find_longest_chain_node().await?;
-
-            // request the blockchain from the node with the longest blockchain
-            util::download_blockchain(
-                &longest_name,
-                longest_count,
-            )
-                .await?;
-
-            println!(
-                "blockchain downloaded from {}",
-                longest_name
-            );
-
-            // recalculate utxos
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();
-            }
+        }
+    }
 
-            // try to adjust difficulty
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.try_adjust_target();
+    // start a task to periodically save the blockchain,
+    // so a sync that gets interrupted can resume from there
+    // normally, you would want to keep and join the handle
+    tokio::spawn(util::save(blockchain_file.clone()));
+
+    if Path::new(&peers_file).exists() {
+        let book = AddressBook::load_from_file(&peers_file)?;
+        println!("loaded {} peer addresses", book.len());
+        *ADDRESS_BOOK.lock().expect("BUG: poisoned lock") = book;
+    }
+    tokio::spawn(address_book::save(peers_file));
+    bans::load(args.bans_file)?;
+    if let Some(admin_port) = args.admin_port {
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin_port).await {
+                println!("admin interface failed: {e}");
             }
+        });
+    }
+
+    // connect to the initial nodes and catch up with them.
+    // Ones that are down are retried later.
+    println!("trying to connect to other nodes...");
+    let mut seeds = nodes;
+    if let Some(seeds_file) = args.seeds_file {
+        seeds.extend(util::load_seeds(&seeds_file)?);
+    }
+    {
+        let mut book = ADDRESS_BOOK.lock().expect("BUG: poisoned lock");
+        for seed in &seeds {
+            book.add_seed(seed);
         }
     }
+    connections::fill(outbound, network).await;
+    println!("total amount of known nodes: {}", NODES.len());
+    let peers = NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+    if let Err(e) = util::sync_blockchain(peers, network).await {
+        println!("sync failed: {e}");
+    }
+    tokio::spawn(connections::manage(outbound, network));
 
     // Start the TCP listener on 0.0.0.0:port
     let addr = format!("0.0.0.0:{}", port);
@@ -96,14 +200,10 @@ async fn main() -> Result<()> {
     println!("Listening on {}", addr);
 
     // start a task to periodically clean up the mempool
-    // normally, you would want to keep and join the handle
     tokio::spawn(util::cleanup());
 
-    // and a task to periodically save the blockchain
-    tokio::spawn(util::save(blockchain_file.clone()));
-
     loop {
         let (socket, _) = listener.accept().await?;
-        tokio::spawn(handler::handle_connection(socket));
+        tokio::spawn(handler::handle_connection(socket, network));
     }
 }
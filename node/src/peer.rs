@@ -0,0 +1,349 @@
+use anyhow::{anyhow, Result};
+use rand::seq::IteratorRandom;
+use chrono::Duration as ChronoDuration;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tokio::time::{timeout, Duration, Instant};
+
+use lib::network::{
+    Envelope, Features, InvItem, Message, Network, PeerAddress, Version,
+    NO_REQUEST,
+};
+
+use crate::bans::{self, BAN_SCORE, DEFAULT_BAN_HOURS};
+use crate::util::Stream;
+
+/// Past this many items a peer's known inventory is forgotten,
+/// so the set does not grow forever. At worst the peer is told
+/// about something twice.
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// Messages waiting to be written to a peer. A peer that lets
+/// this many pile up is too slow to keep.
+const QUEUE_SIZE: usize = 1_000;
+
+/// A peer that takes longer than this to accept a message is
+/// too slow to keep
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer has to answer a ping
+pub const PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many peers a fresh address is passed on to
+const ADDR_RELAY_PEERS: usize = 2;
+
+/// Who opened the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Another node or client we are connected to, after the
+/// handshake
+pub struct Peer {
+    /// The address we connected to, or where an inbound
+    /// connection comes from
+    pub address: String,
+    /// Where the connection goes, which is what bans apply to
+    pub ip: Option<IpAddr>,
+    pub network: Network,
+    pub version: Version,
+    /// What the handshake settled on for this connection
+    pub features: Features,
+    pub direction: Direction,
+    /// Whether the connection is encrypted
+    pub encrypted: bool,
+    /// Points for misbehaving. The peer is banned once this
+    /// reaches `BAN_SCORE`.
+    score: AtomicU32,
+    /// Messages for the task writing to the peer
+    queue: mpsc::Sender<Envelope>,
+    /// Blocks and transactions the peer has or was told about
+    known: SyncMutex<HashSet<InvItem>>,
+    /// Set once the connection is to be closed
+    closed: watch::Sender<bool>,
+    ping: SyncMutex<Ping>,
+}
+
+#[derive(Debug, Default)]
+struct Ping {
+    /// Nonce of the ping we wait on an answer to
+    pending: Option<u64>,
+    /// When the last ping went out
+    sent: Option<Instant>,
+    /// Round-trip time of the last answered ping
+    latency: Option<Duration>,
+}
+
+impl Peer {
+    /// Split the connection and start writing to it from a task
+    /// of its own. Reading is left to the caller.
+    pub fn start(
+        address: String,
+        network: Network,
+        version: Version,
+        features: Features,
+        direction: Direction,
+        stream: Stream,
+    ) -> (Arc<Peer>, ReadHalf<Stream>) {
+        let ip = stream.get_ref().peer_addr().ok().map(|address| address.ip());
+        let encrypted = stream.remote_key().is_some();
+        let (reader, writer) = tokio::io::split(stream);
+        let (queue, outgoing) = mpsc::channel(QUEUE_SIZE);
+        let peer = Arc::new(Peer {
+            address,
+            ip,
+            network,
+            version,
+            features,
+            direction,
+            encrypted,
+            score: AtomicU32::new(0),
+            queue,
+            known: SyncMutex::new(HashSet::new()),
+            closed: watch::Sender::new(false),
+            ping: SyncMutex::new(Ping::default()),
+        });
+        tokio::spawn(write_messages(peer.clone(), writer, outgoing));
+        (peer, reader)
+    }
+
+    /// Whether blocks and transactions are relayed to the peer.
+    /// Only nodes want them, not wallets or miners, and not
+    /// while they sync from us.
+    pub fn relays(&self) -> bool {
+        self.features.relay
+    }
+
+    /// Queue a message, dropping the peer if it cannot keep up
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.reply(NO_REQUEST, message)
+    }
+
+    /// Queue the answer to request `id`, which the peer tells
+    /// apart from its other requests by the id
+    pub fn reply(&self, id: u32, message: Message) -> Result<()> {
+        let envelope = Envelope::new(id, message);
+        self.queue.try_send(envelope).map_err(|e| {
+            self.disconnect();
+            match e {
+                TrySendError::Full(_) => {
+                    anyhow!("{} is too slow, dropping it", self.address)
+                }
+                TrySendError::Closed(_) => {
+                    anyhow!("connection to {} is closed", self.address)
+                }
+            }
+        })
+    }
+
+    /// Hang up on the peer. It leaves the pool once the task
+    /// reading from it has stopped.
+    pub fn disconnect(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// Resolves once `disconnect` was called
+    pub async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        // the sender lives in `self`, so this cannot fail
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Ping the peer if the last ping is `interval` ago, and drop
+    /// it if that one went unanswered for `PING_TIMEOUT`
+    pub fn keep_alive(&self, interval: Duration) {
+        // on its way out of the pool already
+        if *self.closed.borrow() {
+            return;
+        }
+        let nonce = {
+            let mut ping = self.ping.lock().expect("BUG: poisoned lock");
+            let since = ping.sent.map(|sent| sent.elapsed());
+            if ping.pending.is_some() {
+                if since.is_some_and(|since| since > PING_TIMEOUT) {
+                    println!("{} did not answer a ping, dropping it", self.address);
+                    self.disconnect();
+                }
+                return;
+            }
+            if since.is_some_and(|since| since < interval) {
+                return;
+            }
+            let nonce = rand::random();
+            ping.pending = Some(nonce);
+            ping.sent = Some(Instant::now());
+            nonce
+        };
+        if let Err(e) = self.send(Message::Ping(nonce)) {
+            println!("{e}");
+        }
+    }
+
+    /// Record the answer to our ping. Answers to pings we did
+    /// not send are ignored.
+    pub fn pong(&self, nonce: u64) {
+        let mut ping = self.ping.lock().expect("BUG: poisoned lock");
+        if ping.pending != Some(nonce) {
+            return;
+        }
+        ping.pending = None;
+        ping.latency = ping.sent.map(|sent| sent.elapsed());
+    }
+
+    /// Round-trip time of the last answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.ping.lock().expect("BUG: poisoned lock").latency
+    }
+
+    pub fn score(&self) -> u32 {
+        self.score.load(Ordering::Relaxed)
+    }
+
+    /// Add to the peer's misbehavior score, banning and dropping
+    /// it once the score reaches `BAN_SCORE`. Peers on this
+    /// machine only get the score.
+    pub fn misbehaving(&self, points: u32, reason: &str) {
+        if points == 0 {
+            return;
+        }
+        let score = self.score.fetch_add(points, Ordering::Relaxed) + points;
+        println!(
+            "{} misbehaving ({}), score {}",
+            self.address, reason, score
+        );
+        if score >= BAN_SCORE {
+            match self.ip {
+                Some(ip) if bans::is_exempt(&ip) => {
+                    println!("not punishing local peer {}", self.address);
+                }
+                Some(ip) => bans::ban(
+                    ip,
+                    ChronoDuration::hours(DEFAULT_BAN_HOURS),
+                    reason,
+                ),
+                None => self.disconnect(),
+            }
+        }
+    }
+
+    /// Remember that the peer has this item. Returns false if
+    /// it was already known.
+    pub fn mark_known(&self, item: InvItem) -> bool {
+        let mut known = self.known.lock().expect("BUG: poisoned lock");
+        if known.len() >= MAX_KNOWN_INVENTORY {
+            known.clear();
+        }
+        known.insert(item)
+    }
+}
+
+async fn write_messages(
+    peer: Arc<Peer>,
+    mut writer: WriteHalf<Stream>,
+    mut outgoing: mpsc::Receiver<Envelope>,
+) {
+    loop {
+        let envelope = tokio::select! {
+            _ = peer.closed() => return,
+            envelope = outgoing.recv() => match envelope {
+                Some(envelope) => envelope,
+                None => return,
+            },
+        };
+        let result = timeout(
+            SEND_TIMEOUT,
+            envelope.send_async(peer.network, &mut writer),
+        )
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                println!("failed to write to {}: {e}", peer.address);
+                peer.disconnect();
+                return;
+            }
+            Err(_) => {
+                println!("{} is too slow, dropping it", peer.address);
+                peer.disconnect();
+                return;
+            }
+        }
+    }
+}
+
+/// Add a connection we opened to the pool, and start serving
+/// what the peer asks of us on it
+pub fn register(
+    address: String,
+    network: Network,
+    version: Version,
+    features: Features,
+    stream: Stream,
+) -> Arc<Peer> {
+    let (peer, reader) = Peer::start(
+        address,
+        network,
+        version,
+        features,
+        Direction::Outbound,
+        stream,
+    );
+    crate::NODES.insert(peer.address.clone(), peer.clone());
+    tokio::spawn(crate::handler::handle_messages(reader, peer.clone()));
+    peer
+}
+
+/// Tell every peer about items it does not know yet. Each peer
+/// hears about an item once, either from us or because it
+/// announced or sent it to us itself.
+pub fn announce(items: &[InvItem]) {
+    // clone the peers out, so no lock on the pool is held while
+    // queueing for them
+    let peers: Vec<Arc<Peer>> = crate::NODES
+        .iter()
+        .map(|x| x.value().clone())
+        .collect();
+
+    for peer in peers {
+        let new: Vec<InvItem> = items
+            .iter()
+            .filter(|item| peer.mark_known(**item))
+            .copied()
+            .collect();
+        if new.is_empty() {
+            continue;
+        }
+        if let Err(e) = peer.send(Message::Inv(new)) {
+            println!("{e}");
+        }
+    }
+}
+
+/// Pass addresses we just learned on to a few random peers
+/// other than the one they came from. Each peer does the same
+/// with the ones that are new to it, so they spread without
+/// flooding the network.
+pub fn relay_addresses(addresses: Vec<PeerAddress>, from: &str) {
+    if addresses.is_empty() {
+        return;
+    }
+    let peers = crate::NODES
+        .iter()
+        .filter(|x| x.key() != from)
+        .map(|x| x.value().clone())
+        .choose_multiple(&mut rand::thread_rng(), ADDR_RELAY_PEERS);
+
+    for peer in peers {
+        if let Err(e) = peer.send(Message::Addr(addresses.clone())) {
+            println!("{e}");
+        }
+    }
+}
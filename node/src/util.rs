@@ -1,17 +1,44 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Result};
+use static_init::dynamic;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use tokio::net::TcpStream;
-use tokio::time;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::time::{self, timeout, Duration};
 
-use lib::network::Message;
-use lib::types::Blockchain;
+use lib::crypto::PrivateKey;
+use lib::error::BtcError;
+use lib::network::{
+    connect_handshake_async, is_secure_handshake, secure_accept,
+    secure_connect, Features, Message, Network, Services, Transport, Version,
+    MAX_HEADERS,
+};
+use lib::sha256::Hash;
+use lib::types::{Block, BlockHeader, Blockchain, HeaderChain};
 use lib::utils::Saveable;
 
+use crate::bans;
+
+/// A connection to another node or a client, encrypted or not
+pub type Stream = Transport<TcpStream>;
+
 pub async fn load_blockchain(
     blockchain_file: &str,
+    network: Network,
 ) -> Result<()> {
     println!("blockchain file exists, loading...");
     let new_blockchain =
         Blockchain::load_from_file(blockchain_file)?;
+    if new_blockchain.block_height() == 0
+        || new_blockchain.genesis_hash() != network.genesis_hash()
+    {
+        bail!("{} does not start with the {} genesis block", blockchain_file, network);
+    }
     println!("blockchain loaded");
 
     let mut blockchain = crate::BLOCKCHAIN.write().await;
@@ -30,115 +57,453 @@ pub async fn load_blockchain(
     Ok(())
 }
 
-pub async fn populate_connections(
-    nodes: &[String],
+const USER_AGENT: &str =
+    concat!("/node:", env!("CARGO_PKG_VERSION"), "/");
+
+// Sent on every connection, to notice ones to ourselves
+#[dynamic]
+static NONCE: u64 = rand::random();
+
+// introduce ourselves with the height we are at right now
+pub async fn our_version(network: Network) -> Version {
+    let best_height = crate::BLOCKCHAIN.read().await.block_height();
+    Version::new(
+        network,
+        Services::BLOCKS | Services::UTXOS | Services::TEMPLATES,
+        best_height,
+        USER_AGENT,
+    )
+    .with_listen_port(crate::PORT.load(Ordering::Relaxed))
+    .with_nonce(*NONCE)
+}
+
+// the key we prove to hold on encrypted connections, made up
+// the first time. The file is only readable by us, as anyone
+// who reads it can pose as this node.
+pub fn load_node_key(path: &str) -> Result<PrivateKey> {
+    let key = if Path::new(path).exists() {
+        PrivateKey::load_from_file(path)?
+    } else {
+        let key = PrivateKey::new_key();
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        key.save(options.open(path)?)?;
+        key
+    };
+    println!("node key: {}", key.public_key().to_sec1_hex());
+    Ok(key)
+}
+
+pub fn node_key() -> &'static PrivateKey {
+    crate::NODE_KEY.get().expect("BUG: node key is loaded at startup")
+}
+
+// take a connection that is plaintext or encrypted, as the
+// other side chooses. Encrypted ones start with the handshake
+// rather than a frame, which the first byte tells apart.
+pub async fn accept(
+    socket: TcpStream,
+    network: Network,
+) -> Result<Stream> {
+    let mut first = [0u8; 1];
+    socket.peek(&mut first).await?;
+    if !is_secure_handshake(first[0]) {
+        return Ok(Transport::Plain(socket));
+    }
+    let stream = secure_accept(socket, network, node_key()).await?;
+    Ok(Transport::Secure(Box::new(stream)))
+}
+
+// addresses in a seed list file, one per line. Empty lines
+// and lines starting with # are skipped.
+pub fn load_seeds(path: &str) -> Result<Vec<String>> {
+    let seeds = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    Ok(seeds)
+}
+
+// connect to another node, which has to serve blocks. Nothing
+// is relayed to us on this connection, so it only carries
+// answers to what we ask.
+pub async fn connect(
+    address: &str,
+    network: Network,
+) -> Result<Stream> {
+    let (stream, _, _) = open(address, network, false).await?;
+    Ok(stream)
+}
+
+// connect to another node to relay blocks, transactions and
+// addresses with, returning what it offers and what both of
+// us take part in
+pub async fn connect_relay(
+    address: &str,
+    network: Network,
+) -> Result<(Stream, Version, Features)> {
+    open(address, network, true).await
+}
+
+async fn open(
+    address: &str,
+    network: Network,
+    relay: bool,
+) -> Result<(Stream, Version, Features)> {
+    let stream = TcpStream::connect(address).await?;
+    // other nodes are not known by key, so any key will do
+    let mut stream = if crate::ENCRYPT.load(Ordering::Relaxed) {
+        let stream =
+            secure_connect(stream, network, node_key(), None).await?;
+        Transport::Secure(Box::new(stream))
+    } else {
+        Transport::Plain(stream)
+    };
+    let ours = our_version(network).await.with_relay(relay);
+    let version =
+        connect_handshake_async(&mut stream, network, &ours, Services::BLOCKS)
+            .await?;
+    println!(
+        "connected to {} running {} with {} blocks",
+        address, version.user_agent, version.best_height
+    );
+    let features = ours.negotiate(&version);
+    Ok((stream, version, features))
+}
+
+/// How long to wait on a peer while syncing
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a block is requested before the sync gives up on it
+const MAX_BLOCK_ATTEMPTS: u32 = 3;
+
+/// Held while syncing, so syncs started by new peers wait for
+/// the one before to finish
+static SYNC: AsyncMutex<()> = AsyncMutex::const_new(());
+
+/// A peer and the header chain it reported
+struct PeerChain {
+    address: String,
+    network: Network,
+    stream: Stream,
+    headers: HeaderChain,
+}
+
+/// A block that failed validation while syncing
+struct InvalidBlock {
+    height: u64,
+    /// The peer that sent it
+    address: String,
+    error: BtcError,
+}
+
+/// Headers-first sync: collect the header chain of every
+/// peer, pick the one with the most work, then download the
+/// missing blocks from all peers that have them. Blocks are
+/// added in order as they arrive, so a sync that is cut short
+/// continues from the last saved block.
+pub async fn sync_blockchain(
+    mut peers: Vec<String>,
+    network: Network,
 ) -> Result<()> {
-    println!("trying to connect to other nodes...");
-
-    for node in nodes {
-        println!("connecting to {}", node);
-
-        let mut stream = TcpStream::connect(&node).await?;
-        let message = Message::DiscoverNodes;
-        message.send_async(&mut stream).await?;
-        println!("sent DiscoverNodes to {}", node);
-        let message =
-            Message::receive_async(&mut stream).await?;
-        match message {
-            Message::NodeList(child_nodes) => {
-                println!("received NodeList from {}", node);
-                for child_node in child_nodes {
-                    println!("adding node {}", child_node);
-                    let new_stream =
-                        TcpStream::connect(&child_node).await?;
-                    crate::NODES.insert(child_node, new_stream);
-                }
+    let _sync = SYNC.lock().await;
+    // fastest peers first, so they win ties and start
+    // downloading first
+    peers.sort_by_key(|peer| {
+        crate::NODES
+            .get(peer)
+            .and_then(|peer| peer.latency())
+            .unwrap_or(Duration::MAX)
+    });
+    // an invalid block costs its sender, and every peer whose
+    // chain has it is left out when the sync starts over. The
+    // sender is always one of them, so this ends.
+    loop {
+        let Some((invalid, holders)) = sync_from(&peers, network).await? else {
+            return Ok(());
+        };
+        println!(
+            "block {} from {} is invalid: {}",
+            invalid.height, invalid.address, invalid.error
+        );
+        if let Some(peer) = crate::NODES.get(&invalid.address) {
+            peer.misbehaving(bans::penalty(&invalid.error), &invalid.error.to_string());
+        }
+        peers.retain(|peer| !holders.contains(peer));
+    }
+}
+
+// One round of the sync. Returns the invalid block it ran into,
+// if any, with the peers whose chains have it.
+async fn sync_from(
+    peers: &[String],
+    network: Network,
+) -> Result<Option<(InvalidBlock, Vec<String>)>> {
+    // our chain starts with the genesis block, which the
+    // header chain already has
+    let mut local = HeaderChain::new(network);
+    local.extend(
+        crate::BLOCKCHAIN
+            .read()
+            .await
+            .blocks()
+            .skip(1)
+            .map(|block| block.header.clone()),
+    )?;
+
+    let mut chains = vec![];
+    for peer in peers {
+        match fetch_headers(peer, network, local.clone()).await {
+            Ok(chain) => {
+                println!(
+                    "{} has {} blocks",
+                    peer,
+                    chain.headers.height()
+                );
+                chains.push(chain);
             }
-            _ => {
-                println!("unexpected message from {}", node);
+            Err(e) => {
+                println!("failed to get headers from {}: {}", peer, e);
             }
         }
+    }
 
-        crate::NODES.insert(node.clone(), stream);
+    // max_by_key picks the last of equals, so go backwards to
+    // get the fastest peer's chain
+    let Some(best) = chains
+        .iter()
+        .rev()
+        .max_by_key(|chain| chain.headers.total_work())
+        .map(|chain| Arc::new(chain.headers.clone()))
+    else {
+        println!("no peers to sync from");
+        return Ok(None);
+    };
+    if best.total_work() <= local.total_work() {
+        println!("blockchain is up to date");
+        return Ok(None);
     }
 
-    Ok(())
+    // blocks after the common ancestor are replaced
+    let fork = local.fork_point(&best);
+    if fork < local.height() {
+        println!(
+            "a heavier branch forks off at block {}, switching to it",
+            fork
+        );
+    }
+    println!("syncing blocks {} to {}", fork, best.height());
+    // how far each peer's chain follows the best one
+    let limits: Vec<(String, u64)> = chains
+        .iter()
+        .map(|chain| (chain.address.clone(), chain.headers.fork_point(&best)))
+        .collect();
+    let invalid = download_blocks(chains, best, fork, local.height()).await?;
+    Ok(invalid.map(|invalid| {
+        let holders = limits
+            .into_iter()
+            .filter(|(_, limit)| invalid.height < *limit)
+            .map(|(address, _)| address)
+            .collect();
+        (invalid, holders)
+    }))
 }
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    println!(
-        "finding nodes with the highest blockchain length..."
+// Ask a peer for headers until it has no more. Our own chain
+// is the starting point, so the locator lets the peer find
+// the last block we have in common, even on another branch.
+// A peer may have found a few blocks since it told us its
+// height, but not more than another batch.
+async fn fetch_headers(
+    address: &str,
+    network: Network,
+    mut headers: HeaderChain,
+) -> Result<PeerChain> {
+    let (mut stream, version, _) =
+        timeout(SYNC_TIMEOUT, open(address, network, false)).await??;
+    let max_height = version.best_height.saturating_add(MAX_HEADERS as u64);
+    loop {
+        let message = Message::GetHeaders {
+            locator: headers.locator(),
+            stop_hash: Hash::zero(),
+        };
+        message.send_async(network, &mut stream).await?;
+
+        let message = timeout(
+            SYNC_TIMEOUT,
+            Message::receive_async(network, &mut stream),
+        )
+        .await??;
+        let Message::Headers(batch) = message else {
+            bail!("unexpected message from {}", address);
+        };
+        let count = batch.len();
+        headers.connect(batch)?;
+        if headers.height() > max_height {
+            bail!(
+                "{} sent more headers than the {} blocks it has",
+                address,
+                version.best_height
+            );
+        }
+        if count < MAX_HEADERS {
+            break;
+        }
+    }
+
+    Ok(PeerChain {
+        address: address.to_string(),
+        network,
+        stream,
+        headers,
+    })
+}
+
+// Returns the first block that turned out invalid, if any
+async fn download_blocks(
+    peers: Vec<PeerChain>,
+    best: Arc<HeaderChain>,
+    from: u64,
+    local_height: u64,
+) -> Result<Option<InvalidBlock>> {
+    // heights still to download, with how often they were tried
+    let queue: Arc<Mutex<BTreeMap<u64, u32>>> = Arc::new(
+        Mutex::new((from..best.height()).map(|h| (h, 0)).collect()),
     );
-    let mut longest_name = String::new();
-    let mut longest_count = 0;
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    for peer in peers {
+        tokio::spawn(download_worker(
+            peer,
+            best.clone(),
+            queue.clone(),
+            sender.clone(),
+        ));
+    }
+    drop(sender);
 
-    let all_nodes = crate::NODES
-        .iter()
-        .map(|x| x.key().clone())
-        .collect::<Vec<_>>();
-
-    for node in all_nodes {
-        println!("asking {} for blockchain length", node);
-
-        let mut stream =
-            crate::NODES.get_mut(&node).context("no node")?;
-
-        let message = Message::AskDifference(0);
-        message.send_async(&mut *stream).await.unwrap();
-
-        println!("sent AskDifference to {}", node);
-
-        let message =
-            Message::receive_async(&mut *stream).await?;
-        match message {
-            Message::Difference(count) => {
-                println!("received Difference from {}", node);
-                if count > longest_count {
-                    println!(
-                        "new longest blockchain: \
-                   {} blocks from {node}",
-                        count
-                    );
-                    longest_count = count;
-                    longest_name = node;
-                }
+    // blocks arrive out of order, and wait here until the
+    // ones before them are added. A new branch is only switched
+    // to once all of it is here.
+    let reorg = from < local_height;
+    let mut pending = BTreeMap::new();
+    let mut branch = vec![];
+    let mut senders = vec![];
+    let mut next = from;
+    while let Some((height, block, address)) = receiver.recv().await {
+        pending.insert(height, (block, address));
+        while let Some((block, address)) = pending.remove(&next) {
+            if reorg {
+                branch.push(block);
+                senders.push(address);
+            } else if let Err(error) = crate::BLOCKCHAIN.write().await.add_block(block) {
+                return Ok(Some(InvalidBlock { height: next, address, error }));
             }
-            e => {
-                println!(
-                    "unexpected message from {}: {:?}",
-                    node, e
-                );
+            next += 1;
+        }
+        if next == best.height() {
+            if reorg {
+                let reorganized = crate::BLOCKCHAIN
+                    .write()
+                    .await
+                    .reorganize(from, branch);
+                if let Err((index, error)) = reorganized {
+                    return Ok(Some(InvalidBlock {
+                        height: from + index as u64,
+                        address: senders.swap_remove(index),
+                        error,
+                    }));
+                }
             }
+            println!("synced {} blocks", next - from);
+            return Ok(None);
         }
     }
 
-    Ok((longest_name, longest_count as u32))
+    bail!("sync stopped at block {}, no peer could send it", next)
 }
 
-pub async fn download_blockchain(
-    node: &str,
-    count: u32,
-) -> Result<()> {
-    let mut stream = crate::NODES.get_mut(node).unwrap();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
-        message.send_async(&mut *stream).await?;
-
-        let message =
-            Message::receive_async(&mut *stream).await?;
-        match message {
-            Message::NewBlock(block) => {
-                let mut blockchain =
-                    crate::BLOCKCHAIN.write().await;
-                blockchain.add_block(block)?;
+async fn download_worker(
+    mut peer: PeerChain,
+    best: Arc<HeaderChain>,
+    queue: Arc<Mutex<BTreeMap<u64, u32>>>,
+    sender: mpsc::UnboundedSender<(u64, Block, String)>,
+) {
+    // the peer can only send blocks of the best chain up to
+    // where its own chain branches off
+    let limit = peer.headers.fork_point(&best);
+
+    loop {
+        let next = {
+            let mut queue = queue.lock().expect("BUG: poisoned lock");
+            match queue.first_key_value() {
+                Some((&height, _)) if height < limit => queue.pop_first(),
+                _ => None,
+            }
+        };
+        let Some((height, attempts)) = next else {
+            return;
+        };
+
+        match fetch_block(peer.network, &mut peer.stream, height, &best).await {
+            Ok(block) => {
+                if sender.send((height, block, peer.address.clone())).is_err() {
+                    return;
+                }
             }
-            _ => {
-                println!("unexpected message from {}", node);
+            Err(e) => {
+                println!(
+                    "failed to get block {} from {}: {}",
+                    height, peer.address, e
+                );
+                if attempts + 1 < MAX_BLOCK_ATTEMPTS {
+                    queue
+                        .lock()
+                        .expect("BUG: poisoned lock")
+                        .insert(height, attempts + 1);
+                }
+                // after a timeout the answer may still arrive,
+                // so start over on a fresh connection
+                match timeout(
+                    SYNC_TIMEOUT,
+                    connect(&peer.address, peer.network),
+                )
+                .await
+                {
+                    Ok(Ok(stream)) => peer.stream = stream,
+                    _ => return,
+                }
             }
         }
     }
+}
 
-    Ok(())
+async fn fetch_block(
+    network: Network,
+    stream: &mut Stream,
+    height: u64,
+    best: &HeaderChain,
+) -> Result<Block> {
+    let message = Message::FetchBlock(height as usize);
+    message.send_async(network, stream).await?;
+
+    let message = timeout(
+        SYNC_TIMEOUT,
+        Message::receive_async(network, stream),
+    )
+    .await??;
+    let Message::NewBlock(block) = message else {
+        bail!("unexpected message");
+    };
+    // the body has to belong to the header we synced
+    if Some(block.hash()) != best.header(height).map(BlockHeader::hash)
+    {
+        bail!("block does not match its header");
+    }
+
+    Ok(block)
 }
 
 pub async fn cleanup() {
@@ -163,6 +528,10 @@ pub async fn save(name: String) {
 
         println!("saving blockchain to drive...");
         let blockchain = crate::BLOCKCHAIN.read().await;
-        blockchain.save_to_file(name.clone()).unwrap();
+        // write a copy first, so a restart never finds a
+        // half-written file
+        let tmp = format!("{}.tmp", name);
+        blockchain.save_to_file(&tmp).unwrap();
+        std::fs::rename(&tmp, &name).unwrap();
     }
 }
@@ -1,20 +1,32 @@
 use anyhow::Result;
 use crossbeam_skiplist::SkipMap;
 use kanal::Sender;
-use lib::crypto::{PrivateKey, PublicKey, Signature};
-use lib::network::Message;
-use lib::types::{Transaction, TransactionInput, TransactionOutput};
+use lib::address::{Address, PubkeyHash};
+use lib::crypto::{
+    ChildNumber, DerivationPath, ExtendedPrivateKey, PrivateKey, PublicKey, SchnorrPublicKey,
+    SignedMessage, VersionedPublicKey, VersionedSignature,
+};
+use lib::network::{
+    connect_handshake_async, Client, Message, Network, Security, Services, Transport, Version,
+    MAX_HEADERS,
+};
+use lib::sha256::Hash;
+use lib::types::{
+    HeaderChain, OutPoint, OutputLock, Transaction, TransactionInput, TransactionOutput,
+};
 use lib::utils::Saveable;
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as SyncMutex};
 use tokio::sync::Mutex;
-use uuid::Uuid;
 
 use tracing::*;
+use crate::keystore::load_private;
+use crate::message::sign_message;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Key {
@@ -22,28 +34,81 @@ pub struct Key {
     pub private: PathBuf,
 }
 #[derive(Clone, Debug)]
-struct LoadedKey {
-    public: PublicKey,
-    private: PrivateKey,
+pub struct LoadedKey {
+    pub public: PublicKey,
+    pub private: PrivateKey,
 }
 
+/// Receive keys derived from a BIP32 master key, set up by
+/// `wallet create` and `wallet restore`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HdConfig {
+    /// File holding the master xprv
+    pub master_key: PathBuf,
+    /// Account the keys are derived under, e.g. m/84'/0'/0'
+    pub account: DerivationPath,
+    /// Number of receive keys handed out so far
+    pub receive_keys: u32,
+}
+
+impl HdConfig {
+    /// Path of the chain receive keys are derived from,
+    /// <account>/0
+    pub fn receive_chain(&self) -> Result<DerivationPath> {
+        Ok(self.account.child(ChildNumber::normal(0)?))
+    }
+
+    fn load_keys(&self, password: &mut Option<String>) -> Result<Vec<LoadedKey>> {
+        let master: ExtendedPrivateKey = load_private(&self.master_key, password)?;
+        let chain = master.derive_path(&self.receive_chain()?)?;
+        (0..self.receive_keys.max(1))
+            .map(|index| {
+                let key = chain.derive_child(ChildNumber::normal(index)?)?;
+                Ok(LoadedKey {
+                    public: key.private_key.public_key(),
+                    private: key.private_key,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A contact, known either by their address or by the
+/// public key file they shared
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Recipient {
     pub name: String,
-    pub key: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
 }
 
 #[derive(Clone)]
 pub struct LoadedRecipient {
-     pub key: PublicKey,
+     pub lock: OutputLock,
 }
 
 impl Recipient {
-    pub fn load(&self) -> Result<LoadedRecipient> {
-        debug!("Loading recipient key from: {:?}", self.key);
-        let key = PublicKey::load_from_file(&self.key)?;
+    pub fn load(&self, network: Network) -> Result<LoadedRecipient> {
+        if let Some(address) = self.address {
+            if !address.is_valid_for(network) {
+                return Err(anyhow::anyhow!(
+                    "Address of {} is not for {}",
+                    self.name,
+                    network
+                ));
+            }
+            return Ok(LoadedRecipient { lock: address.into() });
+        }
+        let key_path = self
+            .key
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Contact {} has neither a key nor an address", self.name))?;
+        debug!("Loading recipient key from: {:?}", key_path);
+        let key = PublicKey::load_from_file(key_path)?;
         Ok(LoadedRecipient {
-           key
+           lock: VersionedPublicKey::Ecdsa(key).into(),
         })
     }
 }
@@ -65,12 +130,113 @@ pub struct Config {
     pub contacts: Vec<Recipient>,
     pub default_node: String,
     pub fee_config: FeeConfig,
+    #[serde(default)]
+    pub network: Network,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hd: Option<HdConfig>,
+    /// Light client mode: sync block headers and only count
+    /// outputs the node proves to be in the chain
+    #[serde(default)]
+    pub spv: bool,
+    /// Where the verified headers are kept between runs in SPV
+    /// mode. A node has to extend them to be followed.
+    #[serde(default = "default_headers_file")]
+    pub headers_file: PathBuf,
+    /// Encrypt the connection to the node
+    #[serde(default)]
+    pub encrypt: bool,
+    /// Key of the node, as SEC1 hex. With it set, the wallet
+    /// only talks to the node that proves to hold it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_key: Option<String>,
+}
+
+fn default_headers_file() -> PathBuf {
+    PathBuf::from("wallet_headers.cbor")
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn security(&self) -> Result<Security> {
+        let node_key = self
+            .node_key
+            .as_deref()
+            .map(PublicKey::from_sec1_hex)
+            .transpose()?;
+        Ok(Security::new(self.encrypt, node_key))
+    }
+
+    /// Every key of the wallet: the listed key pairs first, then
+    /// the receive keys derived from the master key
+    pub fn load_keys(&self) -> Result<Vec<LoadedKey>> {
+        let mut password = None;
+        let mut keys = vec![];
+        for key in &self.my_keys {
+            debug!("Loading key {:?}", key);
+            let public = PublicKey::load_from_file(&key.public)?;
+            let private = load_private(&key.private, &mut password)?;
+            keys.push(LoadedKey { public, private });
+        }
+        if let Some(hd) = &self.hd {
+            keys.extend(hd.load_keys(&mut password)?);
+        }
+        Ok(keys)
+    }
+}
+
+/// Connection to a node, encrypted if the config asks for it
+pub type NodeStream = Transport<TcpStream>;
+
+/// Connect to a node on our network that offers `services`
+pub async fn connect(
+    node: &str,
+    network: Network,
+    security: &Security,
+    services: Services,
+) -> Result<NodeStream> {
+    let stream = TcpStream::connect(node).await?;
+    let mut stream = Transport::connect(stream, network, security).await?;
+    let version = Version::new(
+        network,
+        Services::NONE,
+        0,
+        concat!("/wallet:", env!("CARGO_PKG_VERSION"), "/"),
+    );
+    let node_version = connect_handshake_async(&mut stream, network, &version, services).await?;
+    debug!("Connected to {} running {}", node, node_version.user_agent);
+    Ok(stream)
+}
+
+/// Headers saved by an earlier run, checked again as they are
+/// loaded, or just the genesis header the first time
+fn load_headers(path: &Path, network: Network) -> Result<HeaderChain> {
+    if !path.exists() {
+        return Ok(HeaderChain::new(network));
+    }
+    let headers = HeaderChain::load_from_file(path)
+        .map_err(|e| anyhow::anyhow!("Cannot load headers from {}: {}", path.display(), e))?;
+    if headers.network() != network {
+        return Err(anyhow::anyhow!(
+            "{} holds {} headers, not {}",
+            path.display(),
+            headers.network(),
+            network
+        ));
+    }
+    debug!("Loaded {} headers from {}", headers.height(), path.display());
+    Ok(headers)
 }
 
+/// An unspent output we own: (used in mempool, outpoint, output)
+type OwnedUtxo = (bool, OutPoint, TransactionOutput);
+
 #[derive(Clone)]
 struct UtxoStore {
     my_keys: Vec<LoadedKey>,
-    utxos: Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+    utxos: Arc<SkipMap<VersionedPublicKey, Vec<OwnedUtxo>>>,
 }
 
 impl UtxoStore {
@@ -84,68 +250,193 @@ impl UtxoStore {
     fn add_key(&mut self, key: LoadedKey) {
         self.my_keys.push(key);
     }
+
+    /// Every form an output paying one of our keys can lock to
+    fn locking_keys(&self) -> Vec<VersionedPublicKey> {
+        self.my_keys
+            .iter()
+            .flat_map(|key| {
+                [
+                    VersionedPublicKey::Ecdsa(key.public.clone()),
+                    VersionedPublicKey::Schnorr(SchnorrPublicKey::from(&key.public)),
+                ]
+            })
+            .collect()
+    }
 }
 pub struct Core {
     pub config: Config,
     utxos: UtxoStore,
     pub tx_sender: Sender<Transaction>,
-    pub stream: Mutex<TcpStream>,
+    client: Client<NodeStream>,
+    /// Messages the node sent without being asked
+    pub pushes: Mutex<mpsc::Receiver<Message>>,
+    /// Verified headers, only synced in SPV mode
+    headers: SyncMutex<HeaderChain>,
 }
 
 impl Core {
-    fn new(config: Config, utxos: UtxoStore, stream: TcpStream) -> Self {
+    /// Addresses to receive coins on, one per key
+    pub fn addresses(&self) -> Vec<Address> {
+        self.utxos
+            .my_keys
+            .iter()
+            .map(|key| {
+                Address::from_public_key(
+                    self.config.network,
+                    &VersionedPublicKey::Ecdsa(key.public.clone()),
+                )
+            })
+            .collect()
+    }
+
+    /// Sign a message with the key at `index`, as listed by
+    /// `addresses`
+    pub fn sign_message(&self, index: usize, message: &str, schnorr: bool) -> Result<SignedMessage> {
+        let key = self
+            .utxos
+            .my_keys
+            .get(index)
+            .ok_or(anyhow::anyhow!("No key number {}", index))?;
+        Ok(sign_message(key, message, schnorr))
+    }
+
+    fn new(config: Config, utxos: UtxoStore, stream: NodeStream, headers: HeaderChain) -> Self {
         let (tx_sender, _) = kanal::bounded(10);
+        let (client, pushes) = Client::new(stream, config.network);
         Core {
             config,
             utxos,
             tx_sender,
-            stream: Mutex::new(stream),
+            client,
+            pushes: Mutex::new(pushes),
+            headers: SyncMutex::new(headers),
         }
     }
-    pub async fn load(config_path: PathBuf) -> Result<Self> {
-        let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    pub async fn load(config: Config) -> Result<Self> {
         let mut utxos = UtxoStore::new();
-        let stream = TcpStream::connect(&config.default_node).await?;
-        for key in &config.my_keys {
-            println!("{:?}", key);
-            let public = PublicKey::load_from_file(&key.public)?;
-            let private = PrivateKey::load_from_file(&key.private)?;
-            utxos.add_key(LoadedKey { public, private });
-            println!("{:?}", utxos.my_keys.len());
-            println!("{:?}", utxos.my_keys[0]);
+        let services = if config.spv {
+            Services::BLOCKS | Services::UTXOS
+        } else {
+            Services::UTXOS
+        };
+        let stream = connect(
+            &config.default_node,
+            config.network,
+            &config.security()?,
+            services,
+        )
+        .await?;
+        for key in config.load_keys()? {
+            utxos.add_key(key);
         }
-        Ok(Core::new(config, utxos, stream))
+        let headers = if config.spv {
+            load_headers(&config.headers_file, config.network)?
+        } else {
+            HeaderChain::new(config.network)
+        };
+        Ok(Core::new(config, utxos, stream, headers))
     }
     pub async fn fetch_utxos(&self) -> Result<()> {
-        for key in &self.utxos.my_keys {
-            let message = Message::FetchUTXOs(key.public.clone());
-            message.send_async(&mut *self.stream.lock().await).await?;
-            if let Message::UTXOs(utxos) =
-                Message::receive_async(&mut *self.stream.lock().await).await?
-            {
-                debug!("Received {} UTXOs for key: {:?}", utxos.len(), key.public);
-                self.utxos.utxos.insert(
-                    key.public.clone(),
-                    utxos
-                        .into_iter()
-                        .map(|(output, marked)| (marked, output))
-                        .collect(),
-                );
+        if self.config.spv {
+            self.sync_headers().await?;
+        }
+        for key in self.utxos.locking_keys() {
+            let utxos = if self.config.spv {
+                self.fetch_proven_utxos(&key).await?
             } else {
+                self.fetch_trusted_utxos(&key).await?
+            };
+            debug!("Received {} UTXOs for key: {:?}", utxos.len(), key);
+            self.utxos.utxos.insert(key, utxos);
+        }
+        info!("UTXOs fetched successfully");
+        Ok(())
+    }
+
+    /// Send a request and wait for the response to it. Other
+    /// requests may be in flight on the same connection.
+    async fn request(&self, message: Message) -> Result<Message> {
+        Ok(self.client.request(message).await?)
+    }
+
+    async fn fetch_trusted_utxos(&self, key: &VersionedPublicKey) -> Result<Vec<OwnedUtxo>> {
+        let Message::UTXOs(utxos) = self.request(Message::FetchUTXOs(key.clone())).await? else {
+            error!("Unexpected response from node");
+            return Err(anyhow::anyhow!("Unexpected response from node"));
+        };
+        Ok(utxos
+            .into_iter()
+            .map(|(outpoint, output, marked)| (marked, outpoint, output))
+            .collect())
+    }
+
+    /// Only outputs whose transactions are proven to be in our
+    /// header chain are kept
+    async fn fetch_proven_utxos(&self, key: &VersionedPublicKey) -> Result<Vec<OwnedUtxo>> {
+        let Message::UTXOProofs(proofs) =
+            self.request(Message::FetchUTXOProofs(key.clone())).await?
+        else {
+            error!("Unexpected response from node");
+            return Err(anyhow::anyhow!("Unexpected response from node"));
+        };
+        let headers = self.headers.lock().expect("BUG: poisoned lock");
+        let mut utxos = vec![];
+        for proof in proofs {
+            match proof.output() {
+                Some(output) if output.lock.is_owned_by(key) && proof.verify(&headers) => {
+                    utxos.push((proof.marked, proof.outpoint(), output.clone()));
+                }
+                _ => warn!("Ignoring unproven UTXO {} from node", proof.outpoint()),
+            }
+        }
+        Ok(utxos)
+    }
+
+    /// Download the headers we do not have yet, checking each
+    /// one before it is added. A node on a branch that leaves
+    /// out any of our headers is not followed, however much
+    /// work it claims. New headers are saved right away.
+    async fn sync_headers(&self) -> Result<()> {
+        let mut chain = self.headers.lock().expect("BUG: poisoned lock").clone();
+        loop {
+            let message = Message::GetHeaders {
+                locator: chain.locator(),
+                stop_hash: Hash::zero(),
+            };
+            let Message::Headers(headers) = self.request(message).await? else {
                 error!("Unexpected response from node");
-                return Err(anyhow::anyhow!("Unexpedted response from node"));
+                return Err(anyhow::anyhow!("Unexpected response from node"));
+            };
+            let count = headers.len();
+            chain
+                .connect(headers)
+                .map_err(|e| anyhow::anyhow!("Node sent a bad header: {}", e))?;
+            if count < MAX_HEADERS {
+                break;
             }
         }
-        info!("UTXOs fetched successfully");
+        let mut headers = self.headers.lock().expect("BUG: poisoned lock");
+        if chain.fork_point(&headers) < headers.height() {
+            return Err(anyhow::anyhow!(
+                "Node is on a branch that does not extend our headers"
+            ));
+        }
+        if chain.height() > headers.height() {
+            chain.save_to_file(&self.config.headers_file)?;
+            *headers = chain;
+        }
+        debug!("Synced headers up to height {}", headers.height());
         Ok(())
     }
+
     pub async fn send_transaction(&self, transaction: Transaction) -> Result<()> {
         debug!(
             "Sending transaction to node: {}",
             self.config.default_node
         );
         let message = Message::SubmitTransaction(transaction);
-        message.send_async(&mut *self.stream.lock().await).await?;
+        self.client.send(message).await?;
         info!("Transaction sent successfully");
         Ok(())
     }
@@ -153,42 +444,38 @@ impl Core {
         self.utxos
             .utxos
             .iter()
-            .map(|entry| entry.value().iter().map(|utxo| utxo.1.value).sum::<u64>())
+            .map(|entry| entry.value().iter().map(|utxo| utxo.2.value).sum::<u64>())
             .sum()
     }
 
     pub fn create_transaction(
         &self,
-        recipient: &PublicKey,
+        recipient: &OutputLock,
         amount: u64,
     ) -> Result<Transaction> {
         let fee = self.calculate_fee(amount);
         let total_amount = amount + fee;
-        let mut inputs = Vec::new();
+        let mut selected = Vec::new();
         let mut input_sum = 0;
         for entry in self.utxos.utxos.iter() {
             let pubkey = entry.key();
             let utxos = entry.value();
-            for (market, utxo) in utxos.iter() {
+            for (market, outpoint, utxo) in utxos.iter() {
                 if *market {
                     continue;
                 }
                 if input_sum >= total_amount {
                     break;
                 }
-                inputs.push(TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature: Signature::sign_output(
-                        &utxo.hash(),
-                        &self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private,
-                    ),
-                });
+                let key = self
+                    .utxos
+                    .my_keys
+                    .iter()
+                    .find(|k| pubkey.is_controlled_by(&k.private))
+                    .unwrap();
+                // outputs locked to a key hash need the key revealed
+                let reveal = matches!(utxo.lock, OutputLock::PubkeyHash(_));
+                selected.push((*outpoint, pubkey.clone(), reveal, &key.private));
                 input_sum += utxo.value;
             }
             if input_sum >= total_amount {
@@ -200,16 +487,27 @@ impl Core {
         }
         let mut outputs = vec![TransactionOutput {
             value: amount,
-            unique_id: Uuid::new_v4(),
-            pubkey: recipient.clone(),
+            lock: recipient.clone(),
         }];
         if input_sum > total_amount {
+            let change_key = VersionedPublicKey::Ecdsa(self.utxos.my_keys[0].public.clone());
             outputs.push(TransactionOutput {
                 value: input_sum - total_amount,
-                unique_id: Uuid::new_v4(),
-                pubkey: self.utxos.my_keys[0].public.clone(),
+                lock: OutputLock::PubkeyHash(PubkeyHash::of(&change_key)),
             });
         }
+        // every input signs the txid, which covers all inputs
+        // and outputs but not the signatures themselves
+        let prev_outputs: Vec<_> = selected.iter().map(|(outpoint, ..)| *outpoint).collect();
+        let txid = Transaction::compute_txid(&prev_outputs, &outputs);
+        let inputs = selected
+            .into_iter()
+            .map(|(previous_output, pubkey, reveal, private)| TransactionInput {
+                previous_output,
+                signature: VersionedSignature::sign(&txid, private, &pubkey),
+                public_key: reveal.then_some(pubkey),
+            })
+            .collect();
         Ok(Transaction::new(inputs, outputs))
     }
     fn calculate_fee(&self, amount: u64) -> u64 {
@@ -219,6 +517,7 @@ impl Core {
         }
     }
     
+    /// Send to a contact by name, or straight to an address
     pub fn send_transaction_async(
         &self,
         recipient: &str,
@@ -226,16 +525,20 @@ impl Core {
     ) -> Result<()> {
         info!("Preparing to send {} satoshis to {}", amount, recipient);
         
-        let recipient_key = self
-            .config
-            .contacts
-            .iter()
-            .find(|c| c.name == recipient)
-            .ok_or(anyhow::anyhow!("Recipient not found"))?
-            .load()?
-            .key;
+        let recipient_lock = match self.config.contacts.iter().find(|c| c.name == recipient) {
+            Some(contact) => contact.load(self.config.network)?.lock,
+            None => {
+                let address: Address = recipient
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Recipient not found"))?;
+                if !address.is_valid_for(self.config.network) {
+                    return Err(anyhow::anyhow!("Address is not for {}", self.config.network));
+                }
+                address.into()
+            }
+        };
         let transaction =
-            self.create_transaction(&recipient_key, amount)?;
+            self.create_transaction(&recipient_lock, amount)?;
         debug!("Sending transaction asynchronously");
         self.tx_sender.send(transaction)?;
         Ok(())
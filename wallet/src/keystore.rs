@@ -0,0 +1,87 @@
+use anyhow::{anyhow, Result};
+use lib::crypto::{EncryptedKey, ExtendedPrivateKey, KeyFile, PrivateKey};
+use lib::error::BtcError;
+use lib::utils::Saveable;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::*;
+use crate::core::Config;
+
+/// Load a private key file, asking for the wallet password the
+/// first time an encrypted one comes up. Files encrypted with
+/// another password, e.g. by `key_gen`, ask for their own.
+pub fn load_private<T: Saveable>(path: &Path, password: &mut Option<String>) -> Result<T> {
+    let load = || {
+        KeyFile::<T>::load_from_file(path)
+            .map_err(|e| anyhow!("Failed to load {}: {}", path.display(), e))
+    };
+    let file = load()?;
+    if !file.is_encrypted() {
+        return Ok(file.unlock("")?);
+    }
+    let shared = match password {
+        Some(shared) => shared,
+        None => password.insert(rpassword::prompt_password("Wallet password: ")?),
+    };
+    match file.unlock(shared) {
+        Err(BtcError::InvalidPassword) => {
+            let own = rpassword::prompt_password(format!("Password for {}: ", path.display()))?;
+            Ok(load()?.unlock(&own)?)
+        }
+        result => Ok(result?),
+    }
+}
+
+pub fn new_password() -> Result<String> {
+    let password = rpassword::prompt_password("New wallet password: ")?;
+    if password.is_empty() {
+        return Err(anyhow!("The password cannot be empty"));
+    }
+    if rpassword::prompt_password("Repeat the password: ")? != password {
+        return Err(anyhow!("Passwords do not match"));
+    }
+    Ok(password)
+}
+
+/// Write a key encrypted. The file is replaced only once the
+/// new one is complete.
+pub fn save_encrypted<T: Saveable>(key: &T, path: &Path, password: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    EncryptedKey::seal(key, password)?.save_to_file(&tmp)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Re-encrypt every private key file of the wallet with a new
+/// password. Plaintext key files from older wallets get
+/// encrypted along the way.
+pub fn rekey(config_path: &Path) -> Result<()> {
+    let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    let mut password = None;
+    let keys = config
+        .my_keys
+        .iter()
+        .map(|key| Ok((key.private.clone(), load_private::<PrivateKey>(&key.private, &mut password)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let master = match &config.hd {
+        Some(hd) => Some((
+            hd.master_key.clone(),
+            load_private::<ExtendedPrivateKey>(&hd.master_key, &mut password)?,
+        )),
+        None => None,
+    };
+
+    let new_password = new_password()?;
+    for (path, key) in &keys {
+        save_encrypted(key, path, &new_password)?;
+        info!("Re-encrypted {:?}", path);
+    }
+    if let Some((path, master)) = &master {
+        save_encrypted(master, path, &new_password)?;
+        info!("Re-encrypted {:?}", path);
+    }
+    println!("{} key files encrypted with the new password", keys.len() + master.iter().count());
+    Ok(())
+}
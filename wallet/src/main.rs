@@ -1,16 +1,22 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use cursive::views::TextContent;
+use lib::address::Address;
+use lib::network::Network;
 use tracing::{debug, info};
 use std::path::PathBuf;
 use std::sync::Arc;
 mod core;
+mod keystore;
+mod message;
+mod seed;
 mod tasks;
 mod ui;
 mod util;
-use core::Core;
+use core::{Config, Core};
+use seed::SeedOptions;
 use tasks::{
-    handle_transactions, ui_task, update_balance, update_utxos,
+    handle_pushes, handle_transactions, ui_task, update_balance, update_utxos,
 };
 use util::{
     big_mode_btc, generate_dummy_config, setup_panic_hook,
@@ -26,6 +32,16 @@ struct Cli {
     config: PathBuf,
     #[arg(short, long, value_name = "ADDRESS")]
     node: Option<String>,
+    /// Run as a light client, trusting only proven outputs
+    #[arg(long)]
+    spv: bool,
+    /// Encrypt the connection to the node
+    #[arg(long)]
+    encrypt: bool,
+    /// Only talk to the node with this key, as SEC1 hex.
+    /// Implies --encrypt.
+    #[arg(long, value_name = "HEX")]
+    node_key: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -34,8 +50,74 @@ enum Commands {
         #[arg(short, long, value_name = "FILE", default_value_os_t = PathBuf::from("wallet_config.toml"))]
         output: PathBuf,
     },
+    /// Create a wallet from a new recovery phrase
+    Create {
+        /// Number of words in the phrase
+        #[arg(long, default_value_t = 12)]
+        words: usize,
+        #[command(flatten)]
+        seed: SeedArgs,
+    },
+    /// Restore a wallet from its recovery phrase
+    Restore {
+        #[command(flatten)]
+        seed: SeedArgs,
+    },
+    /// Encrypt the wallet's key files with a new password
+    Rekey,
+    /// Sign a message with one of the wallet's keys
+    SignMessage {
+        /// Which key to sign with, in the order the wallet lists them
+        #[arg(long, default_value_t = 0)]
+        key: usize,
+        /// Sign with the Schnorr form of the key
+        #[arg(long)]
+        schnorr: bool,
+        message: String,
+    },
+    /// Verify a signed message, read from a file or from stdin with "-"
+    VerifyMessage {
+        #[arg(default_value = "-")]
+        input: PathBuf,
+        /// Only accept signatures by this public key file
+        #[arg(long, value_name = "FILE", conflicts_with = "address")]
+        key: Option<PathBuf>,
+        /// Only accept signatures by this address
+        #[arg(long)]
+        address: Option<Address>,
+    },
+}
+
+#[derive(clap::Args)]
+struct SeedArgs {
+    /// Where to write the master key
+    #[arg(long, value_name = "FILE", default_value_os_t = PathBuf::from("wallet.xprv"))]
+    master_key: PathBuf,
+    #[arg(long, default_value = "mainnet")]
+    network: Network,
+    /// Ask for a BIP39 passphrase, which is needed again on
+    /// restore. It is read without echo, so it stays out of the
+    /// shell history.
+    #[arg(long)]
+    passphrase: bool,
+}
+
+impl SeedArgs {
+    fn options<'a>(&'a self, cli: &'a Cli) -> SeedOptions<'a> {
+        SeedOptions {
+            config_path: &cli.config,
+            master_key: &self.master_key,
+            network: self.network,
+            passphrase: self.passphrase,
+            node: cli.node.as_deref().unwrap_or(DEFAULT_NODE),
+            encrypt: cli.encrypt,
+            node_key: cli.node_key.as_deref(),
+        }
+    }
 }
 
+const DEFAULT_NODE: &str = "127.0.0.1:9000";
+
 #[tokio::main]
 async fn main() -> Result<()> {
     setup_tracing()?;
@@ -47,19 +129,47 @@ async fn main() -> Result<()> {
             debug!("Generating dummy config at: {:?}", output);
             return generate_dummy_config(output);
         }
+        Some(Commands::Create { words, seed }) => {
+            return seed::create(&seed.options(&cli), *words);
+        }
+        Some(Commands::Restore { seed }) => {
+            return seed::restore(&seed.options(&cli)).await;
+        }
+        Some(Commands::Rekey) => {
+            return keystore::rekey(&cli.config);
+        }
+        Some(Commands::SignMessage { key, schnorr, message }) => {
+            return message::sign_command(&cli.config, *key, *schnorr, message);
+        }
+        Some(Commands::VerifyMessage { input, key, address }) => {
+            return message::verify_command(&cli.config, input, key.as_deref(), *address);
+        }
         None => (),
     }
     info!("Loading config from: {:?}", cli.config);
-    let mut core = Core::load(cli.config.clone()).await?;
+    let mut config = Config::load(&cli.config)?;
     if let Some(node) = cli.node {
         info!("Overriding default node with: {}", node);
-        core.config.default_node = node;
+        config.default_node = node;
+    }
+    if cli.spv {
+        config.spv = true;
+    }
+    if cli.encrypt {
+        config.encrypt = true;
+    }
+    if let Some(node_key) = cli.node_key {
+        config.node_key = Some(node_key);
     }
+    let mut core = Core::load(config).await?;
     let (tx_sender, tx_receiver) = kanal::bounded(10);
     core.tx_sender = tx_sender;
     let core = Arc::new(core);
     info!("Starting background tasks");
     let balance_content = TextContent::new(big_mode_btc(&core));
+    // a closed connection shows in the other tasks, so this one
+    // ending is no reason to shut down
+    handle_pushes(core.clone()).await;
     tokio::select! {
         _ = ui_task(core.clone(), balance_content.clone()).await => (),
         _ = update_utxos(core.clone()).await => (),
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use lib::address::Address;
+use lib::crypto::{PublicKey, SchnorrPublicKey, SignedMessage, VersionedPublicKey};
+use lib::network::Network;
+use lib::utils::Saveable;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use crate::core::{Config, LoadedKey};
+
+/// Who a signature has to come from to be accepted
+pub enum Signer {
+    Key(PublicKey),
+    Address(Address),
+    /// Any key, as long as the signature is valid
+    Anyone,
+}
+
+pub fn sign_message(key: &LoadedKey, message: &str, schnorr: bool) -> SignedMessage {
+    let public_key = if schnorr {
+        VersionedPublicKey::Schnorr(SchnorrPublicKey::from(&key.public))
+    } else {
+        VersionedPublicKey::Ecdsa(key.public.clone())
+    };
+    SignedMessage::sign(message, &key.private, &public_key)
+}
+
+/// Check an armored signed message. Returns the address of
+/// the signer on success.
+pub fn verify_message(text: &str, signer: &Signer, network: Network) -> Result<Address> {
+    let signed: SignedMessage = text
+        .parse()
+        .map_err(|_| anyhow!("Not a signed message"))?;
+    if !signed.verify() {
+        return Err(anyhow!("Invalid signature"));
+    }
+    let matches = match signer {
+        Signer::Key(key) => signed.is_signed_by(key),
+        Signer::Address(address) => signed.is_signed_by_address(address),
+        Signer::Anyone => true,
+    };
+    if !matches {
+        return Err(anyhow!("Valid signature, but by another key"));
+    }
+    Ok(Address::from_public_key(network, signed.public_key()))
+}
+
+/// `wallet sign-message`: print the armored signature
+pub fn sign_command(config_path: &Path, key_index: usize, schnorr: bool, message: &str) -> Result<()> {
+    let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    let keys = config.load_keys()?;
+    let key = keys
+        .get(key_index)
+        .ok_or(anyhow!("The wallet has {} keys, no key number {}", keys.len(), key_index))?;
+    print!("{}", sign_message(key, message, schnorr));
+    Ok(())
+}
+
+/// `wallet verify-message`: read an armored message from a
+/// file, or from stdin for `-`
+pub fn verify_command(
+    config_path: &Path,
+    input: &Path,
+    key: Option<&Path>,
+    address: Option<Address>,
+) -> Result<()> {
+    let network = fs::read_to_string(config_path)
+        .ok()
+        .and_then(|config| toml::from_str::<Config>(&config).ok())
+        .map(|config| config.network)
+        .unwrap_or_default();
+    let text = if input == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        fs::read_to_string(input)?
+    };
+    let signer = match (key, address) {
+        (Some(key), None) => Signer::Key(PublicKey::load_from_file(key)?),
+        (None, Some(address)) => Signer::Address(address),
+        (None, None) => Signer::Anyone,
+        (Some(_), Some(_)) => return Err(anyhow!("Give either a key or an address")),
+    };
+    let address = verify_message(&text, &signer, network)?;
+    println!("Valid signature by {address}");
+    Ok(())
+}
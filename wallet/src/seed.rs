@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Result};
+use lib::address::PubkeyHash;
+use lib::crypto::{
+    ChildNumber, DerivationPath, ExtendedPrivateKey, Mnemonic, PublicKey, SchnorrPublicKey,
+    VersionedPublicKey,
+};
+use lib::network::{Message, Network, Security, Services, MAX_HEADERS};
+use lib::sha256::Hash;
+use lib::types::{HeaderChain, OutputLock};
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use tracing::*;
+use crate::core::{connect, Config, FeeConfig, FeeType, HdConfig};
+use crate::keystore::{new_password, save_encrypted};
+
+/// Keys are handed out in order, so a restore stops looking
+/// after this many unused keys in a row
+const GAP_LIMIT: u32 = 20;
+
+/// Settings shared by `create` and `restore`
+pub struct SeedOptions<'a> {
+    pub config_path: &'a Path,
+    pub master_key: &'a Path,
+    pub network: Network,
+    /// Whether to ask for a BIP39 passphrase
+    pub passphrase: bool,
+    pub node: &'a str,
+    pub encrypt: bool,
+    /// Key of the node, as SEC1 hex
+    pub node_key: Option<&'a str>,
+}
+
+impl SeedOptions<'_> {
+    fn security(&self) -> Result<Security> {
+        let node_key = self.node_key.map(PublicKey::from_sec1_hex).transpose()?;
+        Ok(Security::new(self.encrypt, node_key))
+    }
+}
+
+/// m/84'/<coin>'/0', with coin type 1 for the test networks
+fn account_path(network: Network) -> Result<DerivationPath> {
+    let coin = match network {
+        Network::Mainnet => 0,
+        Network::Testnet | Network::Regtest => 1,
+    };
+    Ok(DerivationPath::from(vec![
+        ChildNumber::hardened(84)?,
+        ChildNumber::hardened(coin)?,
+        ChildNumber::hardened(0)?,
+    ]))
+}
+
+pub fn create(options: &SeedOptions<'_>, word_count: usize) -> Result<()> {
+    refuse_overwrite(options)?;
+    let mnemonic = Mnemonic::generate(word_count)?;
+    println!("Write down your recovery phrase and keep it safe.");
+    println!("It is the only way to restore this wallet:\n");
+    println!("    {mnemonic}\n");
+    let passphrase = read_passphrase(options, true)?;
+    let (master, hd) = master_key(options, &mnemonic, &passphrase)?;
+    save(options, &master, hd)
+}
+
+pub async fn restore(options: &SeedOptions<'_>) -> Result<()> {
+    refuse_overwrite(options)?;
+    print!("Recovery phrase: ");
+    io::stdout().flush()?;
+    let mut phrase = String::new();
+    io::stdin().lock().read_line(&mut phrase)?;
+    let mnemonic: Mnemonic = phrase
+        .parse()
+        .map_err(|_| anyhow!("Not a valid recovery phrase"))?;
+
+    let passphrase = read_passphrase(options, false)?;
+    let (master, mut hd) = master_key(options, &mnemonic, &passphrase)?;
+    let receive_chain = master.derive_path(&hd.receive_chain()?)?.public_key();
+    println!("Scanning the chain through {}...", options.node);
+    let used = scan(options.node, options.network, &options.security()?, |index| {
+        Ok(receive_chain.derive_child(ChildNumber::normal(index)?)?.public_key)
+    })
+    .await?;
+    println!("Found {used} used keys");
+
+    // hand out one fresh key on top of the used ones
+    hd.receive_keys = used + 1;
+    save(options, &master, hd)
+}
+
+// Download every block and collect the key hashes that were paid,
+// then derive keys until GAP_LIMIT of them in a row were never
+// paid. Returns how many keys have been used.
+async fn scan(
+    node: &str,
+    network: Network,
+    security: &Security,
+    derive: impl Fn(u32) -> Result<PublicKey>,
+) -> Result<u32> {
+    let mut stream = connect(node, network, security, Services::BLOCKS).await?;
+    let mut headers = HeaderChain::new(network);
+    loop {
+        let message = Message::GetHeaders {
+            locator: headers.locator(),
+            stop_hash: Hash::zero(),
+        };
+        message.send_async(network, &mut stream).await?;
+        let Message::Headers(batch) = Message::receive_async(network, &mut stream).await? else {
+            return Err(anyhow!("Unexpected response from node"));
+        };
+        let count = batch.len();
+        headers.connect(batch)?;
+        if count < MAX_HEADERS {
+            break;
+        }
+    }
+
+    let mut paid = HashSet::new();
+    for (height, header) in headers.headers().enumerate() {
+        Message::FetchBlock(height).send_async(network, &mut stream).await?;
+        let Message::NewBlock(block) = Message::receive_async(network, &mut stream).await? else {
+            return Err(anyhow!("Unexpected response from node"));
+        };
+        if block.hash() != header.hash() {
+            return Err(anyhow!("Block {} does not match its header", height));
+        }
+        for output in block.transactions.iter().flat_map(|tx| &tx.outputs) {
+            paid.insert(match &output.lock {
+                OutputLock::PublicKey(key) => PubkeyHash::of(key),
+                OutputLock::PubkeyHash(hash) => *hash,
+            });
+        }
+    }
+    debug!("Scanned {} blocks, {} paid keys", headers.height(), paid.len());
+
+    let mut used = 0;
+    let mut index = 0;
+    while index < used + GAP_LIMIT {
+        let key = derive(index)?;
+        let forms = [
+            VersionedPublicKey::Schnorr(SchnorrPublicKey::from(&key)),
+            VersionedPublicKey::Ecdsa(key),
+        ];
+        if forms.iter().any(|form| paid.contains(&PubkeyHash::of(form))) {
+            used = index + 1;
+        }
+        index += 1;
+    }
+    Ok(used)
+}
+
+fn refuse_overwrite(options: &SeedOptions) -> Result<()> {
+    for path in [options.config_path, options.master_key] {
+        if path.exists() {
+            return Err(anyhow!("{} already exists", path.display()));
+        }
+    }
+    Ok(())
+}
+
+// The passphrase is asked for twice on create, as a typo would
+// make the wallet impossible to restore
+fn read_passphrase(options: &SeedOptions, confirm: bool) -> Result<String> {
+    if !options.passphrase {
+        return Ok(String::new());
+    }
+    let passphrase = rpassword::prompt_password("BIP39 passphrase: ")?;
+    if confirm && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+        return Err(anyhow!("Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn master_key(
+    options: &SeedOptions,
+    mnemonic: &Mnemonic,
+    passphrase: &str,
+) -> Result<(ExtendedPrivateKey, HdConfig)> {
+    let seed = mnemonic.to_seed(passphrase);
+    let master = ExtendedPrivateKey::new_master(&seed, options.network)?;
+    let hd = HdConfig {
+        master_key: PathBuf::from(options.master_key),
+        account: account_path(options.network)?,
+        receive_keys: 1,
+    };
+    Ok((master, hd))
+}
+
+fn save(options: &SeedOptions, master: &ExtendedPrivateKey, hd: HdConfig) -> Result<()> {
+    save_encrypted(master, options.master_key, &new_password()?)?;
+    info!("Master key written to {:?}", options.master_key);
+    write_config(options, hd)
+}
+
+fn write_config(options: &SeedOptions, hd: HdConfig) -> Result<()> {
+    let config = Config {
+        my_keys: vec![],
+        contacts: vec![],
+        default_node: options.node.to_string(),
+        fee_config: FeeConfig {
+            feetype: FeeType::Percent,
+            value: 0.1,
+        },
+        network: options.network,
+        hd: Some(hd),
+        spv: false,
+        headers_file: PathBuf::from("wallet_headers.cbor"),
+        encrypt: options.encrypt,
+        node_key: options.node_key.map(String::from),
+    };
+    std::fs::write(options.config_path, toml::to_string(&config)?)?;
+    println!("Wallet config written to {}", options.config_path.display());
+    Ok(())
+}
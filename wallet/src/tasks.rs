@@ -21,6 +21,18 @@ pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
     })
 }
 
+/// Log what the node sends without being asked, so it does not
+/// pile up
+pub async fn handle_pushes(core: Arc<Core>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut pushes = core.pushes.lock().await;
+        while let Some(message) = pushes.recv().await {
+            debug!("Node sent {:?}", message);
+        }
+        warn!("Connection to the node is closed");
+    })
+}
+
 pub async fn handle_transactions(
     rx: kanal::AsyncReceiver<Transaction>,
     core: Arc<Core>,
@@ -1,10 +1,14 @@
 use crate::core::Core;
+use crate::message::{verify_message, Signer};
 use anyhow::Result;
 use cursive::event::{Event, Key};
 use cursive::traits::*;
 use cursive::views::{
-    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, TextContent, TextView,
+    Button, Checkbox, Dialog, EditView, LinearLayout, Panel, ResizedView, TextArea, TextContent,
+    TextView,
 };
+use lib::crypto::PublicKey;
+use lib::utils::Saveable;
 use cursive::Cursive;
 use std::sync::{Arc, Mutex};
 use tracing::*;
@@ -50,16 +54,29 @@ fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
 
 fn setup_menubar(siv: &mut Cursive, core: Arc<Core>) {
     siv.menubar()
-        .add_leaf("Send", move |siv| {
-            show_send_transaction(siv, core.clone());
+        .add_leaf("Send", {
+            let core = core.clone();
+            move |siv| show_send_transaction(siv, core.clone())
+        })
+        .add_leaf("Sign message", {
+            let core = core.clone();
+            move |siv| show_sign_message(siv, core.clone())
+        })
+        .add_leaf("Verify message", move |siv| {
+            show_verify_message(siv, core.clone());
         })
         .add_leaf("Quit", |siv| siv.quit());
     siv.set_autohide_menu(false);
 }
 fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
     let instruction = TextView::new("Press Escape to select the top menu");
+    let balance_title = if core.config.spv {
+        "Balance (proven by SPV)"
+    } else {
+        "Balance"
+    };
     let balance_panel = Panel::new(TextView::new_with_content(balance_content))
-        .title("Balance");
+        .title(balance_title);
     let info_layout = create_info_layout(&core);
     let layout = LinearLayout::vertical()
         .child(instruction)
@@ -81,6 +98,16 @@ fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
         Panel::new(TextView::new(keys_content))
             .title("Your keys"),
     ));
+    let addresses_content = core
+        .addresses()
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    info_layout.add_child(ResizedView::with_full_width(
+        Panel::new(TextView::new(addresses_content))
+            .title("Your addresses"),
+    ));
     let contacts_content = core
         .config
         .contacts
@@ -192,3 +219,100 @@ fn show_error_dialog(siv: &mut Cursive, error: impl std::fmt::Display) {
             }),
     )
 }
+
+fn show_sign_message(siv: &mut Cursive, core: Arc<Core>) {
+    info!("Showing sign message dialog");
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("Key number, as listed under your addresses"))
+        .child(EditView::new().content("0").with_name("sign_key"))
+        .child(
+            LinearLayout::horizontal()
+                .child(Checkbox::new().with_name("sign_schnorr"))
+                .child(TextView::new(" Sign with Schnorr")),
+        )
+        .child(TextView::new("Message"))
+        .child(TextArea::new().with_name("sign_text").min_height(5).min_width(60));
+    siv.add_layer(
+        Dialog::around(layout)
+            .title("Sign Message")
+            .button("Sign", move |siv| sign_message(siv, core.clone()))
+            .button("Cancel", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn sign_message(siv: &mut Cursive, core: Arc<Core>) {
+    let index = siv
+        .call_on_name("sign_key", |view: &mut EditView| view.get_content())
+        .unwrap()
+        .parse()
+        .unwrap_or(0);
+    let schnorr = siv
+        .call_on_name("sign_schnorr", |view: &mut Checkbox| view.is_checked())
+        .unwrap();
+    let message = siv
+        .call_on_name("sign_text", |view: &mut TextArea| view.get_content().to_string())
+        .unwrap();
+    match core.sign_message(index, &message, schnorr) {
+        Ok(signed) => {
+            info!("Signed a message with key {}", index);
+            siv.pop_layer();
+            siv.add_layer(
+                Dialog::around(TextView::new(signed.to_string()))
+                    .title("Signed Message")
+                    .button("OK", |s| {
+                        s.pop_layer();
+                    }),
+            );
+        }
+        Err(e) => show_message_dialog(siv, "Error", format!("Failed to sign: {}", e)),
+    }
+}
+
+fn show_verify_message(siv: &mut Cursive, core: Arc<Core>) {
+    info!("Showing verify message dialog");
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("Signed message"))
+        .child(TextArea::new().with_name("verify_text").min_height(8).min_width(70))
+        .child(TextView::new("Expected signer: address or public key file (optional)"))
+        .child(EditView::new().with_name("verify_signer"));
+    siv.add_layer(
+        Dialog::around(layout)
+            .title("Verify Message")
+            .button("Verify", move |siv| verify(siv, core.clone()))
+            .button("Cancel", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn verify(siv: &mut Cursive, core: Arc<Core>) {
+    let text = siv
+        .call_on_name("verify_text", |view: &mut TextArea| view.get_content().to_string())
+        .unwrap();
+    let signer = siv
+        .call_on_name("verify_signer", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let signer = match signer.trim() {
+        "" => Ok(Signer::Anyone),
+        signer => match signer.parse() {
+            Ok(address) => Ok(Signer::Address(address)),
+            Err(_) => PublicKey::load_from_file(signer).map(Signer::Key),
+        },
+    };
+    let result = match signer {
+        Ok(signer) => verify_message(&text, &signer, core.config.network),
+        Err(e) => Err(anyhow::anyhow!("Failed to load the public key: {}", e)),
+    };
+    match result {
+        Ok(address) => show_message_dialog(siv, "Valid", format!("Valid signature by {}", address)),
+        Err(e) => show_message_dialog(siv, "Invalid", e.to_string()),
+    }
+}
+
+fn show_message_dialog(siv: &mut Cursive, title: &str, text: String) {
+    siv.add_layer(Dialog::text(text).title(title).button("OK", |s| {
+        s.pop_layer();
+    }));
+}
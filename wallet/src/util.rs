@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use tracing::*;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use lib::network::Network;
 use crate::core::{Config, Core, FeeConfig, FeeType, Recipient};
 
 pub fn setup_tracing() -> Result<()> {
@@ -37,11 +38,13 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
         contacts: vec![
             Recipient {
                 name: "Billy".to_string(),
-                key: PathBuf::from("billy.pub.pem"),
+                key: Some(PathBuf::from("billy.pub.pem")),
+                address: None,
             },
             Recipient {
                 name: "Lukas".to_string(),
-                key: PathBuf::from("lukas.pub.pem"),
+                key: None,
+                address: Some("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".parse()?),
             },
         ],
         default_node: "127.0.0.1:9000".to_string(),
@@ -49,6 +52,12 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
             feetype: FeeType::Percent,
             value: 0.1,
         },
+        network: Network::Mainnet,
+        hd: None,
+        spv: false,
+        headers_file: PathBuf::from("wallet_headers.cbor"),
+        encrypt: false,
+        node_key: None,
     };
     let config_str = toml::to_string(&dummy_config)?;
     std::fs::write(path, config_str)?;